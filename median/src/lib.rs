@@ -7,16 +7,69 @@
 //! [`batch_n`] takes `N` equal-length input streams and computes, for each
 //! sample position, the median across the `N` streams, plus each input's sum of
 //! squared errors against that median. The median is the middle value for odd
-//! `N`, or the rounding average of the two middle values for even `N`.
+//! `N`, or the rounding average of the two middle values for even `N` (so for
+//! two streams, simply their rounding average). [`batch_n_even`] can take the
+//! lower or higher middle value instead, see [`EvenMedian`].
+//! [`batch_mean_n`] does the same with the rounded arithmetic mean instead of
+//! the median, and [`batch_trimmed_mean_n`] with the rounded mean of all but the
+//! lowest and highest value. [`batch_spread_n`] computes how far apart the
+//! inputs are instead, the difference of the highest and lowest value, and
+//! [`batch_deviation_n`] how far the farthest input is from a given value,
+//! such as their median.
 //!
 //! Work proceeds in fixed [`BLOCK_BYTES`]-byte blocks (`L = BLOCK_BYTES /
 //! size_of::<T>()` lanes per block), each lowering to native packed
-//! instructions.
+//! instructions. There are no per-architecture backends: the same code lowers
+//! to SSE/AVX on x86-64 and to NEON on aarch64, depending on the target
+//! features the crate is compiled for.
+//!
+//! Each block is sorted on its own. Interleaving the sorting networks of two
+//! blocks per iteration, to shorten their dependency chains, measured no
+//! faster with AVX-512: 0.97x, 1.08x and 0.93x the speed at 9, 11 and 15
+//! inputs. Most of the time goes to accumulating the squared errors rather
+//! than to the sort, and out-of-order execution already overlaps the
+//! independent blocks.
 //!
 //! The element type is abstracted by the [`Scalar`] trait. Supported types are
 //! the signed and unsigned integers up to 32 bits and both IEEE floats
 //! (`u8`/`i8`/`u16`/`i16`/`u32`/`i32`/`f32`/`f64`); 64-bit integers are not
 //! supported.
+//!
+//! # Example
+//!
+//! Three captures of the same 64 samples, one of them with a dropout:
+//!
+//! ```
+//! let a = vec![100u16; 64];
+//! let mut b = vec![102u16; 64];
+//! let c = vec![101u16; 64];
+//! b[10] = 0xFFFF;
+//!
+//! let mut out = vec![0u16; 64];
+//! let mut sse = [0u64; 3];
+//! median::batch_n(&mut out, &[&a, &b, &c], &mut sse);
+//!
+//! // the dropout is rejected like any other outlier
+//! assert!(out.iter().all(|&m| m == 101));
+//! assert_eq!(sse, [64, 63 + (0xFFFF - 101) * (0xFFFF - 101), 0]);
+//! ```
+//!
+//! # API
+//!
+//! The `batch_*_n` functions, [`EvenMedian`], [`BLOCK_BYTES`] and the
+//! [`Scalar`] trait as a bound are the interface meant for other crates. The
+//! methods of [`Scalar`], and [`Net`] and [`Nets`], are only public because the
+//! kernels are monomorphized through them.
+//!
+//! # CPU features
+//!
+//! There is no runtime dispatch. How wide the packed instructions are is fixed
+//! when the crate is compiled, by the target features enabled: SSE2 on a plain
+//! x86-64 target, AVX2 with `-C target-cpu=x86-64-v3`, or everything the build
+//! machine has with `-C target-cpu=native`. The results are the same with any
+//! of them, only the speed differs.
+//! [`INSTRUCTION_SET`] tells which it was, and [`supported_instruction_set`]
+//! what the CPU running it could do.
 
 use core::ops::AddAssign;
 
@@ -25,6 +78,54 @@ use core::ops::AddAssign;
 /// `u16`).
 pub const BLOCK_BYTES: usize = 64;
 
+/// The widest packed instructions the kernels were compiled to, going by the
+/// target features enabled: `"AVX-512BW"`, `"AVX2"`, `"SSE4.1"` or `"SSE2"` on
+/// x86-64, `"NEON"` on aarch64, `"scalar"` on anything else.
+pub const INSTRUCTION_SET: &str = if cfg!(target_feature = "avx512bw") {
+    "AVX-512BW"
+} else if cfg!(target_feature = "avx2") {
+    "AVX2"
+} else if cfg!(target_feature = "sse4.1") {
+    "SSE4.1"
+} else if cfg!(target_arch = "x86_64") {
+    "SSE2"
+} else if cfg!(all(target_arch = "aarch64", target_feature = "neon")) {
+    "NEON"
+} else {
+    "scalar"
+};
+
+/// The widest packed instructions of those [`INSTRUCTION_SET`] names that the
+/// CPU running this supports. Wider than [`INSTRUCTION_SET`] if the crate was
+/// compiled for less than the CPU can do.
+pub fn supported_instruction_set() -> &'static str {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if std::arch::is_x86_feature_detected!("avx512bw") {
+            "AVX-512BW"
+        } else if std::arch::is_x86_feature_detected!("avx2") {
+            "AVX2"
+        } else if std::arch::is_x86_feature_detected!("sse4.1") {
+            "SSE4.1"
+        } else {
+            "SSE2"
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    INSTRUCTION_SET
+}
+
+/// Which value the median of an even number of streams is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvenMedian {
+    /// The rounding average of the two middle values.
+    Average,
+    /// The lower of the two middle values.
+    Low,
+    /// The higher of the two middle values.
+    High,
+}
+
 /// Element types the median kernels support:
 /// `u8`/`i8`/`u16`/`i16`/`u32`/`i32`/`f32`/`f64`.
 ///
@@ -34,6 +135,10 @@ pub trait Scalar: Copy {
     /// floats.
     type Acc: Copy + Default + AddAssign + core::fmt::Debug;
 
+    /// Accumulator for the sum of samples in the mean kernel: `i64` for
+    /// integers, `f64` for floats.
+    type Sum: Copy + Default + AddAssign;
+
     /// Lanes per block for this type: `BLOCK_BYTES / size_of::<Self>()`.
     const LANES: usize;
 
@@ -47,12 +152,47 @@ pub trait Scalar: Copy {
     /// Accumulate the squared error of median `m` against original `x` into the
     /// per-input accumulator.
     fn sse_step(acc: &mut Self::Acc, m: Self, x: Self);
+    /// Widen a sample into the mean accumulator.
+    fn widen(x: Self) -> Self::Sum;
+    /// Mean of `n` samples summing to `sum`. Integers round half up, matching
+    /// [`Scalar::avg`] for `n == 2`.
+    fn mean(sum: Self::Sum, n: usize) -> Self;
+    /// Difference of maximum `hi` and minimum `lo`. Signed integers saturate at
+    /// their maximum when it doesn't fit.
+    fn spread(lo: Self, hi: Self) -> Self;
 
     /// Run the median kernel for this type: write each sample's median across
     /// the `N` inputs to `out` and each input's sum of squared errors to `sse_`.
-    fn batch<const N: usize>(out: &mut [Self], sse_: &mut [Self::Acc; N], a: &[&[Self]; N])
-    where
+    /// `even` picks the median for even `N`.
+    fn batch<const N: usize>(
+        out: &mut [Self],
+        sse_: &mut [Self::Acc; N],
+        a: &[&[Self]; N],
+        even: EvenMedian,
+    ) where
+        Nets: Net<N>;
+
+    /// Run the trimmed mean kernel for this type: write each sample's mean
+    /// across the `N` inputs, excluding the lowest and highest, to `out` and
+    /// each input's sum of squared errors to `sse_`.
+    fn batch_trimmed_mean<const N: usize>(
+        out: &mut [Self],
+        sse_: &mut [Self::Acc; N],
+        a: &[&[Self]; N],
+    ) where
         Nets: Net<N>;
+
+    /// Run the mean kernel for this type: write each sample's mean across the
+    /// inputs to `out` and each input's sum of squared errors to `sse_`.
+    fn batch_mean(out: &mut [Self], sse_: &mut [Self::Acc], a: &[&[Self]]);
+
+    /// Run the spread kernel for this type: write each sample's difference of
+    /// the highest and lowest value across the inputs to `out`.
+    fn batch_spread(out: &mut [Self], a: &[&[Self]]);
+
+    /// Run the deviation kernel for this type: write each sample's largest
+    /// difference between an input and `center` to `out`.
+    fn batch_deviation(out: &mut [Self], center: &[Self], a: &[&[Self]]);
 }
 
 /// Compare-exchange: leaves the lane-wise minimum in `a` and maximum in `b`.
@@ -87,12 +227,26 @@ fn sse<T: Scalar, const L: usize>(m: [T; L], x: [T; L]) -> T::Acc {
 }
 
 /// The median kernel for a fixed stream count `N`, generic over the element
-/// type `T` and lane count `L`.
+/// type `T` and lane count `L`. Not meant to be used directly, see
+/// [`batch_n`].
 pub trait Net<const N: usize> {
-    /// Writes each sample's median across the `N` inputs to `out` and
-    /// accumulates each input's sum of squared errors against the median into
-    /// `sse_`.
-    fn run<T: Scalar, const L: usize>(out: &mut [T], sse_: &mut [T::Acc; N], a: &[&[T]; N]);
+    /// Writes each sample's median across the `N` inputs to `out` and each
+    /// input's sum of squared errors against the median to `sse_`, overwriting
+    /// it. `even` picks the median for even `N`, and is ignored for odd.
+    fn run<T: Scalar, const L: usize>(
+        out: &mut [T],
+        sse_: &mut [T::Acc; N],
+        a: &[&[T]; N],
+        even: EvenMedian,
+    );
+
+    /// Like `run`, but writes the mean of the sorted values without the
+    /// lowest and highest instead of the median.
+    fn run_trimmed_mean<T: Scalar, const L: usize>(
+        out: &mut [T],
+        sse_: &mut [T::Acc; N],
+        a: &[&[T]; N],
+    );
 
     /// Fully sorts `N` vectors lane-wise with the same network `run` uses.
     /// Test-only.
@@ -110,10 +264,164 @@ fn batch_median<T: Scalar, const L: usize, const N: usize>(
     out: &mut [T],
     sse_: &mut [T::Acc; N],
     a: &[&[T]; N],
+    even: EvenMedian,
+) where
+    Nets: Net<N>,
+{
+    <Nets as Net<N>>::run::<T, L>(out, sse_, a, even);
+}
+
+/// Runs the trimmed mean kernel for element type `T`, lane count `L` and stream
+/// count `N`.
+#[inline(never)]
+fn batch_trimmed_mean<T: Scalar, const L: usize, const N: usize>(
+    out: &mut [T],
+    sse_: &mut [T::Acc; N],
+    a: &[&[T]; N],
 ) where
     Nets: Net<N>,
 {
-    <Nets as Net<N>>::run::<T, L>(out, sse_, a);
+    <Nets as Net<N>>::run_trimmed_mean::<T, L>(out, sse_, a);
+}
+
+/// Runs the mean kernel for element type `T` and lane count `L`, over any
+/// number of streams.
+#[inline(never)]
+fn batch_mean<T: Scalar, const L: usize>(out: &mut [T], sse_: &mut [T::Acc], a: &[&[T]]) {
+    let len = out.len();
+    assert_eq!(len % L, 0);
+    assert_eq!(sse_.len(), a.len());
+    for x in a {
+        assert_eq!(len, x.len());
+    }
+    sse_.fill(T::Acc::default());
+    for (i, outc) in out.chunks_exact_mut(L).enumerate() {
+        let base = i * L;
+        let mut sum = [T::Sum::default(); L];
+        for x in a {
+            for (s, &v) in sum.iter_mut().zip(&x[base..base + L]) {
+                *s += T::widen(v);
+            }
+        }
+        let m: [T; L] = core::array::from_fn(|j| T::mean(sum[j], a.len()));
+        for (k, x) in a.iter().enumerate() {
+            sse_[k] += sse(m, x[base..base + L].try_into().unwrap());
+        }
+        outc.copy_from_slice(&m);
+    }
+}
+
+/// Runs the spread kernel for element type `T` and lane count `L`, over any
+/// number of streams.
+#[inline(never)]
+fn batch_spread<T: Scalar, const L: usize>(out: &mut [T], a: &[&[T]]) {
+    let len = out.len();
+    assert_eq!(len % L, 0);
+    for x in a {
+        assert_eq!(len, x.len());
+    }
+    for (i, outc) in out.chunks_exact_mut(L).enumerate() {
+        let base = i * L;
+        let mut lo: [T; L] = a[0][base..base + L].try_into().unwrap();
+        let mut hi = lo;
+        for x in &a[1..] {
+            for j in 0..L {
+                lo[j] = T::vmin(lo[j], x[base + j]);
+                hi[j] = T::vmax(hi[j], x[base + j]);
+            }
+        }
+        for j in 0..L {
+            outc[j] = T::spread(lo[j], hi[j]);
+        }
+    }
+}
+
+/// Runs the deviation kernel for element type `T` and lane count `L`, over any
+/// number of streams.
+#[inline(never)]
+fn batch_deviation<T: Scalar, const L: usize>(out: &mut [T], center: &[T], a: &[&[T]]) {
+    let len = out.len();
+    assert_eq!(len % L, 0);
+    assert_eq!(len, center.len());
+    for x in a {
+        assert_eq!(len, x.len());
+    }
+    for (i, outc) in out.chunks_exact_mut(L).enumerate() {
+        let base = i * L;
+        let c: [T; L] = center[base..base + L].try_into().unwrap();
+        let mut lo = c;
+        let mut hi = c;
+        for x in a {
+            for j in 0..L {
+                lo[j] = T::vmin(lo[j], x[base + j]);
+                hi[j] = T::vmax(hi[j], x[base + j]);
+            }
+        }
+        for j in 0..L {
+            outc[j] = T::vmax(T::spread(lo[j], c[j]), T::spread(c[j], hi[j]));
+        }
+    }
+}
+
+/// Computes the per-sample rounded mean across the input streams `a`, writing
+/// each mean to `out` and each input's sum of squared errors against the mean
+/// to `sse_`. All slices must have the same length, a multiple of `T::LANES`;
+/// `sse_` has one entry per input. Any non-zero number of inputs is supported.
+///
+/// Unlike [`batch_n`], outliers are not rejected: a single bad input shifts
+/// the result.
+#[track_caller]
+pub fn batch_mean_n<T: Scalar>(out: &mut [T], a: &[&[T]], sse_: &mut [T::Acc]) {
+    assert!(!a.is_empty());
+    check_lengths(out, a, sse_);
+    T::batch_mean(out, sse_, a);
+}
+
+/// Checks that `out`, the input streams `a` and `sse_` fit together, so that
+/// misuse panics here, at the caller, with what is wrong, rather than deep in
+/// a kernel: `sse_` has one entry per input, and `out` and the inputs all have
+/// the same length, a multiple of `T::LANES`.
+#[track_caller]
+fn check_lengths<T: Scalar>(out: &[T], a: &[&[T]], sse_: &[T::Acc]) {
+    assert!(
+        sse_.len() == a.len(),
+        "sse_ has {} entries for {} inputs, it needs one per input",
+        sse_.len(),
+        a.len()
+    );
+    let len = out.len();
+    assert!(
+        len.is_multiple_of(T::LANES),
+        "out has {len} samples, which isn't a multiple of {}",
+        T::LANES
+    );
+    for (k, x) in a.iter().enumerate() {
+        assert!(
+            x.len() == len,
+            "input {k} has {} samples, but out has {len}",
+            x.len()
+        );
+    }
+}
+
+/// Computes the per-sample spread across the input streams `a`: the difference
+/// of the highest and lowest value, 0 where all inputs agree. All slices must
+/// have the same length, a multiple of `T::LANES`. Any non-zero number of
+/// inputs is supported.
+pub fn batch_spread_n<T: Scalar>(out: &mut [T], a: &[&[T]]) {
+    assert!(!a.is_empty());
+    T::batch_spread(out, a);
+}
+
+/// Computes the per-sample deviation of the input streams `a` from `center`:
+/// the largest difference between any input and the center value, 0 where all
+/// inputs equal it. Passing the output of [`batch_n`] as `center` measures how
+/// far the median had to correct the worst input. All slices must have the
+/// same length, a multiple of `T::LANES`. Any non-zero number of inputs is
+/// supported.
+pub fn batch_deviation_n<T: Scalar>(out: &mut [T], center: &[T], a: &[&[T]]) {
+    assert!(!a.is_empty());
+    T::batch_deviation(out, center, a);
 }
 
 /// Implements [`Scalar`] for an integer type. `$wide` is the wider type the
@@ -123,6 +431,7 @@ macro_rules! impl_int_scalar {
     ($t:ty, $wide:ty, $sse:ident) => {
         impl Scalar for $t {
             type Acc = u64;
+            type Sum = i64;
             const LANES: usize = BLOCK_BYTES / core::mem::size_of::<$t>();
 
             #[inline]
@@ -142,11 +451,56 @@ macro_rules! impl_int_scalar {
                 impl_int_scalar!(@$sse acc, m, x);
             }
             #[inline]
-            fn batch<const N: usize>(out: &mut [Self], sse_: &mut [u64; N], a: &[&[Self]; N])
-            where
+            fn widen(x: Self) -> i64 {
+                x as i64
+            }
+            #[inline]
+            fn mean(sum: i64, n: usize) -> Self {
+                let n = n as i64;
+                (2 * sum + n).div_euclid(2 * n) as $t
+            }
+            #[inline]
+            fn spread(lo: Self, hi: Self) -> Self {
+                hi.saturating_sub(lo)
+            }
+            #[inline]
+            fn batch<const N: usize>(
+                out: &mut [Self],
+                sse_: &mut [u64; N],
+                a: &[&[Self]; N],
+                even: EvenMedian,
+            ) where
                 Nets: Net<N>,
             {
-                batch_median::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }, N>(out, sse_, a)
+                batch_median::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }, N>(
+                    out, sse_, a, even,
+                )
+            }
+            #[inline]
+            fn batch_trimmed_mean<const N: usize>(
+                out: &mut [Self],
+                sse_: &mut [u64; N],
+                a: &[&[Self]; N],
+            ) where
+                Nets: Net<N>,
+            {
+                batch_trimmed_mean::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }, N>(
+                    out, sse_, a,
+                )
+            }
+            #[inline]
+            fn batch_mean(out: &mut [Self], sse_: &mut [u64], a: &[&[Self]]) {
+                batch_mean::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }>(out, sse_, a)
+            }
+            #[inline]
+            fn batch_spread(out: &mut [Self], a: &[&[Self]]) {
+                batch_spread::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }>(out, a)
+            }
+            #[inline]
+            fn batch_deviation(out: &mut [Self], center: &[Self], a: &[&[Self]]) {
+                batch_deviation::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }>(
+                    out, center, a,
+                )
             }
         }
     };
@@ -166,6 +520,7 @@ macro_rules! impl_float_scalar {
     ($t:ty) => {
         impl Scalar for $t {
             type Acc = f64;
+            type Sum = f64;
             const LANES: usize = BLOCK_BYTES / core::mem::size_of::<$t>();
 
             #[inline]
@@ -194,11 +549,55 @@ macro_rules! impl_float_scalar {
                 *acc += d * d;
             }
             #[inline]
-            fn batch<const N: usize>(out: &mut [Self], sse_: &mut [f64; N], a: &[&[Self]; N])
-            where
+            fn widen(x: Self) -> f64 {
+                x as f64
+            }
+            #[inline]
+            fn mean(sum: f64, n: usize) -> Self {
+                (sum / n as f64) as $t
+            }
+            #[inline]
+            fn spread(lo: Self, hi: Self) -> Self {
+                hi - lo
+            }
+            #[inline]
+            fn batch<const N: usize>(
+                out: &mut [Self],
+                sse_: &mut [f64; N],
+                a: &[&[Self]; N],
+                even: EvenMedian,
+            ) where
                 Nets: Net<N>,
             {
-                batch_median::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }, N>(out, sse_, a)
+                batch_median::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }, N>(
+                    out, sse_, a, even,
+                )
+            }
+            #[inline]
+            fn batch_trimmed_mean<const N: usize>(
+                out: &mut [Self],
+                sse_: &mut [f64; N],
+                a: &[&[Self]; N],
+            ) where
+                Nets: Net<N>,
+            {
+                batch_trimmed_mean::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }, N>(
+                    out, sse_, a,
+                )
+            }
+            #[inline]
+            fn batch_mean(out: &mut [Self], sse_: &mut [f64], a: &[&[Self]]) {
+                batch_mean::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }>(out, sse_, a)
+            }
+            #[inline]
+            fn batch_spread(out: &mut [Self], a: &[&[Self]]) {
+                batch_spread::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }>(out, a)
+            }
+            #[inline]
+            fn batch_deviation(out: &mut [Self], center: &[Self], a: &[&[Self]]) {
+                batch_deviation::<Self, { BLOCK_BYTES / core::mem::size_of::<$t>() }>(
+                    out, center, a,
+                )
             }
         }
     };
@@ -221,8 +620,8 @@ impl_float_scalar!(f64);
 /// N => ([lane indices 0..N-1], [median slot(s)], [compare-exchange network])
 /// ```
 ///
-/// The median is the middle sorted lane for odd `N`, or the rounding average of
-/// the two middle lanes for even `N`.
+/// The median is the middle sorted lane for odd `N`, or for even `N` the
+/// rounding average of the two middle lanes or one of them, by [`EvenMedian`].
 macro_rules! medians {
     (
         $(
@@ -240,6 +639,7 @@ macro_rules! medians {
                     out: &mut [T],
                     sse_: &mut [T::Acc; $n],
                     a: &[&[T]; $n],
+                    even: EvenMedian,
                 ) {
                     ::paste::paste! {
                         // Bind each input slice to a local.
@@ -256,9 +656,49 @@ macro_rules! medians {
                             $( let mut [<s $lane>] = [<va $lane>]; )+
                             $( sort2(&mut [<s $x>], &mut [<s $y>]); )+
                             // Median: middle local (odd) or rounding avg of the
-                            // two middle locals (even).
+                            // two middle locals, or either of them (even).
                             let m = [<s $mid0>];
-                            $( let m = avg(m, [<s $midr>]); )*
+                            // unused for odd N, with its single middle local
+                            let _ = even;
+                            $(
+                                let m = match even {
+                                    EvenMedian::Average => avg(m, [<s $midr>]),
+                                    EvenMedian::Low => m,
+                                    EvenMedian::High => [<s $midr>],
+                                };
+                            )*
+                            $( sse_[$lane] += sse(m, [<va $lane>]); )+
+                            outc.copy_from_slice(&m);
+                        }
+                    }
+                }
+
+                #[inline]
+                fn run_trimmed_mean<T: Scalar, const L: usize>(
+                    out: &mut [T],
+                    sse_: &mut [T::Acc; $n],
+                    a: &[&[T]; $n],
+                ) {
+                    ::paste::paste! {
+                        $( let [<a $lane>] = a[$lane]; )+
+                        let len = out.len();
+                        assert_eq!(len % L, 0);
+                        $( assert_eq!(len, [<a $lane>].len()); )+
+                        sse_.fill(T::Acc::default());
+                        for (i, outc) in out.chunks_exact_mut(L).enumerate() {
+                            let base = i * L;
+                            $( let [<va $lane>]: [T; L] = [<a $lane>][base..base + L].try_into().unwrap(); )+
+                            $( let mut [<s $lane>] = [<va $lane>]; )+
+                            $( sort2(&mut [<s $x>], &mut [<s $y>]); )+
+                            // Sum everything but the lowest and highest local.
+                            let sorted = [$( [<s $lane>] ),+];
+                            let mut sum = [T::Sum::default(); L];
+                            for v in &sorted[1..$n - 1] {
+                                for j in 0..L {
+                                    sum[j] += T::widen(v[j]);
+                                }
+                            }
+                            let m: [T; L] = core::array::from_fn(|j| T::mean(sum[j], $n - 2));
                             $( sse_[$lane] += sse(m, [<va $lane>]); )+
                             outc.copy_from_slice(&m);
                         }
@@ -279,19 +719,70 @@ macro_rules! medians {
 
         /// Computes the per-sample median across the input streams `a`, writing
         /// each median to `out` and each input's sum of squared errors against
-        /// the median to `sse_`. All slices must have the same length, a
-        /// multiple of `T::LANES`; `sse_` has one entry per input. Panics if the
-        /// number of inputs is unsupported.
+        /// the median to `sse_`.
+        ///
+        /// For an odd number of inputs the median is the middle value. For an
+        /// even number it is the average of the two middle values, rounded half
+        /// up for integers. `sse_` is overwritten, not added to: entry `k` is
+        /// the sum over all samples of `(median - a[k])²`, in `u64` for
+        /// integers and `f64` for floats.
+        ///
+        /// # Panics
+        ///
+        /// If `a` doesn't have between 2 and 15 inputs, if `sse_` doesn't have
+        /// one entry per input, or if `out` and the inputs aren't all of the
+        /// same length, a multiple of `T::LANES` (32 for `u16`).
+        #[track_caller]
         pub fn batch_n<T: Scalar>(out: &mut [T], a: &[&[T]], sse_: &mut [T::Acc]) {
+            batch_n_even(out, a, sse_, EvenMedian::Average);
+        }
+
+        /// Like [`batch_n`], with `even` picking the median of an even number of
+        /// inputs. Taking one of the two middle values rather than their average
+        /// keeps a sample where half the inputs have a dropout from blending the
+        /// dropout into the clean signal.
+        #[track_caller]
+        pub fn batch_n_even<T: Scalar>(
+            out: &mut [T],
+            a: &[&[T]],
+            sse_: &mut [T::Acc],
+            even: EvenMedian,
+        ) {
+            check_lengths(out, a, sse_);
             match a.len() {
                 $(
                     $n => T::batch::<$n>(
                         out,
                         sse_.try_into().unwrap(),
                         a.try_into().unwrap(),
+                        even,
+                    ),
+                )+
+                n => panic!("the median takes 2 to 15 inputs, not {n}"),
+            }
+        }
+
+        /// Computes the per-sample trimmed mean across the input streams `a`:
+        /// the rounded mean of all values except the lowest and the highest.
+        /// This rejects a single outlier per sample like the median does, but
+        /// averages the remaining values for better noise reduction. For three
+        /// inputs it is the same as the median. Each mean is written to `out`
+        /// and each input's sum of squared errors against it to `sse_`. The
+        /// length requirements and supported input counts are the same as for
+        /// [`batch_n`], except that at least 3 inputs are needed.
+        #[track_caller]
+        pub fn batch_trimmed_mean_n<T: Scalar>(out: &mut [T], a: &[&[T]], sse_: &mut [T::Acc]) {
+            assert!(a.len() >= 3, "trimmed mean needs at least 3 inputs");
+            check_lengths(out, a, sse_);
+            match a.len() {
+                $(
+                    $n => T::batch_trimmed_mean::<$n>(
+                        out,
+                        sse_.try_into().unwrap(),
+                        a.try_into().unwrap(),
                     ),
                 )+
-                _ => panic!(),
+                n => panic!("the trimmed mean takes 3 to 15 inputs, not {n}"),
             }
         }
     };
@@ -300,6 +791,7 @@ macro_rules! medians {
 // Sorting networks as compare-exchange index pairs, one entry per supported
 // stream count.
 medians! {
+    2  => ([0, 1], [0, 1], [(0, 1)]),
     3  => ([0, 1, 2], [1], [(0, 2), (0, 1), (1, 2)]),
     4  => ([0, 1, 2, 3], [1, 2], [(0, 2), (1, 3), (0, 1), (2, 3), (1, 2)]),
     5  => ([0, 1, 2, 3, 4], [2], [(0, 3), (1, 4), (0, 2), (1, 3), (0, 1), (2, 4), (1, 2), (3, 4), (2, 3)]),
@@ -7,7 +7,10 @@
 //! `batch_n` matches a scalar median + sum-of-squared-errors reference. Every
 //! check runs over each supported element type via the [`TestScalar`] harness.
 
-use super::{avg, batch_n, sse, Net, Nets, Scalar, BLOCK_BYTES};
+use super::{
+    avg, batch_deviation_n, batch_mean_n, batch_n, batch_n_even, batch_spread_n,
+    batch_trimmed_mean_n, sse, EvenMedian, Net, Nets, Scalar, BLOCK_BYTES,
+};
 
 /// Tiny deterministic xorshift64 PRNG.
 struct Rng(u64);
@@ -38,6 +41,10 @@ trait TestScalar: Scalar + PartialOrd + core::fmt::Debug {
     /// Reference squared error of `m` against `x`, computed independently of the
     /// kernel (in `i128`/`f64`) so it actually cross-checks `sse_step`.
     fn ref_sse(m: Self, x: Self) -> Self::Acc;
+    /// Reference mean of a column, computed independently of the kernel.
+    fn ref_mean(col: &[Self]) -> Self;
+    /// Reference spread of a column, computed independently of the kernel.
+    fn ref_spread(col: &[Self]) -> Self;
     /// Whether two accumulators agree: exact for `u64`, relative-tolerant for
     /// `f64` (block-wise float summation reorders the adds).
     fn acc_close(got: Self::Acc, want: Self::Acc) -> bool;
@@ -58,6 +65,17 @@ macro_rules! test_int {
                 let d = m as i128 - x as i128;
                 (d * d) as u64
             }
+            fn ref_mean(col: &[$t]) -> $t {
+                // Round half up: floor(sum / n + 1 / 2).
+                let sum = col.iter().map(|&v| v as i128).sum::<i128>();
+                let n = col.len() as i128;
+                (2 * sum + n).div_euclid(2 * n) as $t
+            }
+            fn ref_spread(col: &[$t]) -> $t {
+                let max = col.iter().map(|&v| v as i128).max().unwrap();
+                let min = col.iter().map(|&v| v as i128).min().unwrap();
+                (max - min).min(<$t>::MAX as i128) as $t
+            }
             fn acc_close(got: u64, want: u64) -> bool {
                 got == want
             }
@@ -92,6 +110,14 @@ macro_rules! test_float {
                 let d = (m - x) as f64;
                 d * d
             }
+            fn ref_mean(col: &[$t]) -> $t {
+                (col.iter().map(|&v| v as f64).sum::<f64>() / col.len() as f64) as $t
+            }
+            fn ref_spread(col: &[$t]) -> $t {
+                let max = col.iter().fold(<$t>::MIN, |a, &b| a.max(b));
+                let min = col.iter().fold(<$t>::MAX, |a, &b| a.min(b));
+                max - min
+            }
             fn acc_close(got: f64, want: f64) -> bool {
                 (got - want).abs() <= 1e-9 * got.abs().max(want.abs()).max(1.0)
             }
@@ -138,9 +164,10 @@ where
     }
 }
 
-/// Exercises every supported stream count `3..=15` of the sorting network for
+/// Exercises every supported stream count `2..=15` of the sorting network for
 /// one element type.
 fn check_all_sorts<T: TestScalar, const L: usize>() {
+    check_sort::<T, 2, L>(0x9E3779B97F4A7C14);
     check_sort::<T, 3, L>(0x9E3779B97F4A7C15);
     check_sort::<T, 4, L>(0x9E3779B97F4A7C16);
     check_sort::<T, 5, L>(0x9E3779B97F4A7C17);
@@ -174,7 +201,7 @@ fn reference_median<T: TestScalar>(col: &mut [T]) -> T {
 fn check_batch<T: TestScalar, const L: usize>(seed: u64) {
     let mut rng = Rng::new(seed);
     let len = L * 7; // must be a multiple of L
-    for n in 3..=15usize {
+    for n in 2..=15usize {
         for &wide in &[true, false] {
             let inputs: Vec<Vec<T>> = (0..n)
                 .map(|_| (0..len).map(|_| T::rand(&mut rng, wide)).collect())
@@ -216,6 +243,213 @@ fn check_batch<T: TestScalar, const L: usize>(seed: u64) {
     }
 }
 
+/// End-to-end check of `batch_n_even` picking one of the two middle values
+/// for even stream counts, and the plain median for odd ones.
+fn check_even_median<T: TestScalar, const L: usize>(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let len = L * 7;
+    for n in 2..=15usize {
+        for &wide in &[true, false] {
+            let inputs: Vec<Vec<T>> = (0..n)
+                .map(|_| (0..len).map(|_| T::rand(&mut rng, wide)).collect())
+                .collect();
+            let slices: Vec<&[T]> = inputs.iter().map(|v| v.as_slice()).collect();
+
+            for even in [EvenMedian::Low, EvenMedian::High] {
+                let mut out = inputs[0].clone();
+                let mut sse_acc = vec![T::Acc::default(); n];
+                batch_n_even(&mut out, &slices, &mut sse_acc, even);
+
+                for i in 0..len {
+                    let mut col: Vec<T> = (0..n).map(|k| inputs[k][i]).collect();
+                    col.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    let expected = match even {
+                        EvenMedian::Low if n % 2 == 0 => col[n / 2 - 1],
+                        _ => col[n / 2],
+                    };
+                    assert!(
+                        out[i] == expected,
+                        "{even:?} median mismatch n={n} wide={wide} sample={i}: got {:?} want {:?}",
+                        out[i],
+                        expected
+                    );
+                }
+
+                for (k, input) in inputs.iter().enumerate() {
+                    let mut want = T::Acc::default();
+                    for i in 0..len {
+                        want += T::ref_sse(out[i], input[i]);
+                    }
+                    assert!(
+                        T::acc_close(sse_acc[k], want),
+                        "{even:?} sse mismatch n={n} wide={wide} input={k}: got {:?} want {:?}",
+                        sse_acc[k],
+                        want
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// End-to-end check of `batch_mean_n` against a scalar mean + SSE reference for
+/// one element type, across a range of stream counts.
+fn check_mean<T: TestScalar, const L: usize>(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let len = L * 7;
+    for n in 1..=15usize {
+        for &wide in &[true, false] {
+            let inputs: Vec<Vec<T>> = (0..n)
+                .map(|_| (0..len).map(|_| T::rand(&mut rng, wide)).collect())
+                .collect();
+            let slices: Vec<&[T]> = inputs.iter().map(|v| v.as_slice()).collect();
+
+            let mut out = inputs[0].clone();
+            let mut sse_acc = vec![T::Acc::default(); n];
+            batch_mean_n(&mut out, &slices, &mut sse_acc);
+
+            for i in 0..len {
+                let col: Vec<T> = (0..n).map(|k| inputs[k][i]).collect();
+                let expected = T::ref_mean(&col);
+                assert!(
+                    out[i] == expected,
+                    "mean mismatch n={n} wide={wide} sample={i}: got {:?} want {:?}",
+                    out[i],
+                    expected
+                );
+            }
+
+            for (k, input) in inputs.iter().enumerate() {
+                let mut want = T::Acc::default();
+                for i in 0..len {
+                    want += T::ref_sse(out[i], input[i]);
+                }
+                assert!(
+                    T::acc_close(sse_acc[k], want),
+                    "sse mismatch n={n} wide={wide} input={k}: got {:?} want {:?}",
+                    sse_acc[k],
+                    want
+                );
+            }
+        }
+    }
+}
+
+/// End-to-end check of `batch_trimmed_mean_n` against a scalar reference for one
+/// element type, across all stream counts.
+fn check_trimmed_mean<T: TestScalar, const L: usize>(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let len = L * 7;
+    for n in 3..=15usize {
+        for &wide in &[true, false] {
+            let inputs: Vec<Vec<T>> = (0..n)
+                .map(|_| (0..len).map(|_| T::rand(&mut rng, wide)).collect())
+                .collect();
+            let slices: Vec<&[T]> = inputs.iter().map(|v| v.as_slice()).collect();
+
+            let mut out = inputs[0].clone();
+            let mut sse_acc = vec![T::Acc::default(); n];
+            batch_trimmed_mean_n(&mut out, &slices, &mut sse_acc);
+
+            for i in 0..len {
+                let mut col: Vec<T> = (0..n).map(|k| inputs[k][i]).collect();
+                col.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let expected = T::ref_mean(&col[1..n - 1]);
+                assert!(
+                    out[i] == expected,
+                    "trimmed mean mismatch n={n} wide={wide} sample={i}: got {:?} want {:?}",
+                    out[i],
+                    expected
+                );
+            }
+
+            for (k, input) in inputs.iter().enumerate() {
+                let mut want = T::Acc::default();
+                for i in 0..len {
+                    want += T::ref_sse(out[i], input[i]);
+                }
+                assert!(
+                    T::acc_close(sse_acc[k], want),
+                    "sse mismatch n={n} wide={wide} input={k}: got {:?} want {:?}",
+                    sse_acc[k],
+                    want
+                );
+            }
+        }
+    }
+}
+
+/// End-to-end check of `batch_spread_n` against a scalar reference for one
+/// element type, across a range of stream counts.
+fn check_spread<T: TestScalar, const L: usize>(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let len = L * 7;
+    for n in 1..=15usize {
+        for &wide in &[true, false] {
+            let inputs: Vec<Vec<T>> = (0..n)
+                .map(|_| (0..len).map(|_| T::rand(&mut rng, wide)).collect())
+                .collect();
+            let slices: Vec<&[T]> = inputs.iter().map(|v| v.as_slice()).collect();
+
+            let mut out = inputs[0].clone();
+            batch_spread_n(&mut out, &slices);
+
+            for i in 0..len {
+                let col: Vec<T> = (0..n).map(|k| inputs[k][i]).collect();
+                let expected = T::ref_spread(&col);
+                assert!(
+                    out[i] == expected,
+                    "spread mismatch n={n} wide={wide} sample={i}: got {:?} want {:?}",
+                    out[i],
+                    expected
+                );
+            }
+        }
+    }
+}
+
+/// End-to-end check of `batch_deviation_n` against a scalar reference for one
+/// element type, across a range of stream counts. The center is the median, as
+/// the stacker uses it, and also an unrelated stream, which may lie outside the
+/// inputs.
+fn check_deviation<T: TestScalar, const L: usize>(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let len = L * 7;
+    for n in 2..=15usize {
+        for &wide in &[true, false] {
+            let inputs: Vec<Vec<T>> = (0..n)
+                .map(|_| (0..len).map(|_| T::rand(&mut rng, wide)).collect())
+                .collect();
+            let slices: Vec<&[T]> = inputs.iter().map(|v| v.as_slice()).collect();
+
+            let mut median = inputs[0].clone();
+            batch_n(&mut median, &slices, &mut vec![T::Acc::default(); n]);
+            let other: Vec<T> = (0..len).map(|_| T::rand(&mut rng, wide)).collect();
+
+            for center in [&median, &other] {
+                let mut out = inputs[0].clone();
+                batch_deviation_n(&mut out, center, &slices);
+
+                for i in 0..len {
+                    let expected = (0..n)
+                        .map(|k| T::ref_spread(&[inputs[k][i], center[i]]))
+                        .fold(None, |a: Option<T>, d| match a {
+                            Some(a) if a >= d => Some(a),
+                            _ => Some(d),
+                        })
+                        .unwrap();
+                    assert!(
+                        out[i] == expected,
+                        "deviation mismatch n={n} wide={wide} sample={i}: got {:?} want {:?}",
+                        out[i],
+                        expected
+                    );
+                }
+            }
+        }
+    }
+}
+
 macro_rules! type_suite {
     ($mod:ident, $t:ty, $lanes:literal) => {
         mod $mod {
@@ -239,6 +473,31 @@ macro_rules! type_suite {
             fn batch_matches_reference() {
                 check_batch::<$t, $lanes>(0xC0FFEE123);
             }
+
+            #[test]
+            fn even_median_matches_reference() {
+                check_even_median::<$t, $lanes>(0xC0FFEE321);
+            }
+
+            #[test]
+            fn mean_matches_reference() {
+                check_mean::<$t, $lanes>(0xC0FFEE456);
+            }
+
+            #[test]
+            fn trimmed_mean_matches_reference() {
+                check_trimmed_mean::<$t, $lanes>(0xC0FFEE789);
+            }
+
+            #[test]
+            fn spread_matches_reference() {
+                check_spread::<$t, $lanes>(0xC0FFEEABC);
+            }
+
+            #[test]
+            fn deviation_matches_reference() {
+                check_deviation::<$t, $lanes>(0xC0FFEEDEF);
+            }
         }
     };
 }
@@ -269,6 +528,8 @@ fn avg_rounds_without_overflow() {
         let got = avg::<u16, 32>([x; 32], [y; 32])[0];
         let exp = ((x as u32 + y as u32 + 1) >> 1) as u16;
         assert_eq!(got, exp, "avg({x}, {y})");
+        let mean = <u16 as Scalar>::mean(x as i64 + y as i64, 2);
+        assert_eq!(mean, exp, "mean({x}, {y})");
     }
 }
 
@@ -288,6 +549,111 @@ fn sse_matches_scalar() {
     }
 }
 
+/// A `u16` kernel writing each input's squared error to its last argument.
+type Kernel = fn(&mut [u16], &[&[u16]], &mut [u64]);
+
+/// The kernels overwrite `sse_` rather than add to it, so one buffer can be
+/// reused for every call, like the stacker does for the parts of a field.
+#[test]
+fn sse_is_overwritten() {
+    let mut rng = Rng::new(0xACC);
+    let len = 32 * 8;
+    for n in 3..=15usize {
+        let inputs: Vec<Vec<u16>> = (0..n)
+            .map(|_| (0..len).map(|_| u16::rand(&mut rng, true)).collect())
+            .collect();
+        let slices: Vec<&[u16]> = inputs.iter().map(|v| v.as_slice()).collect();
+        let mut out = vec![0u16; len];
+        let kernels: [Kernel; 3] = [batch_n, batch_mean_n, batch_trimmed_mean_n];
+        for kernel in kernels {
+            let mut fresh = vec![0u64; n];
+            kernel(&mut out, &slices, &mut fresh);
+            let mut reused = vec![u64::MAX / 2; n];
+            kernel(&mut out, &slices, &mut reused);
+            assert_eq!(reused, fresh, "n={n}");
+            kernel(&mut out, &slices, &mut reused);
+            assert_eq!(reused, fresh, "n={n}");
+        }
+    }
+}
+
+/// Mismatched lengths are caught before the kernels, with a message saying
+/// which argument is off.
+#[test]
+#[should_panic(expected = "sse_ has 2 entries for 3 inputs")]
+fn sse_of_the_wrong_length_is_named() {
+    let input = vec![0u16; 32];
+    let mut out = vec![0u16; 32];
+    batch_n(&mut out, &[&input, &input, &input], &mut [0u64; 2]);
+}
+
+#[test]
+#[should_panic(expected = "input 1 has 64 samples, but out has 32")]
+fn an_input_of_the_wrong_length_is_named() {
+    let input = vec![0u16; 32];
+    let long = vec![0u16; 64];
+    let mut out = vec![0u16; 32];
+    batch_trimmed_mean_n(&mut out, &[&input, &long, &input], &mut [0u64; 3]);
+}
+
+/// The squared error of a whole PAL field, every sample as far from the
+/// median as it can be, still fits the `u64` accumulator.
+#[test]
+fn sse_of_a_field_of_worst_case_errors() {
+    let len = (1135usize * 313).div_ceil(32) * 32;
+    let low = vec![0u16; len];
+    let high = vec![u16::MAX; len];
+    // 8 inputs at the bottom of the range outvote 7 at the top
+    let slices: Vec<&[u16]> = (0..15)
+        .map(|k| if k < 8 { &low[..] } else { &high[..] })
+        .collect();
+    let mut out = vec![0u16; len];
+    let mut sse_acc = vec![0u64; 15];
+    batch_n(&mut out, &slices, &mut sse_acc);
+    assert!(out.iter().all(|&m| m == 0));
+    let want = len as u128 * (u16::MAX as u128).pow(2);
+    for (k, &sse) in sse_acc.iter().enumerate() {
+        let want = if k < 8 { 0 } else { want };
+        assert_eq!(sse as u128, want, "input={k}");
+    }
+}
+
+/// Data whose length isn't a multiple of the lanes gets padded to one: the
+/// padding must not change the data's output, and adds no SSE when zeroed.
+#[test]
+fn padding_past_data() {
+    let mut rng = Rng::new(0xFEED);
+    let len = 910usize * 3; // three NTSC lines, not a multiple of 32
+    let padded = len.div_ceil(32) * 32;
+    for n in 2..=8usize {
+        let mut inputs: Vec<Vec<u16>> = (0..n)
+            .map(|_| (0..padded).map(|_| u16::rand(&mut rng, true)).collect())
+            .collect();
+        let mut stale_out = vec![0u16; padded];
+        let mut stale_sse = vec![0u64; n];
+        {
+            let slices: Vec<&[u16]> = inputs.iter().map(|v| v.as_slice()).collect();
+            batch_n(&mut stale_out, &slices, &mut stale_sse);
+        }
+        for input in &mut inputs {
+            input[len..].fill(0);
+        }
+        let slices: Vec<&[u16]> = inputs.iter().map(|v| v.as_slice()).collect();
+        let mut out = vec![0u16; padded];
+        let mut sse_acc = vec![0u64; n];
+        batch_n(&mut out, &slices, &mut sse_acc);
+
+        assert_eq!(out[..len], stale_out[..len], "n={n}");
+        assert!(out[len..].iter().all(|&v| v == 0), "n={n}");
+        for (k, input) in inputs.iter().enumerate() {
+            let want = (0..len)
+                .map(|i| u16::ref_sse(out[i], input[i]))
+                .sum::<u64>();
+            assert_eq!(sse_acc[k], want, "n={n} input={k}");
+        }
+    }
+}
+
 /// Throughput benchmark for `batch_n`. Run with:
 ///   cargo test --release -- --ignored --nocapture bench_batch_n
 /// Uses cache-resident buffers so it measures compute throughput (the best
@@ -0,0 +1,137 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::system::SystemConstants;
+use crate::tbc_metadata::DropOuts;
+use std::ops::Range;
+
+/// Sum of squared errors of `input` shifted right by `shift` samples against `reference`, over
+/// `range`. The shifted range must stay within `input`.
+fn shifted_sse(reference: &[u16], input: &[u16], range: Range<usize>, shift: isize) -> u64 {
+    let shifted = range.start.wrapping_add_signed(-shift)..range.end.wrapping_add_signed(-shift);
+    reference[range]
+        .iter()
+        .zip(&input[shifted])
+        .map(|(&r, &x)| {
+            let d = r as i64 - x as i64;
+            (d * d) as u64
+        })
+        .sum()
+}
+
+/// Finds the shift in `-range..=range` that best aligns `input` to `reference` within the useful
+/// window.
+pub fn find_shift(reference: &[u16], input: &[u16], range: usize, sys: &SystemConstants) -> isize {
+    let window = sys.useful_start_sample..sys.useful_end_sample;
+    let range = range as isize;
+    (-range..=range)
+        .min_by_key(|&shift| {
+            // prefer the smallest shift among equally good ones
+            (
+                shifted_sse(reference, input, window.clone(), shift),
+                shift.unsigned_abs(),
+            )
+        })
+        .unwrap()
+}
+
+/// Shifts the samples right by `shift` (left if negative), replicating the edge sample into the
+/// exposed area.
+pub fn shift_samples(samples: &mut [u16], shift: isize) {
+    let len = samples.len();
+    let amount = shift.unsigned_abs().min(len);
+    if amount == 0 {
+        return;
+    }
+    if shift > 0 {
+        let edge = samples[0];
+        samples.copy_within(0..len - amount, amount);
+        samples[..amount].fill(edge);
+    } else {
+        let edge = samples[len - 1];
+        samples.copy_within(amount..len, 0);
+        samples[len - amount..].fill(edge);
+    }
+}
+
+/// Moves the lines of a field of `field_width` samples down by `lines` (up if negative),
+/// replicating the edge line into the exposed lines. The field must have more than `lines` lines.
+pub fn shift_lines(samples: &mut [u16], lines: isize, field_width: usize) {
+    let len = samples.len();
+    let amount = lines.unsigned_abs() * field_width;
+    if amount == 0 {
+        return;
+    }
+    if lines > 0 {
+        samples.copy_within(0..len - amount, amount);
+        for line in (0..amount).step_by(field_width) {
+            samples.copy_within(amount..amount + field_width, line);
+        }
+    } else {
+        samples.copy_within(amount..len, 0);
+        let edge = len - amount - field_width;
+        for line in (len - amount..len).step_by(field_width) {
+            samples.copy_within(edge..edge + field_width, line);
+        }
+    }
+}
+
+/// Moves the dropouts down by `lines` (up if negative), the same way [`shift_lines`] moves the
+/// field's lines. Dropouts moved out of the field's `field_height` lines are left out.
+pub fn shift_dropout_lines(dropouts: &mut DropOuts, lines: isize, field_height: usize) {
+    let mut shifted = DropOuts {
+        field_line: vec![],
+        startx: vec![],
+        endx: vec![],
+    };
+    for j in 0..dropouts.field_line.len() {
+        let Some(line) = dropouts
+            .line_index(j, field_height)
+            .and_then(|line| line.checked_add_signed(lines))
+            .filter(|&line| line < field_height)
+        else {
+            continue;
+        };
+        shifted.push(line, dropouts.startx[j], dropouts.endx[j]);
+    }
+    *dropouts = shifted;
+}
+
+/// Shifts the dropouts right by `shift` samples (left if negative), the same way
+/// [`shift_samples`] moves the field's samples. Dropouts pushed past a line's end continue on the
+/// next line, and ones pushed out of the field are cut off, as are ones on a line the field of
+/// `field_height` lines doesn't have.
+pub fn shift_dropouts(
+    dropouts: &mut DropOuts,
+    shift: isize,
+    field_width: usize,
+    field_height: usize,
+) {
+    let field_size = field_width * field_height;
+    let mut shifted = DropOuts {
+        field_line: vec![],
+        startx: vec![],
+        endx: vec![],
+    };
+    for j in 0..dropouts.field_line.len() {
+        let Some(line) = dropouts.line_index(j, field_height) else {
+            continue;
+        };
+        let line_start = line * field_width;
+        let clamp = |x: usize| x.saturating_add_signed(shift).min(field_size);
+        let mut start = clamp(line_start + dropouts.startx[j]);
+        let end = clamp(line_start + dropouts.endx[j]);
+        while start < end {
+            let line = start / field_width;
+            let line_end = end.min((line + 1) * field_width);
+            shifted.push(
+                line,
+                start - line * field_width,
+                line_end - line * field_width,
+            );
+            start = line_end;
+        }
+    }
+    *dropouts = shifted;
+}
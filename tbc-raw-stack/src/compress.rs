@@ -0,0 +1,136 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::worker::to_bytes;
+use crate::StackError;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+/// FLAC can't store the real sample rate of a TBC, so a nominal one is used. Nothing reading the
+/// file cares, the metadata has the real one.
+const FLAC_SAMPLE_RATE: usize = 48000;
+
+/// An output `.tbc` file, either written raw or compressed on the fly, or nothing at all when
+/// only analyzing.
+pub enum TbcWriter {
+    Raw(BufWriter<File>),
+    Flac(FlacEncoder),
+    Discard,
+}
+
+impl TbcWriter {
+    /// Writes `samples` little-endian, as the decoders do, whatever the byte order of the machine.
+    pub fn write_samples(&mut self, samples: &[u16]) -> io::Result<()> {
+        if cfg!(target_endian = "little") {
+            return self.write_all(unsafe { to_bytes(samples) });
+        }
+        let bytes = samples
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.write_all(&bytes)
+    }
+
+    /// Flushes everything written and, when compressing, waits for the encoder to finish the file.
+    pub fn finish(self) -> Result<(), StackError> {
+        match self {
+            TbcWriter::Raw(mut file) => Ok(file.flush()?),
+            TbcWriter::Flac(encoder) => encoder.finish(),
+            TbcWriter::Discard => Ok(()),
+        }
+    }
+}
+
+impl Write for TbcWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TbcWriter::Raw(file) => file.write(buf),
+            TbcWriter::Flac(encoder) => encoder.stdin.write(buf),
+            TbcWriter::Discard => Ok(buf.len()),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            TbcWriter::Raw(file) => file.write_all(buf),
+            TbcWriter::Flac(encoder) => encoder.stdin.write_all(buf),
+            TbcWriter::Discard => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TbcWriter::Raw(file) => file.flush(),
+            TbcWriter::Flac(encoder) => encoder.stdin.flush(),
+            TbcWriter::Discard => Ok(()),
+        }
+    }
+}
+
+/// The `flac` command line encoder, fed raw samples through its standard input. The samples are
+/// stored the way [`TbcReader`](crate::reader::TbcReader) reads them back.
+pub struct FlacEncoder {
+    path: PathBuf,
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl FlacEncoder {
+    /// Starts an encoder writing to `path`, which must not exist yet unless `overwrite`.
+    pub fn spawn(path: PathBuf, overwrite: bool) -> Result<Self, StackError> {
+        if !overwrite && std::fs::exists(&path).unwrap_or(false) {
+            return Err(StackError::OutputExists { path });
+        }
+        let mut command = Command::new("flac");
+        // in a process group of its own, Ctrl-C stops the stacking but lets it finish the file
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command
+            .args(overwrite.then_some("--force"))
+            .args([
+                "--silent",
+                "--force-raw-format",
+                "--endian=little",
+                "--sign=unsigned",
+                "--channels=1",
+                "--bps=16",
+                &format!("--sample-rate={FLAC_SAMPLE_RATE}"),
+                "-o",
+            ])
+            .arg(&path)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| StackError::Compress {
+                path: path.clone(),
+                source: io::Error::new(e.kind(), format!("cannot run `flac`: {e}")),
+            })?;
+        let stdin = child.stdin.take().unwrap();
+        Ok(FlacEncoder { path, child, stdin })
+    }
+
+    /// Closes the encoder's input, so it writes out the rest of the file, and waits for it.
+    fn finish(self) -> Result<(), StackError> {
+        let FlacEncoder {
+            path,
+            mut child,
+            stdin,
+        } = self;
+        drop(stdin);
+        let status = child.wait().map_err(|source| StackError::Compress {
+            path: path.clone(),
+            source,
+        })?;
+        if !status.success() {
+            return Err(StackError::Compress {
+                path,
+                source: io::Error::other(format!("`flac` failed with {status}")),
+            });
+        }
+        Ok(())
+    }
+}
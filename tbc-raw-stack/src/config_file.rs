@@ -0,0 +1,235 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command};
+use serde_derive::Deserialize;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+/// One `[[input]]` of a config file.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigInput {
+    basename: String,
+    start_field: Option<usize>,
+    start_seqno: Option<usize>,
+    sample_offset: Option<isize>,
+    vshift: Option<isize>,
+    weight: Option<usize>,
+    #[serde(default)]
+    luma_only: bool,
+    #[serde(default)]
+    chroma_only: bool,
+    #[serde(default)]
+    invert: bool,
+}
+
+/// The options given once for each input, which a config file lists under `[[input]]` instead.
+const INPUT_OPTIONS: [&str; 9] = [
+    "input-basename",
+    "start-field",
+    "start-seqno",
+    "sample-offset",
+    "vshift",
+    "input-weight",
+    "luma-only",
+    "chroma-only",
+    "invert",
+];
+
+/// Returns the command line with the options of the `--config` file in it, if one is given, put
+/// before the options on the command line so that those take precedence. Options given on the
+/// command line replace the config file's rather than adding to them: inputs given on the command
+/// line replace all of the config file's, and a per-input option replaces it for all inputs.
+pub fn args_with_config(command: Command, args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let Ok(matches) = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+    else {
+        // let the real parse report it
+        return Ok(args);
+    };
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(args);
+    };
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+    let mut table = text
+        .parse::<Table>()
+        .map_err(|e| format!("Couldn't parse {}: {e}", path.display()))?;
+
+    // the start field of the inputs without one, the command line's taking precedence
+    let start_field_all = if given(&matches, "start_field_all") {
+        matches.get_one::<usize>("start_field_all").copied()
+    } else {
+        match table.get("start-field-all") {
+            Some(Value::Integer(v)) => usize::try_from(*v).ok(),
+            _ => None,
+        }
+    };
+
+    let mut config = vec![];
+    if let Some(inputs) = table.remove("input") {
+        let inputs: Vec<ConfigInput> = inputs
+            .try_into()
+            .map_err(|e| format!("Bad [[input]] in {}: {e}", path.display()))?;
+        // there is no start sequence number meaning the first field to fill in for the others
+        let start_seqnos = inputs.iter().filter(|i| i.start_seqno.is_some()).count();
+        if start_seqnos != 0 && start_seqnos != inputs.len() {
+            return Err(format!(
+                "Some [[input]]s in {} have a start-seqno and some don't, give it for all of them or none",
+                path.display()
+            ));
+        }
+        config.extend(input_args(&command, &matches, &inputs, start_field_all));
+    }
+    for (key, value) in table {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .filter(|_| key != "config")
+            .ok_or_else(|| format!("Unknown option {key} in {}", path.display()))?;
+        if INPUT_OPTIONS.contains(&key.as_str()) {
+            return Err(format!(
+                "{key} in {} belongs to an [[input]]",
+                path.display()
+            ));
+        }
+        if given(&matches, arg.get_id().as_str()) {
+            continue;
+        }
+        let many = arg.get_num_args().is_some_and(|n| n.max_values() > 1);
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        // a flag set to false is left off
+        let values = values
+            .into_iter()
+            .filter(|value| value != &Value::Boolean(false))
+            .map(|value| match value {
+                Value::String(s) => Ok(Some(s)),
+                Value::Integer(_) | Value::Float(_) => Ok(Some(value.to_string())),
+                Value::Boolean(true) => Ok(None),
+                _ => Err(format!(
+                    "Option {key} in {} must be a string, number or boolean",
+                    path.display()
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if many {
+            config.push(format!("--{key}").into());
+            config.extend(values.into_iter().flatten().map(OsString::from));
+        } else {
+            for value in values {
+                config.push(match value {
+                    Some(value) => format!("--{key}={value}").into(),
+                    None => format!("--{key}").into(),
+                });
+            }
+        }
+    }
+
+    let mut args = args.into_iter();
+    Ok(args.next().into_iter().chain(config).chain(args).collect())
+}
+
+/// Whether `id` was given on the command line.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+/// The options for the config file's inputs, leaving out the ones given on the command line, or
+/// all of them if inputs are. Start fields are also left out for `--seqno-start` or
+/// `--start-seqno` on the command line, and start sequence numbers for `--seqno-start` or
+/// `--start-field`. Inputs without a start field get `start_field_all`, if given, when others
+/// have one.
+fn input_args(
+    command: &Command,
+    matches: &ArgMatches,
+    inputs: &[ConfigInput],
+    start_field_all: Option<usize>,
+) -> Vec<OsString> {
+    let mut args = vec![];
+    if given(matches, "input_basename") {
+        return args;
+    }
+    let mut push = |key: &str, values: Vec<String>| {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key))
+            .unwrap();
+        if !given(matches, arg.get_id().as_str()) {
+            args.extend(values.iter().map(|v| format!("--{key}={v}").into()));
+        }
+    };
+    push(
+        "input-basename",
+        inputs.iter().map(|i| i.basename.clone()).collect(),
+    );
+    // start fields, offsets, shifts and weights are given for all inputs or none
+    let seqno_start = given(matches, "seqno_start");
+    let start_seqno = given(matches, "start_seqno");
+    let start_field = given(matches, "start_field");
+    if inputs.iter().any(|i| i.start_field.is_some()) && !seqno_start && !start_seqno {
+        push(
+            "start-field",
+            inputs
+                .iter()
+                .map(|i| i.start_field.or(start_field_all).unwrap_or(1).to_string())
+                .collect(),
+        );
+    }
+    if inputs.iter().any(|i| i.start_seqno.is_some()) && !seqno_start && !start_field {
+        push(
+            "start-seqno",
+            inputs
+                .iter()
+                .filter_map(|i| i.start_seqno.map(|s| s.to_string()))
+                .collect(),
+        );
+    }
+    if inputs.iter().any(|i| i.sample_offset.is_some()) {
+        push(
+            "sample-offset",
+            inputs
+                .iter()
+                .map(|i| i.sample_offset.unwrap_or(0).to_string())
+                .collect(),
+        );
+    }
+    if inputs.iter().any(|i| i.vshift.is_some()) {
+        push(
+            "vshift",
+            inputs
+                .iter()
+                .map(|i| i.vshift.unwrap_or(0).to_string())
+                .collect(),
+        );
+    }
+    if inputs.iter().any(|i| i.weight.is_some()) {
+        push(
+            "input-weight",
+            inputs
+                .iter()
+                .map(|i| i.weight.unwrap_or(1).to_string())
+                .collect(),
+        );
+    }
+    // these name the inputs they apply to instead
+    let numbered = |only: fn(&ConfigInput) -> bool| {
+        (1..)
+            .zip(inputs)
+            .filter(|&(_, i)| only(i))
+            .map(|(n, _)| n.to_string())
+            .collect()
+    };
+    push("luma-only", numbered(|i| i.luma_only));
+    push("chroma-only", numbered(|i| i.chroma_only));
+    push("invert", numbered(|i| i.invert));
+    args
+}
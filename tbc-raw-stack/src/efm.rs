@@ -0,0 +1,108 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Carries the `.efm` of a LaserDisc capture, the EFM data holding its digital audio, over to the
+//! output. ld-decode writes it as one T-value per byte, and records how many of them each field
+//! has as `efmTValues` in the field's metadata.
+
+use crate::metadata_reader::FieldStream;
+use crate::StackError;
+use serde::de;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+/// Copies the EFM data of `fields` (0-based) of the input `basename` to the output `output`, as
+/// one piece from the first to the last of them. The EFM decoder follows the audio by its own
+/// timecodes, so it isn't cut up around the dupes and drops of the video. Without `efmTValues`,
+/// the whole file is copied, as where the fields start in it is unknown.
+pub fn copy_efm(
+    basename: &str,
+    fields: Range<usize>,
+    output: &str,
+    overwrite: bool,
+) -> Result<(), StackError> {
+    let json = PathBuf::from(format!("{basename}.tbc.json"));
+    let efm = PathBuf::from(format!("{basename}.efm"));
+    let out = PathBuf::from(format!("{output}.efm"));
+    let open = |path: &PathBuf| {
+        File::open(path).map_err(|source| StackError::Open {
+            path: path.clone(),
+            source,
+        })
+    };
+
+    // the T-values before the first field and in the fields, if every field has a count
+    let mut counts = FieldStream::spawn(open(&json)?, 0);
+    let mut range = Some(0u64..0u64);
+    for idx in 0..fields.end {
+        let field = counts
+            .next()
+            .map_err(|source| StackError::BadMetadata {
+                path: json.clone(),
+                source,
+            })?
+            .ok_or_else(|| StackError::BadMetadata {
+                path: json.clone(),
+                source: de::Error::custom("it has fewer fields than were stacked"),
+            })?;
+        let count = field.other.get("efmTValues").and_then(|v| v.as_u64());
+        range = range.zip(count).map(|(range, count)| {
+            if idx < fields.start {
+                range.start + count..range.end + count
+            } else {
+                range.start..range.end + count
+            }
+        });
+    }
+
+    let mut input = open(&efm)?;
+    let length = input.metadata()?.len();
+    let range = match range {
+        Some(range) => range,
+        None => {
+            warn!(
+                "{} doesn't record efmTValues for every field, copying all of {} instead, which may not line up with the output",
+                json.display(),
+                efm.display()
+            );
+            0..length
+        }
+    };
+    if range.end > length {
+        warn!(
+            "{} is {length} bytes, shorter than the {} bytes of EFM data its metadata records",
+            efm.display(),
+            range.end
+        );
+    }
+
+    let file = if overwrite {
+        File::create(&out)
+    } else {
+        File::create_new(&out)
+    };
+    let file = file.map_err(|source| match source.kind() {
+        io::ErrorKind::AlreadyExists => StackError::OutputExists { path: out.clone() },
+        _ => StackError::Create {
+            path: out.clone(),
+            source,
+        },
+    })?;
+    input.seek(SeekFrom::Start(range.start))?;
+    let mut writer = BufWriter::new(file);
+    let copied = io::copy(
+        &mut BufReader::new(input).take(range.end - range.start),
+        &mut writer,
+    )?;
+    writer.flush()?;
+    info!(
+        "Copied {copied} bytes of EFM data of {} to {}",
+        efm.display(),
+        out.display()
+    );
+    Ok(())
+}
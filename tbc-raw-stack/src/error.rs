@@ -0,0 +1,128 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::StackReport;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Why a [`stack`](crate::stack) run failed.
+#[derive(Error, Debug)]
+pub enum StackError {
+    #[error("Invalid number of inputs ({0}), must be between {min} and {max}", min = crate::MIN_INPUT_STREAMS, max = crate::MAX_INPUT_STREAMS)]
+    InputCount(usize),
+
+    #[error("Cannot open {}: {source}", path.display())]
+    Open { path: PathBuf, source: io::Error },
+
+    #[error("Cannot create {}: {source}", path.display())]
+    Create { path: PathBuf, source: io::Error },
+
+    #[error("Output {} already exists", path.display())]
+    OutputExists { path: PathBuf },
+
+    #[error("Cannot compress {}: {source}", path.display())]
+    Compress { path: PathBuf, source: io::Error },
+
+    #[error("Cannot parse metadata {}: {source}", path.display())]
+    BadMetadata {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    /// `start_field` is 1-based, `input` is the index into the config's inputs.
+    #[error("Start field {start_field} is out of range for input #{}, which has fields 1 to {fields} (start fields are counted from the start of each input)", input + 1)]
+    StartField {
+        input: usize,
+        start_field: usize,
+        fields: usize,
+    },
+
+    /// `start_field` is 1-based, `input` is the index into the config's inputs. `fields` is how
+    /// many the input has, in its metadata or, if they hold fewer, its files.
+    #[error("Input #{} has no fields to stack from start field {start_field} on, it only has {fields}", input + 1)]
+    EmptyInput {
+        input: usize,
+        start_field: usize,
+        fields: usize,
+    },
+
+    /// `input` is the index into the config's inputs.
+    #[error("Input #{} has sequence numbers {first} to {last}, which don't include {seq_no}", input + 1)]
+    SeqNoRange {
+        input: usize,
+        seq_no: usize,
+        first: usize,
+        last: usize,
+    },
+
+    /// `input` is the index into the config's inputs.
+    #[error("Input #{} has no field with sequence number {seq_no} to start on, it skips it", input + 1)]
+    SeqNoMissing { input: usize, seq_no: usize },
+
+    /// `input` is the index into the config's inputs.
+    #[error("{} of input #{} is {found} bytes, but its {fields} fields take {expected} bytes", path.display(), input + 1)]
+    InputSize {
+        input: usize,
+        path: PathBuf,
+        fields: usize,
+        expected: u64,
+        found: u64,
+    },
+
+    /// `input` and `reference` are indices into the config's inputs.
+    #[error("Input #{} has {what} {found}, but input #{} has {expected}", input + 1, reference + 1)]
+    InputMismatch {
+        input: usize,
+        reference: usize,
+        what: &'static str,
+        expected: String,
+        found: String,
+    },
+
+    /// `input` and `other` are indices into the config's inputs.
+    #[error("Input #{} {reason} input #{}, and would count twice in the stack", input + 1, other + 1)]
+    DuplicateInput {
+        input: usize,
+        other: usize,
+        reason: &'static str,
+    },
+
+    /// `input` is the index into the config's inputs.
+    #[error("Input #{}, the reference input, must have correct field order, start it on a first field", input + 1)]
+    ReferenceFieldOrder { input: usize },
+
+    #[error("{0}")]
+    InvalidOption(String),
+
+    #[error("Output {basename} failed verification with {problems} problems")]
+    Verify { basename: String, problems: usize },
+
+    #[error("The median kernels failed the self-test for {failures} counts of inputs")]
+    SelfTest { failures: usize },
+
+    #[error(
+        "Arguments don't match the run being resumed. Previous: {previous}, current: {current}"
+    )]
+    ResumeMismatch { previous: String, current: String },
+
+    /// `input` is the index into the config's inputs.
+    #[error("Cannot read input #{}: {source}", input + 1)]
+    Read { input: usize, source: io::Error },
+
+    /// The run stopped partway. The fields written before are complete, and `report` describes
+    /// them: saving its metadata makes the output usable, and `resume` can carry on from it.
+    #[error("{source}, stopped after {} output fields", report.metadata.fields.len())]
+    Incomplete {
+        report: Box<StackReport>,
+        source: Box<StackError>,
+    },
+
+    /// The [`StackObserver`](crate::StackObserver) was asked to stop, such as with Ctrl-C.
+    #[error("Interrupted")]
+    Interrupted,
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
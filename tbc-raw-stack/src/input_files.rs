@@ -0,0 +1,291 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The `.tbc` and `_chroma.tbc` files of an input, read where they are asked for, or on a reader
+//! thread of the input's own, so that the reads of the inputs of a field wait on their drives
+//! together rather than in turn.
+
+use crate::reader::TbcReader;
+use crate::worker::FieldBuffer;
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The luma and chroma of a field.
+type Buffers = (Box<FieldBuffer>, Option<Box<FieldBuffer>>);
+
+pub struct InputFiles {
+    /// Whether the luma is inverted as it is read.
+    invert: bool,
+    files: Files,
+}
+
+enum Files {
+    Direct {
+        tbc: TbcReader,
+        chroma: Option<TbcReader>,
+    },
+    Thread(ReaderThread),
+}
+
+/// A thread owning the files of an input, reading a field whenever asked to.
+struct ReaderThread {
+    has_chroma: bool,
+    requests: Option<Sender<Request>>,
+    replies: Receiver<Reply>,
+    /// The buffers the thread reads the next field into, swapped with the ones it is read for.
+    spare: Option<Buffers>,
+    /// Whether a read was requested that hasn't been received yet.
+    pending: bool,
+    handle: Option<JoinHandle<()>>,
+}
+
+enum Request {
+    Read(Buffers, usize),
+    Skip(usize),
+}
+
+struct Reply {
+    /// The buffers read into, `None` for a skip.
+    buffers: Option<Buffers>,
+    result: io::Result<()>,
+    /// How long the read took on the thread.
+    elapsed: Duration,
+}
+
+impl InputFiles {
+    pub fn new(tbc: TbcReader, chroma: Option<TbcReader>, invert: bool) -> Self {
+        InputFiles {
+            invert,
+            files: Files::Direct { tbc, chroma },
+        }
+    }
+
+    pub fn has_chroma(&self) -> bool {
+        match &self.files {
+            Files::Direct { chroma, .. } => chroma.is_some(),
+            Files::Thread(thread) => thread.has_chroma,
+        }
+    }
+
+    /// Stops reading the chroma. Returns whether there was any. Only before
+    /// [`spawn_reader`](Self::spawn_reader).
+    pub fn drop_chroma(&mut self) -> bool {
+        match &mut self.files {
+            Files::Direct { chroma, .. } => chroma.take().is_some(),
+            Files::Thread(_) => unreachable!("the reader thread has the files"),
+        }
+    }
+
+    /// The length of the luma file in bytes, if it records it. Only before
+    /// [`spawn_reader`](Self::spawn_reader).
+    pub fn byte_len(&self) -> io::Result<Option<u64>> {
+        match &self.files {
+            Files::Direct { tbc, .. } => tbc.byte_len(),
+            Files::Thread(_) => unreachable!("the reader thread has the files"),
+        }
+    }
+
+    /// Moves the files to a reader thread of their own, unless they are mapped into memory,
+    /// which leaves nothing to wait on.
+    pub fn spawn_reader(&mut self) {
+        let Files::Direct { tbc, .. } = &self.files else {
+            return;
+        };
+        if matches!(tbc, TbcReader::Mapped { .. }) {
+            return;
+        }
+        let Files::Direct {
+            mut tbc,
+            mut chroma,
+        } = std::mem::replace(&mut self.files, Files::Thread(ReaderThread::placeholder()))
+        else {
+            unreachable!()
+        };
+        let has_chroma = chroma.is_some();
+        let invert = self.invert;
+        let (requests, thread_requests) = channel::<Request>();
+        let (thread_replies, replies) = channel();
+        let handle = thread::spawn(move || {
+            for request in thread_requests {
+                let started = Instant::now();
+                let (buffers, result) = match request {
+                    Request::Read((mut luma, mut chroma_buf), field_size) => {
+                        let result = read(
+                            &mut tbc,
+                            chroma.as_mut(),
+                            invert,
+                            &mut luma,
+                            chroma_buf.as_deref_mut(),
+                            field_size,
+                        );
+                        (Some((luma, chroma_buf)), result)
+                    }
+                    Request::Skip(field_size) => {
+                        (None, skip(&mut tbc, chroma.as_mut(), field_size))
+                    }
+                };
+                let elapsed = started.elapsed();
+                if thread_replies
+                    .send(Reply {
+                        buffers,
+                        result,
+                        elapsed,
+                    })
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        self.files = Files::Thread(ReaderThread {
+            has_chroma,
+            requests: Some(requests),
+            replies,
+            spare: Some((Box::default(), has_chroma.then(Box::<FieldBuffer>::default))),
+            pending: false,
+            handle: Some(handle),
+        });
+    }
+
+    /// Starts reading the next field on the reader thread, to be taken with
+    /// [`read`](Self::read). Does nothing without one.
+    pub fn request(&mut self, field_size: usize) -> io::Result<()> {
+        match &mut self.files {
+            Files::Direct { .. } => Ok(()),
+            Files::Thread(thread) => thread.request(field_size),
+        }
+    }
+
+    /// Reads the next field into `luma` and `chroma`, or takes the one requested, returning how
+    /// long reading it took.
+    pub fn read(
+        &mut self,
+        luma: &mut Box<FieldBuffer>,
+        chroma: Option<&mut Box<FieldBuffer>>,
+        field_size: usize,
+    ) -> io::Result<Duration> {
+        match &mut self.files {
+            Files::Direct {
+                tbc,
+                chroma: chroma_file,
+            } => {
+                let started = Instant::now();
+                let chroma = chroma.map(|c| &mut **c);
+                read(
+                    tbc,
+                    chroma_file.as_mut(),
+                    self.invert,
+                    luma,
+                    chroma,
+                    field_size,
+                )?;
+                Ok(started.elapsed())
+            }
+            Files::Thread(thread) => {
+                if !thread.pending {
+                    thread.request(field_size)?;
+                }
+                thread.pending = false;
+                let reply = thread.reply()?;
+                let (mut read_luma, mut read_chroma) = reply.buffers.expect("a read's buffers");
+                if reply.result.is_ok() {
+                    std::mem::swap(luma, &mut read_luma);
+                    if let (Some(chroma), Some(read_chroma)) = (chroma, read_chroma.as_mut()) {
+                        std::mem::swap(chroma, read_chroma);
+                    }
+                }
+                thread.spare = Some((read_luma, read_chroma));
+                reply.result.map(|()| reply.elapsed)
+            }
+        }
+    }
+
+    /// Moves past the next field without reading it.
+    pub fn skip(&mut self, field_size: usize) -> io::Result<()> {
+        match &mut self.files {
+            Files::Direct { tbc, chroma } => skip(tbc, chroma.as_mut(), field_size),
+            Files::Thread(thread) => {
+                thread.send(Request::Skip(field_size))?;
+                thread.reply()?.result
+            }
+        }
+    }
+}
+
+impl ReaderThread {
+    /// Stands in for the files while they are moved to the thread.
+    fn placeholder() -> Self {
+        ReaderThread {
+            has_chroma: false,
+            requests: None,
+            replies: channel().1,
+            spare: None,
+            pending: false,
+            handle: None,
+        }
+    }
+
+    fn request(&mut self, field_size: usize) -> io::Result<()> {
+        let buffers = self.spare.take().expect("a read already requested");
+        self.send(Request::Read(buffers, field_size))?;
+        self.pending = true;
+        Ok(())
+    }
+
+    fn send(&self, request: Request) -> io::Result<()> {
+        (self.requests.as_ref())
+            .and_then(|requests| requests.send(request).ok())
+            .ok_or_else(stopped)
+    }
+
+    fn reply(&self) -> io::Result<Reply> {
+        self.replies.recv().map_err(|_| stopped())
+    }
+}
+
+impl Drop for ReaderThread {
+    fn drop(&mut self) {
+        // closing the requests ends the thread, along with the files
+        self.requests = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("the input's reader thread stopped")
+}
+
+/// Reads the next field of `tbc` and `chroma_file` into `luma` and `chroma`.
+fn read(
+    tbc: &mut TbcReader,
+    chroma_file: Option<&mut TbcReader>,
+    invert: bool,
+    luma: &mut FieldBuffer,
+    chroma: Option<&mut FieldBuffer>,
+    field_size: usize,
+) -> io::Result<()> {
+    tbc.read(&mut luma.0[0..field_size])?;
+    if invert {
+        // before anything else sees it, the black pSNR included
+        for sample in &mut luma.0[0..field_size] {
+            *sample = u16::MAX - *sample;
+        }
+    }
+    if let (Some(file), Some(chroma)) = (chroma_file, chroma) {
+        file.read(&mut chroma.0[0..field_size])?;
+    }
+    Ok(())
+}
+
+fn skip(tbc: &mut TbcReader, chroma: Option<&mut TbcReader>, field_size: usize) -> io::Result<()> {
+    tbc.skip(field_size)?;
+    if let Some(chroma) = chroma {
+        chroma.skip(field_size)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reads the inputs of an `--input-list` file: one input a line, as
+//! `basename[,start_field[,sample_offset[,weight]]]`. Empty lines and lines starting with `#` are
+//! skipped.
+
+use crate::parse_start_field;
+use std::path::Path;
+use tbc_raw_stack::{InputConfig, Planes, StackError};
+
+/// The inputs listed in `path`, those without a start field starting at `start_field`.
+pub fn read(path: &Path, start_field: usize) -> Result<Vec<InputConfig>, StackError> {
+    let text = std::fs::read_to_string(path).map_err(|source| StackError::Open {
+        path: path.into(),
+        source,
+    })?;
+    let mut inputs = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |what: &str| {
+            StackError::InvalidOption(format!(
+                "{what} on line {} of {}",
+                number + 1,
+                path.display()
+            ))
+        };
+        let mut values = line.split(',').map(str::trim);
+        let basename = values.next().filter(|b| !b.is_empty());
+        let input = InputConfig {
+            basename: basename.ok_or_else(|| invalid("No basename"))?.to_string(),
+            start_field: match values.next() {
+                Some(v) => parse_start_field(v)
+                    .map_err(|e| invalid(&format!("Bad start field {v:?}: {e}")))?,
+                None => start_field,
+            },
+            start_seq_no: None,
+            sample_offset: match values.next() {
+                Some(v) => v
+                    .parse()
+                    .map_err(|e| invalid(&format!("Bad sample offset {v:?}: {e}")))?,
+                None => 0,
+            },
+            vshift: 0,
+            weight: match values.next() {
+                Some(v) => v
+                    .parse()
+                    .map_err(|e| invalid(&format!("Bad weight {v:?}: {e}")))?,
+                None => 1,
+            },
+            planes: Planes::Both,
+            invert: false,
+        };
+        if values.next().is_some() {
+            return Err(invalid("More than 4 values"));
+        }
+        inputs.push(input);
+    }
+    Ok(inputs)
+}
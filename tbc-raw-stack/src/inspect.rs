@@ -0,0 +1,107 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::tbc_metadata::{TbcMetadata, VideoParameters};
+use crate::StackError;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+/// How many fields each entry of [`Inspection::dropouts`] covers.
+pub const DROPOUT_BLOCK_FIELDS: usize = 1000;
+
+/// What [`inspect`] found in a capture's metadata. Field numbers are 1-based, as **ld-analyse**
+/// shows them.
+#[derive(Debug, Clone)]
+pub struct Inspection {
+    pub video_parameters: VideoParameters,
+    /// The count of fields listed, which the video parameters may disagree with.
+    pub fields: usize,
+    /// The fields whose `seqNo` isn't past the one before, which stacking skips as dupes.
+    pub dupes: Vec<usize>,
+    /// Each field following a jump in `seqNo`, with the count of sequence numbers skipped.
+    pub gaps: Vec<(usize, usize)>,
+    /// The fields, other than dupes, of the same field order as the one before.
+    pub field_order_breaks: Vec<usize>,
+    /// The dropouts of every [`DROPOUT_BLOCK_FIELDS`] fields in turn, the last block possibly
+    /// shorter.
+    pub dropouts: Vec<DropoutBlock>,
+}
+
+/// The dropouts of a run of fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DropoutBlock {
+    pub first_field: usize,
+    pub fields: usize,
+    /// The count of dropouts listed.
+    pub dropouts: usize,
+    /// The count of samples they cover.
+    pub samples: usize,
+}
+
+/// Reads the metadata of the capture `basename` and describes it, without touching its `.tbc`
+/// files.
+pub fn inspect(basename: &str) -> Result<Inspection, StackError> {
+    let json = PathBuf::from(format!("{basename}.tbc.json"));
+    let file = File::open(&json).map_err(|source| StackError::Open {
+        path: json.clone(),
+        source,
+    })?;
+    let metadata: TbcMetadata = serde_json::from_reader(BufReader::new(file))
+        .map_err(|source| StackError::BadMetadata { path: json, source })?;
+    let fields = &metadata.fields;
+
+    let mut dupes = vec![];
+    let mut gaps = vec![];
+    let mut field_order_breaks = vec![];
+    for (i, pair) in fields.windows(2).enumerate() {
+        let (previous, field) = (&pair[0], &pair[1]);
+        let number = i + 2;
+        if field.seq_no <= previous.seq_no {
+            dupes.push(number);
+            continue;
+        }
+        if field.seq_no > previous.seq_no + 1 {
+            gaps.push((number, field.seq_no - previous.seq_no - 1));
+        }
+        if field.is_first_field == previous.is_first_field {
+            field_order_breaks.push(number);
+        }
+    }
+
+    let field_width = metadata.video_parameters.field_width;
+    let dropouts = fields
+        .chunks(DROPOUT_BLOCK_FIELDS)
+        .enumerate()
+        .map(|(block, chunk)| {
+            let listed = chunk.iter().filter_map(|f| f.drop_outs.as_ref());
+            let (mut dropouts, mut samples) = (0, 0);
+            for d in listed {
+                dropouts += d.field_line.len();
+                samples += d
+                    .startx
+                    .iter()
+                    .zip(&d.endx)
+                    // a malformed dropout may end past its line, count it up to the end of it
+                    .map(|(&start, &end)| end.min(field_width).saturating_sub(start))
+                    .sum::<usize>();
+            }
+            DropoutBlock {
+                first_field: block * DROPOUT_BLOCK_FIELDS + 1,
+                fields: chunk.len(),
+                dropouts,
+                samples,
+            }
+        })
+        .collect();
+
+    Ok(Inspection {
+        fields: fields.len(),
+        video_parameters: metadata.video_parameters,
+        dupes,
+        gaps,
+        field_order_breaks,
+        dropouts,
+    })
+}
@@ -0,0 +1,110 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Matches the luma levels of every input to the reference input's, for captures from decks that
+//! put black and white at slightly different sample values.
+
+use crate::system::SystemConstants;
+use crate::worker::FieldBuffer;
+use std::ops::Range;
+use tracing::info;
+
+/// How many stacked fields the levels of the inputs are measured over, after which they stay.
+pub const LEVEL_MATCH_FIELDS: usize = 50;
+
+/// The least difference between the black and the average picture level, in sample values, to
+/// find a gain from. A picture that close to black only gets its offset matched.
+const MIN_LEVEL_RANGE: f64 = 1024.;
+
+/// A gain and offset taking an input's luma levels to the reference input's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LevelCorrection {
+    pub gain: f32,
+    pub offset: f32,
+}
+
+impl LevelCorrection {
+    pub fn apply(self, samples: &mut [u16]) {
+        for sample in samples {
+            *sample = (*sample as f32 * self.gain + self.offset)
+                .round()
+                .clamp(0., u16::MAX as f32) as u16;
+        }
+    }
+}
+
+/// Measures the black level of each input in the black pSNR window, and its average picture level
+/// in the RMSE window, over the first [`LEVEL_MATCH_FIELDS`] stacked fields.
+pub struct LevelMatch {
+    reference: usize,
+    black: Range<usize>,
+    picture: Range<usize>,
+    /// Count of fields measured.
+    fields: usize,
+    /// The sums of each input's black and average picture levels over the fields measured.
+    sums: Vec<(f64, f64)>,
+}
+
+impl LevelMatch {
+    pub fn new(inputs: usize, reference: usize, sys: &SystemConstants) -> Self {
+        LevelMatch {
+            reference,
+            black: sys.black_start_sample..sys.black_end_sample,
+            picture: sys.useful_start_sample..sys.useful_end_sample,
+            fields: 0,
+            sums: vec![(0., 0.); inputs],
+        }
+    }
+
+    /// Adds the levels of the fields in `luma`, one for each input, if still measuring, and
+    /// returns the gain and offset of each input as measured so far.
+    pub fn update(&mut self, luma: &[Box<FieldBuffer>]) -> Vec<LevelCorrection> {
+        if self.fields < LEVEL_MATCH_FIELDS {
+            let mean = |samples: &[u16]| {
+                samples.iter().map(|&v| v as u64).sum::<u64>() as f64 / samples.len() as f64
+            };
+            for (sums, field) in self.sums.iter_mut().zip(luma) {
+                sums.0 += mean(&field.0[self.black.clone()]);
+                sums.1 += mean(&field.0[self.picture.clone()]);
+            }
+            self.fields += 1;
+            if self.fields == LEVEL_MATCH_FIELDS {
+                for (i, level) in self.corrections().iter().enumerate() {
+                    info!(
+                        "Levels of input #{} matched to the reference input with a gain of {:.4} and an offset of {:.1}",
+                        i + 1,
+                        level.gain,
+                        level.offset
+                    );
+                }
+            }
+        }
+        self.corrections()
+    }
+
+    /// The gain and offset of each input, taking its black and average picture level to the
+    /// reference input's.
+    pub fn corrections(&self) -> Vec<LevelCorrection> {
+        let fields = self.fields.max(1) as f64;
+        let (ref_black, ref_picture) = self.sums[self.reference];
+        let (ref_black, ref_picture) = (ref_black / fields, ref_picture / fields);
+        self.sums
+            .iter()
+            .map(|&(black, picture)| {
+                let (black, picture) = (black / fields, picture / fields);
+                let gain = if (picture - black).abs() < MIN_LEVEL_RANGE
+                    || (ref_picture - ref_black).abs() < MIN_LEVEL_RANGE
+                {
+                    1.
+                } else {
+                    (ref_picture - ref_black) / (picture - black)
+                };
+                LevelCorrection {
+                    gain: gain as f32,
+                    offset: (ref_black - black * gain) as f32,
+                }
+            })
+            .collect()
+    }
+}
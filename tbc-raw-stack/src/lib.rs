@@ -0,0 +1,489 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Stacks multiple captures of the same tape into one, by taking the per-sample median (or
+//! another [`StackMode`]) of the time base corrected fields.
+//!
+//! [`stack`] does the whole job for a [`StackConfig`]: it writes the output `.tbc` and
+//! `_chroma.tbc` files, and returns the output metadata together with per-field metrics in a
+//! [`StackReport`]. Saving those is up to the caller. [`stack_with_observer`] also hands each
+//! field's report to a [`StackObserver`] as soon as it is written. [`verify_output`] checks a
+//! written output for consistency afterwards, and [`inspect`] describes a capture's metadata
+//! before it is stacked.
+
+mod align;
+mod compress;
+mod efm;
+mod error;
+mod input_files;
+mod inspect;
+mod levels;
+mod metadata_reader;
+mod reader;
+mod report;
+mod resume;
+mod side_metadata;
+mod stack;
+mod system;
+pub mod tbc_metadata;
+mod verify;
+mod worker;
+mod writer;
+
+pub use error::StackError;
+pub use inspect::{inspect, DropoutBlock, Inspection};
+pub use report::{
+    FieldKind, FieldReport, InputSummary, RunInfo, RunInput, StackObserver, StackReport,
+};
+pub use stack::{stack, stack_with_observer};
+pub use verify::verify_output;
+
+use clap::ValueEnum;
+use std::time::Duration;
+
+pub const MIN_INPUT_STREAMS: usize = 2;
+pub const MAX_INPUT_STREAMS: usize = 15;
+
+/// How the samples of the inputs are combined into the output.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackMode {
+    /// Per-sample median, rejects dropouts and outliers
+    Median,
+    /// Per-sample mean, better noise reduction but does not reject dropouts
+    Mean,
+    /// Per-sample mean without the lowest and highest sample, needs at least 4 inputs
+    TrimmedMean,
+    /// The base input as is, with its dropouts filled with the median of the other inputs
+    DropoutFill,
+}
+
+/// Which value the median of an even number of inputs takes, with [`StackMode::Median`].
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvenMedian {
+    /// The average of the two middle values, which blends a dropout on half the inputs with the
+    /// clean signal
+    Avg,
+    /// The lower of the two middle values
+    Low,
+    /// The higher of the two middle values
+    High,
+}
+
+/// What the inputs are combined as, with [`StackMode::Median`], [`StackMode::Mean`] and
+/// [`StackMode::TrimmedMean`].
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MedianSpace {
+    /// The sample values as they are
+    Raw,
+    /// The linear light the luma samples stand for, turned back into sample values afterwards.
+    /// The chroma is combined as it is
+    Linear,
+}
+
+/// What to do once one of the inputs runs out of fields.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TailMode {
+    /// End the output with the shortest input
+    Stop,
+    /// Keep copying the fields of the input with the most left, as long as it lasts
+    Passthrough,
+}
+
+/// What to do when an input's `.tbc` files are of a different size than its metadata describes.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SizeMismatch {
+    /// Refuse to start
+    Error,
+    /// Warn, and stack until the shorter of the two ends
+    Warn,
+    /// Warn, and stack all the fields the files hold, with made up metadata for those past the
+    /// end of the metadata: the sequence numbers and field order carried on, without dropouts
+    Extend,
+}
+
+/// The byte order of the samples in the inputs' raw `.tbc` files. The output is always written
+/// little-endian, as the decoders write it.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    /// Little-endian, as the decoders write it
+    Le,
+    /// Big-endian
+    Be,
+    /// The byte order of the machine running the stacker
+    Native,
+}
+
+impl Endian {
+    /// Whether samples of this byte order need their bytes swapped to be native.
+    pub(crate) fn swaps(self) -> bool {
+        match self {
+            Endian::Le => cfg!(target_endian = "big"),
+            Endian::Be => cfg!(target_endian = "little"),
+            Endian::Native => false,
+        }
+    }
+}
+
+/// Where the side metadata of each output field (VBI data, closed captions, field phase) comes
+/// from. Everything else is taken from the reference input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SideMetadata {
+    /// Taken from the input at this index
+    Input(usize),
+    /// The value most inputs agree on, ties going to the earlier input
+    Vote,
+}
+
+/// What the VITS metrics of each output field (`vitsMetrics`) other than the bPSNR, which is
+/// always measured on the output, are made of.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VitsMetricsMode {
+    /// Left out, only the bPSNR measured on the output is kept
+    Recompute,
+    /// Copied from the reference input, describing it rather than the output
+    Reference,
+    /// The average of the inputs that have them for the numeric ones, like `wSNR`, and the
+    /// reference input's for the rest
+    Average,
+}
+
+/// When to warn about an input matching the stacked output poorly, a sign of a bad source or a
+/// desync, or suspiciously well, a sign of a duplicate input or of the others having no picture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RmseWarn {
+    /// RMSE pSNR below which an input's field counts as bad
+    pub psnr: f32,
+    /// How far below the average RMSE pSNR of the other inputs it must be as well
+    pub delta: f32,
+    /// How far above the average RMSE pSNR of the other inputs an input's field must be to count
+    /// as suspiciously high
+    pub high_delta: f32,
+    /// Warn after this many bad or suspiciously high fields in a row, and again after every this
+    /// many
+    pub streak: usize,
+}
+
+impl RmseWarn {
+    /// Whether input `i` counts as bad among `rmse_psnr`, the RMSE pSNR of every input. Values
+    /// that aren't finite are left out of the average of the others, so a single one can't decide
+    /// for every other input, and one that isn't finite itself never counts as bad.
+    pub fn is_bad(&self, rmse_psnr: &[f32], i: usize) -> bool {
+        Self::against_others(rmse_psnr, i)
+            .is_some_and(|(v, avg_of_others)| v < self.psnr && v < avg_of_others - self.delta)
+    }
+
+    /// Whether input `i` matches the output suspiciously better than the others do, the same way
+    /// [`is_bad`](Self::is_bad) leaves out values that aren't finite.
+    pub fn is_high(&self, rmse_psnr: &[f32], i: usize) -> bool {
+        Self::against_others(rmse_psnr, i)
+            .is_some_and(|(v, avg_of_others)| v > avg_of_others + self.high_delta)
+    }
+
+    /// The RMSE pSNR of input `i` and the average of the other inputs' finite ones, if it is
+    /// finite and there are any.
+    fn against_others(rmse_psnr: &[f32], i: usize) -> Option<(f32, f32)> {
+        let v = rmse_psnr[i];
+        let others = rmse_psnr
+            .iter()
+            .enumerate()
+            .filter(|&(j, other)| j != i && other.is_finite())
+            .map(|(_, &other)| other)
+            .collect::<Vec<_>>();
+        if !v.is_finite() || others.is_empty() {
+            return None;
+        }
+        Some((v, others.iter().sum::<f32>() / others.len() as f32))
+    }
+}
+
+impl Default for RmseWarn {
+    fn default() -> Self {
+        RmseWarn {
+            psnr: 32.,
+            delta: 5.,
+            high_delta: 15.,
+            streak: 30,
+        }
+    }
+}
+
+/// The schema of a `.tbc.json`, which ld-decode changed in how it names the video system.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataVersion {
+    /// `system` names it, "PAL", "NTSC" or "PAL-M", as ld-decode writes since it supports PAL-M
+    Current,
+    /// `isSourcePal` tells PAL from NTSC, as older versions of ld-decode and its tools expect
+    Legacy,
+}
+
+/// Which of its luma and chroma an input takes part in the stack with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Planes {
+    /// Both of them
+    #[default]
+    Both,
+    /// Only the luma, for a capture with bad chroma
+    LumaOnly,
+    /// Only the chroma, for a capture with bad luma
+    ChromaOnly,
+}
+
+impl Planes {
+    /// Whether the input's luma is stacked.
+    pub fn luma(self) -> bool {
+        self != Planes::ChromaOnly
+    }
+
+    /// Whether the input's chroma is stacked.
+    pub fn chroma(self) -> bool {
+        self != Planes::LumaOnly
+    }
+}
+
+/// One capture to stack.
+#[derive(Clone, Debug)]
+pub struct InputConfig {
+    /// Path of the capture without the `.tbc` extension
+    pub basename: String,
+    /// Field index to start with (1-based), counted from the start of this input rather than
+    /// relative to the other inputs. Unused with [`start_seq_no`](Self::start_seq_no) or
+    /// [`StackConfig::seq_no_start`]
+    pub start_field: usize,
+    /// Start on the field with this sequence number instead of
+    /// [`start_field`](Self::start_field). The input must have a field with it, unlike with
+    /// [`StackConfig::seq_no_start`], which this takes precedence over
+    pub start_seq_no: Option<usize>,
+    /// Shift the input right by this many samples (left if negative) before stacking
+    pub sample_offset: isize,
+    /// Move the input's lines down by this many (up if negative) before stacking, for a decoder
+    /// that put it whole lines off the others
+    pub vshift: isize,
+    /// How many times the input takes part in each combination, 1 for all inputs counting the
+    /// same
+    pub weight: usize,
+    /// Whether the input's luma, chroma or both are stacked. Its error against the output is
+    /// measured either way
+    pub planes: Planes,
+    /// Swap the input's black and white, taking each luma sample from the top of the range, for a
+    /// capture decoded with the wrong polarity
+    pub invert: bool,
+}
+
+/// Everything a [`stack`] run needs to know.
+#[derive(Clone, Debug)]
+pub struct StackConfig {
+    /// The captures to stack, between 2 and 15 of them
+    pub inputs: Vec<InputConfig>,
+    /// Path of the output without the `.tbc` extension
+    pub output_basename: String,
+    /// Start each input on its field with this sequence number instead of its
+    /// [`start_field`](InputConfig::start_field), or the next one if it skipped it
+    pub seq_no_start: Option<usize>,
+    /// End each input with its field with this sequence number, or the last one before it if it
+    /// skipped it
+    pub seq_no_end: Option<usize>,
+    /// How many fields to process (0 = all)
+    pub max_fields: usize,
+    /// Stop the output at this running time, at the frame rate of the inputs' system, if earlier
+    /// than [`max_fields`](Self::max_fields)
+    pub max_duration: Option<Duration>,
+    /// Leave out the first this many output fields, still reading the inputs for them, to trim a
+    /// lead-in. Must be even, so the output starts on a first field
+    pub skip_output_fields: usize,
+    /// Only write every this many output fields, starting with the first, for a quick preview.
+    /// The inputs are still read for the others, which aren't stacked. 1 for all of them
+    pub decimate: usize,
+    /// How many inputs should agree on having a dropout to mark it as such, `None` for half of
+    /// the inputs rounded up. With [`StackMode::DropoutFill`], only the inputs other than the base
+    /// one are counted
+    pub dropout_threshold: Option<usize>,
+    /// Also mark every dropout the reference input has as one in the output, however few of the
+    /// other inputs agree
+    pub keep_reference_dropouts: bool,
+    /// Convert duplicated frames to drops
+    pub dupes_to_drops: bool,
+    /// Drop the frames with a stacked field that every input's RMSE pSNR is below this on, as the
+    /// inputs are all out of sync there rather than one of them being bad. `None` to write every
+    /// field. Can't be combined with [`resume`](Self::resume). The fields dropped this way still
+    /// count towards [`max_fields`](Self::max_fields) and [`max_duration`](Self::max_duration),
+    /// which then make fewer output fields
+    pub drop_on_max_rmse: Option<f32>,
+    /// Keep the `seqNo` of the reference input's field in each output field, instead of numbering
+    /// the output fields from 1
+    pub preserve_seq_no: bool,
+    /// How to combine the inputs
+    pub mode: StackMode,
+    /// The median of an even number of inputs, also used to fill dropouts with
+    /// [`StackMode::DropoutFill`]
+    pub even_median: EvenMedian,
+    /// What the luma is combined as. A median of an odd number of inputs is the same either way,
+    /// it makes a difference to the average of the two middle values and the means. Can't be
+    /// combined with [`StackMode::DropoutFill`]
+    pub median_space: MedianSpace,
+    /// Weight the inputs by how well they match the output in each field, so a clearly better
+    /// capture dominates. Only for [`StackMode::Median`] and [`StackMode::Mean`]
+    pub weighted: bool,
+    /// Take the samples before and after the RMSE window, where the head switch is, from the
+    /// stacked input with the best black pSNR in each field rather than combining them, so the
+    /// head switch noise of different inputs doesn't mix. Can't be combined with
+    /// [`StackMode::DropoutFill`]
+    pub edge_from_best: bool,
+    /// Match the luma levels of every input to the reference input's before combining them, with a
+    /// gain and offset taking its black level and average picture level to the reference's,
+    /// measured over the first stacked fields
+    pub level_match: bool,
+    /// Index of the input whose dropouts get filled by [`StackMode::DropoutFill`], unused by the
+    /// other modes
+    pub base_input: usize,
+    /// Index of the input the others follow: it must start on a first field, the other inputs are
+    /// kept in its field order, and the output metadata is based on its. With
+    /// [`StackMode::DropoutFill`], the metadata and alignment use the base input instead
+    pub reference_input: usize,
+    /// Align each input horizontally to the reference one, searching this many samples in both
+    /// directions (0 = off)
+    pub halign_range: usize,
+    /// Skip a field in inputs whose field order doesn't match the reference input's
+    pub fix_field_order: bool,
+    /// Keep the other inputs on the field whose sequence number is as far from their start as the
+    /// reference input's is, skipping and repeating fields as needed, instead of following each
+    /// input's own dupes and gaps
+    pub align_by_seq_no: bool,
+    /// What to do once an input ends
+    pub tail: TailMode,
+    /// What to do when an input's files don't match its metadata in size
+    pub size_mismatch: SizeMismatch,
+    /// The byte order of the samples in the inputs' raw `.tbc` files, FLAC compressed ones don't
+    /// depend on it
+    pub input_endian: Endian,
+    /// Refuse to start when two inputs look like the same capture, rather than only warning
+    pub reject_duplicate_inputs: bool,
+    /// Samples of the field to measure the output's black pSNR in, from the start of the field,
+    /// `None` for the system's default
+    pub bpsnr_window: Option<(usize, usize)>,
+    /// Samples of the field to measure the inputs' RMSE against the output in, from the start of
+    /// the field, `None` for the system's default. Widened to multiples of 32 samples
+    pub rmse_window: Option<(usize, usize)>,
+    /// Weigh the squared error of samples within this many samples of the RMSE window's edges
+    /// down towards them, so head switching noise creeping into the window counts less. Rounded up
+    /// to a multiple of 32 samples, 0 for none
+    pub rmse_edge_taper: usize,
+    /// Measure the RMSE pSNR of every input on each line of every this many output fields,
+    /// starting with the first, reported in [`FieldReport::line_rmse_psnr`]. 0 for none
+    pub line_metrics_every: usize,
+    /// When to warn about an input being bad or out of sync
+    pub rmse_warn: RmseWarn,
+    /// Leave an input out of the stack once it counted as bad for [`rmse_warn`](Self::rmse_warn)
+    /// for its streak of fields in a row, until it hasn't for as many. Can't be combined with
+    /// [`StackMode::DropoutFill`]
+    pub exclude_bad_inputs: bool,
+    /// Replace each input's field with its median with the input's this many earlier fields of
+    /// the same parity before stacking, 0 for none. Only for static content, such as test patterns
+    /// and freeze frames, as anything moving gets smeared. At most 14
+    pub temporal_prefilter: usize,
+    /// Where the side metadata of each output field comes from
+    pub side_metadata: SideMetadata,
+    /// What the VITS metrics of each output field other than the bPSNR are
+    pub vits_metrics: VitsMetricsMode,
+    /// The schema of the output metadata, `None` for the one of the input it is based on
+    pub metadata_version: Option<MetadataVersion>,
+    /// Also mark the output as having a dropout wherever an input deviates from it by more than
+    /// this many IRE, the places the stacking had to correct whether the decoder flagged a
+    /// dropout there or not. Can't be combined with [`resume`](Self::resume)
+    pub detect_dropouts: Option<f32>,
+    /// Where every stacked input has a dropout, interpolate the output along the line from the
+    /// samples on either side instead. The output still marks a dropout there
+    pub interpolate_common_dropouts: bool,
+    /// Also write a confidence map to this basename's `.tbc`: for every sample of the output
+    /// luma, the difference of the highest and lowest input
+    pub confidence_output: Option<String>,
+    /// Also write the difference of the output and the reference input to this basename's
+    /// `.tbc`: for every sample of the output luma, how far the stack moved it away from the
+    /// reference input, magnified for viewing
+    pub diff_output: Option<String>,
+    /// Compress the output `.tbc` files with FLAC, using the `flac` command line encoder
+    pub compress_output: bool,
+    /// Round the output luma and chroma samples to this many bits, 8 to 16, keeping them as 16-bit
+    /// samples with the low bits zero. Everything is stacked and measured at full precision
+    pub output_bits: u32,
+    /// Copy the EFM data of the reference input's `.efm`, from its first to its last field in the
+    /// output, to the output's `.efm`. LaserDisc captures carry their digital audio in it
+    pub efm_passthrough: bool,
+    /// Continue an interrupted run, appending to its existing output
+    pub resume: bool,
+    /// Replace existing output files, instead of refusing to start
+    pub overwrite: bool,
+    /// Only stack to measure how well the inputs match, without writing any output. Combine with
+    /// [`max_fields`](Self::max_fields) to check the start fields quickly
+    pub analyze: bool,
+    /// Number of worker threads stacking fields, `None` for the logical CPU count
+    pub threads: Option<usize>,
+    /// Upper bound in bytes for the field buffers and the I/O buffers of the `.tbc` files, which
+    /// get smaller to fit. `None` for I/O buffers of 256 fields each
+    pub memory_budget: Option<usize>,
+    /// Map the raw input `.tbc` files into memory instead of reading them through I/O buffers,
+    /// leaving the budget to the outputs
+    pub mmap_inputs: bool,
+}
+
+impl StackConfig {
+    /// A config stacking `inputs` into `output_basename` with the default settings.
+    pub fn new(inputs: Vec<InputConfig>, output_basename: String) -> Self {
+        StackConfig {
+            inputs,
+            output_basename,
+            seq_no_start: None,
+            seq_no_end: None,
+            max_fields: 0,
+            max_duration: None,
+            skip_output_fields: 0,
+            decimate: 1,
+            dropout_threshold: None,
+            keep_reference_dropouts: false,
+            dupes_to_drops: false,
+            drop_on_max_rmse: None,
+            preserve_seq_no: false,
+            mode: StackMode::Median,
+            even_median: EvenMedian::Avg,
+            median_space: MedianSpace::Raw,
+            weighted: false,
+            edge_from_best: false,
+            level_match: false,
+            base_input: 0,
+            reference_input: 0,
+            halign_range: 0,
+            fix_field_order: false,
+            align_by_seq_no: false,
+            tail: TailMode::Stop,
+            size_mismatch: SizeMismatch::Error,
+            input_endian: Endian::Le,
+            reject_duplicate_inputs: false,
+            bpsnr_window: None,
+            rmse_window: None,
+            rmse_edge_taper: 0,
+            line_metrics_every: 0,
+            rmse_warn: RmseWarn::default(),
+            exclude_bad_inputs: false,
+            temporal_prefilter: 0,
+            side_metadata: SideMetadata::Input(0),
+            vits_metrics: VitsMetricsMode::Recompute,
+            metadata_version: None,
+            detect_dropouts: None,
+            interpolate_common_dropouts: false,
+            confidence_output: None,
+            diff_output: None,
+            compress_output: false,
+            output_bits: 16,
+            efm_passthrough: false,
+            resume: false,
+            overwrite: false,
+            analyze: false,
+            threads: None,
+            memory_budget: None,
+            mmap_inputs: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -2,566 +2,1140 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-mod tbc_metadata;
-
-use crate::tbc_metadata::{System, TbcMetadata, VitsMetrics};
-use clap::Parser;
+mod config_file;
+mod input_list;
+mod outputs;
+mod progress;
+mod selftest;
+
+use crate::outputs::{Events, FieldMetrics, JsonArrayWriter};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressDrawTarget};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::time::Instant;
-use tracing::{info, span, trace, warn, Level};
+use std::io::IsTerminal;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tbc_raw_stack::{
+    Endian, EvenMedian, FieldKind, FieldReport, InputConfig, InputSummary, Inspection, MedianSpace,
+    MetadataVersion, Planes, RmseWarn, RunInfo, SideMetadata, SizeMismatch, StackConfig,
+    StackError, StackMode, StackObserver, TailMode, VitsMetricsMode, MAX_INPUT_STREAMS,
+};
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
+/// How the log is written to stderr.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, with the field being worked on as a structured `idx` in `span`
+    Json,
+}
+
+/// What to do instead of stacking.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Describe a capture's .tbc.json without stacking: its video parameters, seqNo dupes and gaps, and its dropouts every 1000 fields
+    Inspect {
+        /// Basename of the capture
+        basename: String,
+    },
+}
+
 /// Stack multiple tapes
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read options from this TOML file, with the inputs as [[input]] tables; options on the command line take precedence
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
     /// Input basenames
     #[arg(short, long)]
     input_basename: Vec<String>,
 
-    /// Field index to start with, for each input (1-based)
-    #[arg(short, long)]
+    /// Read more inputs from this file, one a line as basename[,start_field[,sample_offset[,weight]]], stacked after the --input-basename ones
+    #[arg(long, value_name = "FILE")]
+    input_list: Option<PathBuf>,
+
+    /// Field index to start with, for each input (1-based, counted from the start of that input)
+    #[arg(short, long, allow_negative_numbers = true, value_parser = parse_start_field, conflicts_with = "seqno_start")]
     start_field: Vec<usize>,
 
+    /// Start field for every input without its own --start-field; the --start-field values given go to the first inputs in order
+    #[arg(long, value_name = "N", allow_negative_numbers = true, value_parser = parse_start_field, conflicts_with = "seqno_start")]
+    start_field_all: Option<usize>,
+
+    /// Sequence number (seqNo in the .tbc.json) of the field to start with, for each input, instead of --start-field
+    #[arg(long, value_name = "SEQNO", conflicts_with_all = ["start_field", "start_field_all", "seqno_start"])]
+    start_seqno: Vec<usize>,
+
+    /// Start each input on its field with this sequence number (seqNo in the .tbc.json), instead of giving start fields
+    #[arg(long, value_name = "SEQNO")]
+    seqno_start: Option<usize>,
+
+    /// End each input with its field with this sequence number
+    #[arg(long, value_name = "SEQNO")]
+    seqno_end: Option<usize>,
+
+    /// Shift each input right by this many samples (left if negative) before stacking, one for each input if given
+    #[arg(long, allow_negative_numbers = true)]
+    sample_offset: Vec<isize>,
+
+    /// Move each input's lines down by this many (up if negative) before stacking, one for each input if given, for an input decoded whole lines off the others
+    #[arg(long, allow_negative_numbers = true)]
+    vshift: Vec<isize>,
+
+    /// How many times each input counts when combining, one for each input if given, so a better capture can outvote the others (median, mean and trimmed mean)
+    #[arg(long, conflicts_with = "weighted")]
+    input_weight: Vec<usize>,
+
+    /// Stack only the luma of this input (1-based), for a capture with bad chroma; can be given more than once
+    #[arg(long, value_name = "INPUT")]
+    luma_only: Vec<usize>,
+
+    /// Stack only the chroma of this input (1-based), for a capture with bad luma; can be given more than once
+    #[arg(long, value_name = "INPUT")]
+    chroma_only: Vec<usize>,
+
+    /// Swap black and white in the luma of this input (1-based), for a capture decoded with the wrong polarity; can be given more than once
+    #[arg(long, value_name = "INPUT")]
+    invert: Vec<usize>,
+
     /// Output basename
-    #[arg(short, long)]
-    output_basename: String,
+    #[arg(short, long, required_unless_present_any = ["analyze", "selftest"])]
+    output_basename: Option<String>,
 
     /// How many fields to process (0 = all)
     #[arg(short = 'c', long, default_value_t = 0)]
     max_fields: usize,
 
+    /// How many frames to process, instead of --max-fields
+    #[arg(long, conflicts_with = "max_fields")]
+    max_frames: Option<usize>,
+
+    /// Running time to process, as [[HH:]MM:]SS, at the frame rate of the inputs' system
+    #[arg(long, value_parser = parse_duration, conflicts_with_all = ["max_fields", "max_frames"])]
+    max_duration: Option<Duration>,
+
+    /// Leave out this many fields from the start of the output, to trim a lead-in (must be even)
+    #[arg(long, default_value_t = 0)]
+    skip_output_fields: usize,
+
+    /// Only write every Nth output field, for a quick preview of a long tape
+    #[arg(long, default_value_t = 1, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    decimate: u64,
+
+    /// Only check how well the inputs line up over this many fields (0 = all), without writing any output
+    #[arg(
+        long,
+        num_args = 0..=1,
+        default_missing_value = "1000",
+        value_name = "FIELDS",
+        conflicts_with_all = [
+            "max_fields",
+            "max_frames",
+            "max_duration",
+            "resume",
+            "compress_output",
+            "confidence_output",
+            "diff_output",
+            "efm_passthrough"
+        ]
+    )]
+    analyze: Option<usize>,
+
     /// How many inputs should agree on having a dropout to mark it as such [default: ceil(inputs_count / 2)]
     #[arg(short, long)]
     dropout_threshold: Option<usize>,
 
+    /// Also mark every dropout the reference input has in the output, however few of the other inputs agree
+    #[arg(long, default_value_t = false)]
+    keep_reference_dropouts: bool,
+
     /// Convert duplicated frames to drops
     #[arg(long, default_value_t = false)]
     dupes_to_drops: bool,
 
+    /// Drop the frames with a stacked field that every input's RMSE pSNR is below this on, as the inputs are all out of sync there. They still count towards --max-fields, --max-frames and --max-duration
+    #[arg(long, value_name = "PSNR", conflicts_with = "resume")]
+    drop_on_max_rmse: Option<f32>,
+
+    /// Keep the seqNo of the reference input's fields in the output, instead of numbering the output fields from 1
+    #[arg(long, default_value_t = false, conflicts_with = "verify")]
+    preserve_seqno: bool,
+
     /// If provided, write field mappings
     #[arg(long)]
     fieldmap_csv: Option<PathBuf>,
 
+    /// If provided, write for each output field whether it was stacked, a dupe or dropped, and which inputs had a dupe skipped or matched poorly
+    #[arg(long)]
+    decisions_csv: Option<PathBuf>,
+
+    /// If provided, write the problems found as ranges of output fields: inputs matching poorly, dupes, gaps, and the dupes and drops in the output
+    #[arg(long)]
+    events_csv: Option<PathBuf>,
+
     /// If provided, write RMSE pSNR
     #[arg(long)]
     metrics_csv: Option<PathBuf>,
-}
 
-struct InputTbc {
-    index: usize,
-    metadata: TbcMetadata,
-    tbc: BufReader<File>,
-    chroma: Option<BufReader<File>>,
-    field_index: usize,
-    dupe_count: usize,
-    last_seq_no: usize,
-}
+    /// If provided, write the RMSE pSNR of every input on each line of a sample of the fields, to find inputs shifted vertically
+    #[arg(long)]
+    line_metrics_csv: Option<PathBuf>,
 
-unsafe fn to_bytes<T>(input: &[T]) -> &[u8] {
-    let ptr = input as *const [T] as *const u8; // Cast slice of T to a slice of u8
-    let len = input.len() * size_of::<T>(); // Calculate the length in bytes
-    std::slice::from_raw_parts(ptr, len) // Create a slice of u8 from the raw pointer
-}
-unsafe fn to_bytes_mut<T>(input: &mut [T]) -> &mut [u8] {
-    let ptr = input as *mut [T] as *mut u8; // Cast slice of T to a mutable slice of u8
-    let len = input.len() * size_of::<T>(); // Calculate the length in bytes
-    std::slice::from_raw_parts_mut(ptr, len) // Create a mutable slice of u8 from the raw pointer
-}
+    /// Measure every this many fields for --line-metrics-csv, starting with the first
+    #[arg(long, default_value_t = 50, value_name = "FIELDS", value_parser = clap::value_parser!(u64).range(1..), requires = "line_metrics_csv")]
+    line_metrics_every: u64,
 
-const MAX_SAMPLES_PER_FIELD: usize = 0x57000;
-const MIN_INPUT_STREAMS: usize = 3;
-const MAX_INPUT_STREAMS: usize = 15;
+    /// If provided, write per-field metrics as JSON
+    #[arg(long)]
+    metrics_json: Option<PathBuf>,
 
-const RMSE_WARN_THRESHOLD: usize = 30;
+    /// If provided, write a summary of how each input did as JSON
+    #[arg(long)]
+    summary_json: Option<PathBuf>,
 
-// 355 255 PAL samples * 512 * 2 channels = ~347 MB per input
-// 347 MB * (15 input + 1 output) = 5.552 GB total memory usage
-// since 512 is also the default sector size, it may help with storage stuff too...
-const IO_BUFFER_MULTIPLIER: usize = 512;
+    /// How to combine the inputs
+    #[arg(long, value_enum, default_value_t = StackMode::Median)]
+    mode: StackMode,
 
-struct SystemConstants {
-    /// Start sample for calculating black pSNR
-    black_start_sample: usize,
+    /// Median of an even number of inputs: the average of the two middle values, or one of them
+    #[arg(long, value_enum, default_value_t = EvenMedian::Avg)]
+    even_median: EvenMedian,
 
-    /// End sample for calculating black pSNR
-    black_end_sample: usize,
+    /// Combine the luma as it is, or as the linear light it stands for, which changes the average of the two middle inputs and the means
+    #[arg(long, value_enum, default_value_t = MedianSpace::Raw)]
+    median_space: MedianSpace,
 
-    /// Start sample for calculating RMSE pSNR
-    useful_start_sample: usize,
+    /// Weight each input by how well it matches the output, so a clearly better capture dominates (median and mean only)
+    #[arg(long, default_value_t = false)]
+    weighted: bool,
 
-    /// End sample for calculating RMSE pSNR
-    useful_end_sample: usize,
+    /// Copy the parts of the field outside the RMSE window, where the head switch is, from the input with the best bPSNR in each field instead of combining them
+    #[arg(long, default_value_t = false)]
+    edge_from_best: bool,
 
-    /// Difference between black and white
-    psnr_scale: f32,
-}
+    /// Match the black and picture levels of every input to the reference input's before combining, for captures from different decks
+    #[arg(long, default_value_t = false)]
+    level_match: bool,
 
-impl SystemConstants {
-    fn error_to_psnr(&self, error: f32) -> f32 {
-        20. * (self.psnr_scale / error).log10()
-    }
-}
+    /// Input (1-based) whose dropouts get filled from the other inputs with --mode dropout-fill [default: 1]
+    #[arg(long, value_parser = input_number())]
+    base_input: Option<usize>,
 
-const SYSTEM_PAL: SystemConstants = SystemConstants {
-    black_start_sample: 24048,
-    black_end_sample: 24928, // 24 935 originally but we pick a nicer number
-    useful_start_sample: 61312, // line 55
-    useful_end_sample: 258752, // line 229
-    psnr_scale: 0.7 * (0xD300 - 0x0100) as f32,
-};
+    /// Input (1-based) the others follow: it must start on a first field, and the output metadata is based on it
+    #[arg(long, default_value_t = 1, value_parser = input_number())]
+    reference_input: usize,
 
-const SYSTEM_NTSC: SystemConstants = SystemConstants {
-    black_start_sample: 144,    // 143 originally
-    black_end_sample: 432,      // 429 originally
-    useful_start_sample: 27328, // line 31
-    useful_end_sample: 209280,  // line 231
-    psnr_scale: 0.75 * (0xC800 - 0x0400) as f32,
-};
+    /// Align each input horizontally to the reference one, searching this many samples in both directions (0 = off)
+    #[arg(long, default_value_t = 0)]
+    halign_range: usize,
 
-fn calculate_bpsnr(field: &[u16], constants: &SystemConstants) -> f32 {
-    let region = &field[constants.black_start_sample..constants.black_end_sample];
-    let len = region.len();
-    assert_eq!(len % 16, 0);
-    let mut sum = 0u32;
-    for chunk in region.chunks_exact(16) {
-        let chunk: &[u16; 16] = chunk.try_into().unwrap();
-        for v in chunk {
-            sum += *v as u32;
-        }
-    }
-    let mean = sum as f32 / len as f32;
-    let mut variance = 0f32;
-    for chunk in region.chunks_exact(16) {
-        let chunk: &[u16; 16] = chunk.try_into().unwrap();
-        for v in chunk {
-            let dev = *v as f32 - mean;
-            variance += dev * dev;
-        }
-    }
-    let stddev = (variance / len as f32).sqrt();
-    constants.error_to_psnr(stddev)
-}
+    /// Skip a field in inputs whose field order doesn't match the reference input's
+    #[arg(long, default_value_t = false)]
+    fix_field_order: bool,
 
-#[repr(align(64))]
-#[derive(Copy, Clone)]
-struct FieldBuffer([u16; MAX_SAMPLES_PER_FIELD]);
+    /// Keep the inputs on the field with the same sequence number, relative to the start field, as the reference input
+    #[arg(
+        long = "align-by-seqno",
+        default_value_t = false,
+        conflicts_with = "fix_field_order"
+    )]
+    align_by_seq_no: bool,
 
-impl Default for FieldBuffer {
-    fn default() -> Self {
-        FieldBuffer([0; MAX_SAMPLES_PER_FIELD]) // Initialize the array with zeros
-    }
-}
+    /// What to do once an input ends: stop, or keep copying the input with the most fields left
+    #[arg(long, value_enum, default_value_t = TailMode::Stop)]
+    tail: TailMode,
 
-fn main() {
-    let level = std::env::var("RUST_LOG").unwrap_or_else(|_| {
-        format!("{}=info", env!("CARGO_PKG_NAME").replace("-", "_")).to_string()
-    });
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::new(level.as_str()))
-        .init();
+    /// What to do when the size of an input's .tbc files doesn't match its metadata
+    #[arg(long, value_enum, default_value_t = SizeMismatch::Error)]
+    size_mismatch: SizeMismatch,
 
-    let args = Args::parse();
+    /// Byte order of the samples in the input .tbc files, which the decoders write little-endian (FLAC compressed ones don't depend on it)
+    #[arg(long, value_enum, default_value_t = Endian::Le)]
+    input_endian: Endian,
 
-    if !(MIN_INPUT_STREAMS..MAX_INPUT_STREAMS).contains(&args.input_basename.len()) {
-        panic!(
-            "Invalid number of inputs, must be between {MIN_INPUT_STREAMS} and {MAX_INPUT_STREAMS}"
-        );
-    }
+    /// Refuse to start when two inputs look like the same capture, instead of only warning
+    #[arg(long, default_value_t = false)]
+    no_duplicate_inputs: bool,
 
-    if args.input_basename.len() != args.start_field.len() {
-        panic!("Count of input parameters and start field parameters is not equal!");
-    }
+    /// Measure the output's black pSNR in these samples of the field, counted from its start [default: per system]
+    #[arg(long, num_args = 2, value_names = ["START", "END"])]
+    bpsnr_window: Option<Vec<usize>>,
 
-    let mut inputs = args
-        .input_basename
-        .iter()
-        .enumerate()
-        .map(|(i, p)| {
-            let json = p.clone() + ".tbc.json";
-            let tbc = p.clone() + ".tbc";
-            let chroma = p.clone() + "_chroma.tbc";
-            let start_field = args.start_field[i] - 1;
-
-            let metadata: TbcMetadata =
-                serde_json::from_reader(File::open(json).expect("Cannot open input JSON metadata"))
-                    .expect("Cannot parse JSON metadata");
-            let field_size =
-                metadata.video_parameters.field_height * metadata.video_parameters.field_width;
-            let field_bytes = field_size * 2;
-            let tbc_file = File::open(tbc).expect("Cannot open tbc file");
-            let mut tbc_file =
-                BufReader::with_capacity(field_size * IO_BUFFER_MULTIPLIER, tbc_file);
-            tbc_file
-                .seek(SeekFrom::Start((field_bytes * start_field) as u64))
-                .expect("Cannot seek to start field");
-            let chroma_file = match File::open(chroma) {
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
-                v => Some({
-                    let chroma_file = v.expect("Cannot open chroma file");
-                    let mut chroma_file =
-                        BufReader::with_capacity(field_size * IO_BUFFER_MULTIPLIER, chroma_file);
-                    chroma_file
-                        .seek(SeekFrom::Start((field_bytes * start_field) as u64))
-                        .expect("Cannot seek to start field");
-                    chroma_file
-                }),
-            };
-            InputTbc {
-                index: i,
-                metadata,
-                tbc: tbc_file,
-                chroma: chroma_file,
-                field_index: start_field,
-                dupe_count: start_field % 2,
-                last_seq_no: 0,
-            }
-        })
-        .collect::<Vec<_>>();
+    /// Measure the inputs' RMSE pSNR in these samples of the field, counted from its start [default: per system]
+    #[arg(long, num_args = 2, value_names = ["START", "END"])]
+    rmse_window: Option<Vec<usize>>,
 
-    if inputs[0].dupe_count != 0 {
-        panic!("The first input must have correct field order!")
-    }
+    /// Weigh the squared error of samples within this many samples of the RMSE window's edges down towards them, so head switching noise creeping into the window doesn't trip the warnings (0 = off)
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    rmse_edge_taper: usize,
 
-    let system = inputs[0].metadata.video_parameters.system.clone();
-    let sys = if system == System::Pal {
-        &SYSTEM_PAL
-    } else {
-        &SYSTEM_NTSC
-    };
+    /// Count an input's field as bad when its RMSE pSNR is below this
+    #[arg(long, default_value_t = RmseWarn::default().psnr)]
+    rmse_warn_psnr: f32,
 
-    let have_chroma = inputs[0].chroma.is_some();
+    /// Count it as bad only if it is also this much below the average RMSE pSNR of the other inputs
+    #[arg(long, default_value_t = RmseWarn::default().delta)]
+    rmse_warn_delta: f32,
 
-    let dropout_threshold = args.dropout_threshold.unwrap_or(inputs.len().div_ceil(2));
+    /// Count an input's field as suspiciously high when its RMSE pSNR is this much above the average of the other inputs
+    #[arg(long, default_value_t = RmseWarn::default().high_delta)]
+    rmse_warn_high_delta: f32,
 
-    let field_width = inputs[0].metadata.video_parameters.field_width;
-    let field_height = inputs[0].metadata.video_parameters.field_height;
-    let field_size = field_width * field_height;
-    let field_size_rounded = field_size.div_ceil(32) * 32;
+    /// Warn about an input after this many bad or suspiciously high fields in a row
+    #[arg(long, default_value_t = RmseWarn::default().streak)]
+    rmse_warn_streak: usize,
 
-    let max_fields = args.max_fields;
+    /// Leave an input out of the stack after that many bad fields in a row, until it has as many good ones in a row
+    #[arg(long, default_value_t = false)]
+    exclude_bad_inputs: bool,
 
-    let mut out_luma = {
-        let path = args.output_basename.clone() + ".tbc";
-        let file = File::create_new(path).expect("Cannot create tbc file");
-        BufWriter::with_capacity(field_size * IO_BUFFER_MULTIPLIER, file)
-    };
-    let mut out_chroma = if have_chroma {
-        let path = args.output_basename.clone() + "_chroma.tbc";
-        let file = File::create_new(path).expect("Cannot create tbc file");
-        Some(BufWriter::with_capacity(
-            field_size * IO_BUFFER_MULTIPLIER,
-            file,
-        ))
-    } else {
-        None
-    };
-    let mut out_fields: Vec<tbc_metadata::Field> = Vec::new();
-    let mut out_metrics = args.metrics_csv.map(|f| {
-        let file = File::create_new(f).expect("Cannot open metrics file");
-        BufWriter::new(file)
-    });
-    let mut out_fieldmap = args.fieldmap_csv.map(|f| {
-        let file = File::create_new(f).expect("Cannot open metrics file");
-        BufWriter::new(file)
-    });
-
-    let mut dupes_written = 0usize;
-
-    let mut new_luma = Box::new(<FieldBuffer>::default());
-    let new_luma = &mut new_luma.0.as_mut_slice()[0..field_size_rounded];
-    let mut new_chroma = Box::new(<FieldBuffer>::default());
-    let new_chroma = &mut new_chroma.0.as_mut_slice()[0..field_size_rounded];
-    let mut new_field = inputs[0].metadata.fields[inputs[0].field_index].clone();
-
-    let mut in_luma = vec![<FieldBuffer>::default(); inputs.len()];
-    let mut in_luma = in_luma.iter_mut().map(|f| f.0.as_mut()).collect::<Vec<_>>();
-    let mut in_chroma = vec![<FieldBuffer>::default(); inputs.len()];
-    let mut in_chroma = in_chroma
-        .iter_mut()
-        .map(|f| f.0.as_mut())
-        .collect::<Vec<_>>();
+    /// Median each input's field with its this many earlier fields of the same parity before stacking, only for static content like test patterns and freeze frames
+    #[arg(long, default_value_t = 0, value_name = "FIELDS")]
+    temporal_prefilter: usize,
 
-    let mut sse_luma = vec![0u64; inputs.len()];
-    let mut sse_luma_edge = vec![0u64; inputs.len()];
-    let mut sse_chroma = vec![0u64; inputs.len()];
-    let mut rmse_bad_in_a_row = vec![0usize; inputs.len()];
+    /// Take the VBI, closed caption and field phase metadata of each field from this input (1-based) [default: the reference input]
+    #[arg(long, value_parser = input_number(), conflicts_with = "side_metadata_vote")]
+    side_metadata_input: Option<usize>,
 
-    let now = Instant::now();
+    /// Take the VBI, closed caption and field phase metadata of each field from the majority of inputs
+    #[arg(long, default_value_t = false)]
+    side_metadata_vote: bool,
+
+    /// What the VITS metrics of each output field other than the bPSNR, which is measured on the output, are taken from
+    #[arg(long, value_enum, default_value_t = VitsMetricsMode::Recompute)]
+    vits_metrics: VitsMetricsMode,
+
+    /// Write the output .tbc.json in this schema, instead of the one of the input it is based on
+    #[arg(long, value_enum)]
+    metadata_version: Option<MetadataVersion>,
+
+    /// Also mark dropouts wherever an input deviates from the output by more than this many IRE
+    #[arg(long, value_name = "IRE", conflicts_with = "resume")]
+    detect_dropouts: Option<f32>,
+
+    /// Where every input has a dropout, interpolate the output from the samples on either side on the same line
+    #[arg(long, default_value_t = false)]
+    interpolate_common_dropouts: bool,
+
+    /// If provided, write a confidence map with how far apart the inputs are at each sample, viewable like a TBC
+    #[arg(long)]
+    confidence_output: Option<String>,
+
+    /// If provided, write how far the output is from the reference input at each sample, magnified, viewable like a TBC
+    #[arg(long, value_name = "BASENAME")]
+    diff_output: Option<String>,
+
+    /// Compress the output .tbc files with FLAC, needs the flac command line encoder
+    #[arg(long, default_value_t = false, conflicts_with = "resume")]
+    compress_output: bool,
+
+    /// Round the output samples to this many bits, still stored as 16-bit samples with the low bits zero, which FLAC compresses away with --compress-output
+    #[arg(long, default_value_t = 16, value_name = "BITS", value_parser = clap::value_parser!(u32).range(8..=16))]
+    output_bits: u32,
+
+    /// Copy the reference input's .efm (LaserDisc EFM audio data), from its first to its last field in the output, to the output's .efm
+    #[arg(long, default_value_t = false)]
+    efm_passthrough: bool,
+
+    /// Continue an interrupted run, appending to its existing output
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    /// Overwrite the output and side output files if they exist, instead of refusing to start
+    #[arg(
+        long,
+        visible_alias = "overwrite",
+        default_value_t = false,
+        conflicts_with = "resume"
+    )]
+    force: bool,
+
+    /// Once done, read the output's metadata and files back and check that they are consistent. Without any inputs, only check an existing output
+    #[arg(long, default_value_t = false, conflicts_with = "analyze")]
+    verify: bool,
+
+    /// Don't show a progress bar
+    #[arg(long, default_value_t = false)]
+    no_progress: bool,
 
-    let mut drop_next = false;
+    /// Only log warnings and errors
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
 
-    loop {
-        let new_field_idx = out_fields.len();
+    /// How to write the log
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 
-        let _span = span!(Level::INFO, "field", idx = new_field_idx + 1).entered();
+    /// Number of worker threads stacking fields [default: logical CPU count]
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
 
-        if max_fields != 0 && out_fields.len() == max_fields {
-            // we exported the requested count of fields
-            break;
+    /// Check the median kernels against a plain implementation on this many synthetic fields for every count of inputs, and log their speed, without stacking anything
+    #[arg(long, hide = true, num_args = 0..=1, default_missing_value = "100", value_name = "FIELDS")]
+    selftest: Option<usize>,
+
+    /// Memory to stay under for the field and I/O buffers in MiB, shrinking the I/O buffers to fit
+    #[arg(long, value_name = "MIB")]
+    memory_budget: Option<usize>,
+
+    /// Map the input .tbc files into memory instead of reading them through I/O buffers, for the largest stacks
+    #[arg(long)]
+    max_memory_fail_safe: bool,
+}
+
+/// Streams the optional side outputs and drives the progress bar while stacking.
+struct CliObserver {
+    resume: bool,
+    force: bool,
+    show_progress: bool,
+    progress: ProgressBar,
+    rmse_warn: RmseWarn,
+    inputs: usize,
+    fieldmap_csv: Option<PathBuf>,
+    decisions_csv: Option<PathBuf>,
+    events_csv: Option<PathBuf>,
+    metrics_csv: Option<PathBuf>,
+    metrics_json: Option<PathBuf>,
+    line_metrics_csv: Option<PathBuf>,
+    out_metrics: Option<BufWriter<File>>,
+    out_metrics_json: Option<JsonArrayWriter<BufWriter<File>>>,
+    out_fieldmap: Option<BufWriter<File>>,
+    out_decisions: Option<BufWriter<File>>,
+    out_line_metrics: Option<BufWriter<File>>,
+    /// The events collected so far and their file, written out at the end.
+    out_events: Option<(Events, BufWriter<File>)>,
+    /// Set on Ctrl-C, stops the run after the field being written.
+    interrupted: Arc<AtomicBool>,
+}
+
+impl CliObserver {
+    /// Flushes and closes the side outputs.
+    fn finish(self) -> Result<(), StackError> {
+        if let Some(mut out_metrics) = self.out_metrics {
+            out_metrics.flush()?;
+        }
+        if let Some(mut out_fieldmap) = self.out_fieldmap {
+            out_fieldmap.flush()?;
+        }
+        if let Some(mut out_decisions) = self.out_decisions {
+            out_decisions.flush()?;
+        }
+        if let Some(mut out_line_metrics) = self.out_line_metrics {
+            out_line_metrics.flush()?;
         }
+        if let Some((events, mut out_events)) = self.out_events {
+            events.write(&mut out_events)?;
+            out_events.flush()?;
+        }
+        if let Some(out_metrics_json) = self.out_metrics_json {
+            out_metrics_json.finish()?;
+        }
+        Ok(())
+    }
+}
 
-        if inputs
-            .iter()
-            .any(|i| i.field_index == i.metadata.fields.len())
-        {
-            // one of the inputs ended
-            break;
+/// Parses a start field, explaining that it isn't an offset from another input if it's negative.
+fn parse_start_field(s: &str) -> Result<usize, String> {
+    match s.parse::<isize>() {
+        Ok(v) if v < 0 => Err(format!(
+            "start field {v} is negative, but start fields aren't offsets from another input: give \
+             each input the 1-based index of the field to start with in that input"
+        )),
+        _ => s.parse::<usize>().map_err(|e| e.to_string()),
+    }
+}
+
+/// Parses a 1-based input number.
+fn input_number() -> clap::builder::RangedU64ValueParser<usize> {
+    clap::builder::RangedU64ValueParser::new().range(1..=MAX_INPUT_STREAMS as u64)
+}
+
+/// Parses a running time given as `[[HH:]MM:]SS`, the seconds possibly fractional.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration `{s}`, expected [[HH:]MM:]SS");
+    if s.matches(':').count() > 2 {
+        return Err(invalid());
+    }
+    let mut parts = s.rsplit(':');
+    let seconds = parts
+        .next()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| v.is_finite() && *v >= 0.)
+        .ok_or_else(invalid)?;
+    let mut whole = 0u64;
+    for (part, scale) in parts.zip([60, 3600]) {
+        whole += part.parse::<u64>().map_err(|_| invalid())? * scale;
+    }
+    Ok(Duration::from_secs(whole) + Duration::from_secs_f64(seconds))
+}
+
+/// Formats a running time in seconds as `HH:MM:SS.mmm`.
+fn format_duration(secs: f64) -> String {
+    let millis = (secs * 1000.).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Creates a side output file, truncating an existing one only if `overwrite`.
+fn create(path: &Path, overwrite: bool) -> Result<File, StackError> {
+    if overwrite {
+        File::create(path)
+    } else {
+        File::create_new(path)
+    }
+    .map_err(|source| match source.kind() {
+        io::ErrorKind::AlreadyExists => StackError::OutputExists { path: path.into() },
+        _ => StackError::Create {
+            path: path.into(),
+            source,
+        },
+    })
+}
+
+impl StackObserver for CliObserver {
+    fn start(
+        &mut self,
+        run: &RunInfo,
+        fields: usize,
+        resumed_fields: usize,
+    ) -> Result<(), StackError> {
+        if let Some(f) = self.metrics_csv.take() {
+            let file = if self.resume {
+                outputs::open_csv(&f, resumed_fields)?
+            } else {
+                create(&f, self.force)?
+            };
+            self.out_metrics = Some(BufWriter::new(file));
+        }
+        if let Some(f) = self.metrics_json.take() {
+            // can't append to a finished JSON document, the resumed fields are left out instead
+            let file = create(&f, self.resume || self.force)?;
+            self.out_metrics_json =
+                Some(JsonArrayWriter::new(BufWriter::new(file), run, "fields")?);
+        }
+        if let Some(f) = self.fieldmap_csv.take() {
+            let file = if self.resume {
+                outputs::open_csv(&f, resumed_fields)?
+            } else {
+                create(&f, self.force)?
+            };
+            self.out_fieldmap = Some(BufWriter::new(file));
+        }
+        if let Some(f) = self.decisions_csv.take() {
+            let file = if self.resume {
+                outputs::open_csv(&f, resumed_fields)?
+            } else {
+                create(&f, self.force)?
+            };
+            self.out_decisions = Some(BufWriter::new(file));
+        }
+        if let Some(f) = self.events_csv.take() {
+            // the events of the resumed fields are kept, those past them dropped
+            let file = if self.resume {
+                outputs::open_csv(&f, resumed_fields)?
+            } else {
+                create(&f, self.force)?
+            };
+            self.out_events = Some((Events::default(), BufWriter::new(file)));
+        }
+        if let Some(f) = self.line_metrics_csv.take() {
+            let file = if self.resume {
+                outputs::open_csv(&f, resumed_fields)?
+            } else {
+                create(&f, self.force)?
+            };
+            self.out_line_metrics = Some(BufWriter::new(file));
         }
+        self.inputs = run.inputs.len();
 
-        let mut should_write_dupe = false;
-        for f in &mut inputs {
-            if f.metadata.fields[f.field_index].seq_no <= f.last_seq_no {
-                warn!(
-                    "Dupe in input #{}, at field {}",
-                    f.index + 1,
-                    f.field_index + 1
-                );
-                if f.dupe_count % 2 == dupes_written % 2 {
-                    // we only actually write out a dupe if it looks "new"
-                    should_write_dupe = true;
-                }
-                f.dupe_count += 1;
-                f.field_index += 1;
-                f.tbc.seek_relative((field_size * 2) as i64).unwrap();
-                if let Some(chroma) = f.chroma.as_mut() {
-                    chroma.seek_relative((field_size * 2) as i64).unwrap();
-                }
-            }
+        if self.show_progress {
+            self.progress.set_length(fields as u64);
+            self.progress.set_style(progress::style());
+            self.progress.set_draw_target(ProgressDrawTarget::stderr());
         }
+        Ok(())
+    }
 
-        // let's check it again after the dupe skipping
-        if inputs
-            .iter()
-            .any(|i| i.field_index == i.metadata.fields.len())
-        {
-            break;
+    fn field(&mut self, report: &FieldReport) -> Result<(), StackError> {
+        if report.kind == FieldKind::Resumed {
+            self.progress.inc(1);
+            return Ok(());
+        }
+        // only once past the resumed fields, stopping among them would cut the output short
+        if self.interrupted.load(Ordering::Relaxed) {
+            return Err(StackError::Interrupted);
         }
 
-        if should_write_dupe {
-            dupes_written += 1;
-            if args.dupes_to_drops {
-                warn!("Dropping dupe field and the following one");
-                drop_next = true;
-                continue;
-            } else {
-                warn!("Writing out dupe");
-            }
-        } else {
-            {
-                new_field.seq_no = new_field_idx + 1;
-                let str = inputs
+        if let Some(decisions) = self.out_decisions.as_mut() {
+            let row = outputs::decision_row(report, &self.rmse_warn, self.inputs);
+            decisions.write_all(row.as_bytes())?;
+        }
+        if let Some((events, _)) = self.out_events.as_mut() {
+            events.add(report, &self.rmse_warn);
+        }
+
+        // a dropped field isn't in the output, only the decisions show it
+        if report.kind == FieldKind::Dropped {
+            return Ok(());
+        }
+
+        if let Some(sources) = &report.sources {
+            if let Some(fieldmap) = self.out_fieldmap.as_mut() {
+                let str = sources
                     .iter()
-                    .map(|i| (i.field_index + 1).to_string())
+                    .map(|v| v.to_string())
                     .collect::<Vec<_>>()
                     .join(",");
-                trace!("Generating from fields {}", str);
-                if let Some(fieldmap) = out_fieldmap.as_mut() {
-                    fieldmap
-                        .write_all(format!("{},{}\n", new_field_idx + 1, str).as_bytes())
-                        .unwrap();
-                }
-            }
-
-            new_field = inputs[0].metadata.fields[inputs[0].field_index].clone();
-
-            for i in 0..inputs.len() {
-                inputs[i]
-                    .tbc
-                    .read_exact(unsafe { to_bytes_mut(&mut in_luma[i][0..field_size]) })
-                    .unwrap();
-                if let Some(chroma) = inputs[i].chroma.as_mut() {
-                    chroma
-                        .read_exact(unsafe { to_bytes_mut(&mut in_chroma[i][0..field_size]) })
-                        .unwrap();
-                }
+                fieldmap.write_all(format!("{},{}\n", report.field, str).as_bytes())?;
             }
+        }
 
-            // We calculate median luma in 3 parts, because we only want the SSE of the middle bits.
-            // The rest may be garbage due to head switch, and we don't want it to skew the numbers.
-            median::batch_n(
-                &mut new_luma[0..sys.useful_start_sample],
-                in_luma
-                    .iter()
-                    .map(|f| &(**f)[0..sys.useful_start_sample])
-                    .collect::<Vec<_>>()
-                    .as_slice(),
-                &mut sse_luma_edge[..],
-            );
-            median::batch_n(
-                &mut new_luma[sys.useful_start_sample..sys.useful_end_sample],
-                in_luma
+        if !report.rmse_psnr.is_empty() {
+            if let Some(metrics) = self.out_metrics.as_mut() {
+                // the chroma columns follow the luma ones, if there is chroma, then the inputs'
+                // bPSNR
+                let str = report
+                    .rmse_psnr
                     .iter()
-                    .map(|f| &(**f)[sys.useful_start_sample..sys.useful_end_sample])
+                    .chain(&report.chroma_rmse_psnr)
+                    .chain(&report.input_bpsnr)
+                    .map(|v| format!("{}", v))
                     .collect::<Vec<_>>()
-                    .as_slice(),
-                &mut sse_luma[..],
-            );
-            median::batch_n(
-                &mut new_luma[sys.useful_end_sample..field_size_rounded],
-                in_luma
-                    .iter()
-                    .map(|f| &(**f)[sys.useful_end_sample..field_size_rounded])
-                    .collect::<Vec<_>>()
-                    .as_slice(),
-                &mut sse_luma_edge[..],
-            );
-
-            if have_chroma {
-                median::batch_n(
-                    new_chroma,
-                    in_chroma
-                        .iter()
-                        .map(|f| &(**f)[0..field_size_rounded])
-                        .collect::<Vec<_>>()
-                        .as_slice(),
-                    &mut sse_chroma[..],
-                );
+                    .join(",");
+                metrics.write_all(format!("{},{}\n", report.field, str).as_bytes())?;
+            }
+            if let Some(metrics) = self.out_metrics_json.as_mut() {
+                metrics.push(&FieldMetrics {
+                    field: report.field,
+                    rmse_psnr: &report.rmse_psnr,
+                    chroma_rmse_psnr: &report.chroma_rmse_psnr,
+                    bpsnr: report.bpsnr,
+                    input_bpsnr: &report.input_bpsnr,
+                    dupe: report.kind == FieldKind::Dupe,
+                    input_dupes: report.input_dupes.iter().map(|i| i + 1).collect(),
+                    dropouts: report.dropouts,
+                })?;
             }
+        }
+        if let Some(line_metrics) = self.out_line_metrics.as_mut() {
+            for (line, psnr) in report.line_rmse_psnr.iter().enumerate() {
+                let row = outputs::line_metrics_row(report.field, line, psnr);
+                line_metrics.write_all(row.as_bytes())?;
+            }
+        }
+        self.progress.inc(1);
+        Ok(())
+    }
+}
 
-            new_field.vits_metrics = Some(VitsMetrics {
-                bpsnr: calculate_bpsnr(&new_luma[0..field_size], sys) as f64,
-                other: Default::default(),
-            });
+/// Logs a table of how each input did.
+fn log_summary(inputs: &[InputSummary]) {
+    let psnr = |v: Option<f32>| v.map_or("-".to_string(), |v| format!("{v:.2}"));
+    info!("Input  Mean pSNR  Median pSNR  Bad fields  Dupes  Gaps  Outlier fields  Basename");
+    for (i, input) in inputs.iter().enumerate() {
+        info!(
+            "{:<5}  {:>9}  {:>11}  {:>10}  {:>5}  {:>4}  {:>14}  {}",
+            format!("#{}", i + 1),
+            psnr(input.mean_rmse_psnr),
+            psnr(input.median_rmse_psnr),
+            input.bad_fields,
+            input.dupes,
+            input.gaps,
+            input.outlier_fields,
+            input.basename
+        );
+    }
+}
 
-            #[derive(PartialEq, Eq)]
-            enum Dropout {
-                Start,
-                End,
-            }
+/// Logs, for each input, whether it lined up with the others, and where it stopped doing so if
+/// it didn't.
+fn log_analysis(fields: &[FieldReport], rmse_warn: &RmseWarn, inputs: usize) {
+    let stacked = fields
+        .iter()
+        .filter(|f| f.kind == FieldKind::Stacked && !f.rmse_psnr.is_empty())
+        .collect::<Vec<_>>();
+    let mut all_good = true;
+    for i in 0..inputs {
+        let mut bad = stacked.iter().filter(|f| rmse_warn.is_bad(&f.rmse_psnr, i));
+        let Some(first) = bad.next() else {
+            continue;
+        };
+        all_good = false;
+        warn!(
+            "Input #{} matched poorly in {} of {} fields, first at output field {}. Check its start field, or look for a desync there.",
+            i + 1,
+            bad.count() + 1,
+            stacked.len(),
+            first.field
+        );
+    }
+    for i in 0..inputs {
+        let mut high = stacked
+            .iter()
+            .filter(|f| rmse_warn.is_high(&f.rmse_psnr, i));
+        let Some(first) = high.next() else {
+            continue;
+        };
+        all_good = false;
+        warn!(
+            "Input #{} matched suspiciously better than the others in {} of {} fields, first at output field {}. Is it a duplicate of another input, or do the others have no picture there?",
+            i + 1,
+            high.count() + 1,
+            stacked.len(),
+            first.field
+        );
+    }
+    if all_good {
+        info!(
+            "All inputs line up over the {} fields analyzed",
+            stacked.len()
+        );
+    }
+}
 
-            let mut flat_dropouts = inputs
-                .iter()
-                .flat_map(|i| {
-                    if let Some(dropouts) = &i.metadata.fields[i.field_index].drop_outs {
-                        let mut out = vec![];
-                        for j in 0..dropouts.field_line.len() {
-                            let line = dropouts.field_line[j];
-                            if line >= field_height {
-                                continue; // WTF?
-                            }
-                            let startx = dropouts.startx[j];
-                            let endx = dropouts.endx[j];
-                            out.push((line * field_width + startx, Dropout::Start));
-                            out.push((line * field_width + endx, Dropout::End));
-                        }
-                        out
-                    } else {
-                        vec![]
-                    }
-                })
-                .collect::<Vec<_>>();
-            flat_dropouts.sort_unstable_by(|a, b| a.0.cmp(&b.0));
-
-            new_field.drop_outs = if flat_dropouts.is_empty() {
-                None
-            } else {
-                let mut out_dropouts = tbc_metadata::DropOuts {
-                    field_line: vec![],
-                    startx: vec![],
-                    endx: vec![],
-                };
-                let mut depth = 0usize;
-                let mut start = 0usize;
-                for (sample, do_type) in flat_dropouts {
-                    if do_type == Dropout::Start {
-                        depth += 1;
-                        if depth == dropout_threshold {
-                            start = sample;
-                        }
-                    } else {
-                        if depth == dropout_threshold {
-                            let line = start / field_width;
-                            let startx = start - line * field_width;
-                            let endx = sample - line * field_width;
-                            out_dropouts.field_line.push(line);
-                            out_dropouts.startx.push(startx);
-                            out_dropouts.endx.push(endx);
-                        }
-                        depth -= 1;
-                    }
-                }
-                Some(out_dropouts)
-            };
+fn main() -> ExitCode {
+    let args = match config_file::args_with_config(Args::command(), std::env::args_os().collect()) {
+        Ok(args) => Args::parse_from(args),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let crate_name = env!("CARGO_PKG_NAME").replace("-", "_");
+    let level = match std::env::var("RUST_LOG") {
+        _ if args.quiet => format!("{crate_name}=warn"),
+        Ok(level) => level,
+        Err(_) => format!("{crate_name}=info"),
+    };
+    // Hidden until we know what we're processing
+    let progress = ProgressBar::hidden();
+    let log = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(level.as_str()))
+        .with_writer(progress::ProgressWriter(progress.clone()));
+    match args.log_format {
+        LogFormat::Text => log.with_ansi(io::stderr().is_terminal()).init(),
+        LogFormat::Json => log.json().init(),
+    }
 
-            for i in &mut inputs {
-                i.last_seq_no = i.metadata.fields[i.field_index].seq_no;
-                i.field_index += 1;
+    let result = run(args, &progress);
+    progress.finish_and_clear();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            match e {
+                StackError::OutputExists { .. } => eprintln!("Use --force to overwrite it"),
+                // the usual status of a process stopped by SIGINT
+                StackError::Interrupted => return ExitCode::from(130),
+                _ => {}
             }
+            ExitCode::FAILURE
         }
+    }
+}
 
-        if drop_next {
-            drop_next = false;
-            continue;
-        }
+/// Checks a written output, logging every problem found.
+fn verify(basename: String) -> Result<(), StackError> {
+    let problems = tbc_raw_stack::verify_output(&basename)?;
+    for problem in &problems {
+        error!("{problem}");
+    }
+    if !problems.is_empty() {
+        return Err(StackError::Verify {
+            basename,
+            problems: problems.len(),
+        });
+    }
+    info!("Output verified");
+    Ok(())
+}
+
+/// Prints what [`tbc_raw_stack::inspect`] found in a capture's metadata.
+fn inspect(basename: &str) -> Result<(), StackError> {
+    let Inspection {
+        video_parameters: params,
+        fields,
+        dupes,
+        gaps,
+        field_order_breaks,
+        dropouts,
+    } = tbc_raw_stack::inspect(basename)?;
+    let system = &params.system;
+    println!("System: {system}");
+    println!("Field size: {}x{}", params.field_width, params.field_height);
+    println!(
+        "Fields: {fields} ({})",
+        format_duration(fields as f64 / (system.frame_rate() * 2.))
+    );
+    if params.number_of_sequential_fields != fields {
+        println!(
+            "The video parameters say there are {} fields",
+            params.number_of_sequential_fields
+        );
+    }
+    let mut other = params.other.iter().collect::<Vec<_>>();
+    other.sort_unstable_by_key(|&(key, _)| key);
+    for (key, value) in other {
+        println!("{key}: {value}");
+    }
+
+    // where each kind of problem first happens, with how often it does
+    let first = |fields: &[usize]| {
+        fields
+            .first()
+            .map_or(String::new(), |f| format!(", first at field {f}"))
+    };
+    println!("Dupes: {}{}", dupes.len(), first(&dupes));
+    let gap_fields = gaps.iter().map(|&(field, _)| field).collect::<Vec<_>>();
+    println!(
+        "Gaps: {}, {} fields missing{}",
+        gaps.len(),
+        gaps.iter().map(|&(_, missing)| missing).sum::<usize>(),
+        first(&gap_fields)
+    );
+    println!(
+        "Field order breaks: {}{}",
+        field_order_breaks.len(),
+        first(&field_order_breaks)
+    );
+
+    let field_size = params.field_width * params.field_height;
+    println!("Fields       Dropouts  Samples  Per field  Of samples");
+    for block in dropouts {
+        let samples = (block.fields * field_size).max(1);
+        println!(
+            "{:<11}  {:>8}  {:>7}  {:>9.2}  {:>9.4}%",
+            format!(
+                "{}-{}",
+                block.first_field,
+                block.first_field + block.fields - 1
+            ),
+            block.dropouts,
+            block.samples,
+            block.dropouts as f64 / block.fields as f64,
+            block.samples as f64 * 100. / samples as f64
+        );
+    }
+    Ok(())
+}
+
+fn run(args: Args, progress: &ProgressBar) -> Result<(), StackError> {
+    if let Some(Command::Inspect { basename }) = &args.command {
+        return inspect(basename);
+    }
+    if let Some(fields) = args.selftest {
+        return selftest::run(fields);
+    }
+    if let Some(basename) = args
+        .output_basename
+        .clone()
+        .filter(|_| args.verify && args.input_basename.is_empty() && args.input_list.is_none())
+    {
+        return verify(basename);
+    }
+    if args.start_field_all.is_some() && args.start_field.len() > args.input_basename.len() {
+        return Err(StackError::InvalidOption(
+            "There are more start field parameters than input parameters!".into(),
+        ));
+    }
+    if args.seqno_start.is_none()
+        && args.start_seqno.is_empty()
+        && args.start_field_all.is_none()
+        && args.input_basename.len() != args.start_field.len()
+    {
+        return Err(StackError::InvalidOption(
+            "Count of input parameters and start field parameters is not equal!".into(),
+        ));
+    }
+    if !args.start_seqno.is_empty() && args.start_seqno.len() != args.input_basename.len() {
+        return Err(StackError::InvalidOption(
+            "Count of input parameters and start seqNo parameters is not equal!".into(),
+        ));
+    }
+    if !args.sample_offset.is_empty() && args.sample_offset.len() != args.input_basename.len() {
+        return Err(StackError::InvalidOption(
+            "Count of input parameters and sample offset parameters is not equal!".into(),
+        ));
+    }
+    if !args.vshift.is_empty() && args.vshift.len() != args.input_basename.len() {
+        return Err(StackError::InvalidOption(
+            "Count of input parameters and vshift parameters is not equal!".into(),
+        ));
+    }
+    if !args.input_weight.is_empty() && args.input_weight.len() != args.input_basename.len() {
+        return Err(StackError::InvalidOption(
+            "Count of input parameters and input weight parameters is not equal!".into(),
+        ));
+    }
+    if args.base_input.is_some() && args.mode != StackMode::DropoutFill {
+        return Err(StackError::InvalidOption(
+            "--base-input only applies to --mode dropout-fill".into(),
+        ));
+    }
 
-        {
-            let useful_size = sys.useful_end_sample - sys.useful_start_sample;
-            let rmse_psnr = sse_luma
-                .iter()
-                .map(|f| sys.error_to_psnr((*f as f32 / useful_size as f32).sqrt()))
-                .collect::<Vec<_>>();
-
-            let str = rmse_psnr
-                .iter()
-                .map(|v| format!("{}", v))
-                .collect::<Vec<_>>()
-                .join(",");
-            trace!("RMSE pSNR: {}", str);
-            if let Some(metrics) = out_metrics.as_mut() {
-                metrics
-                    .write_all(format!("{},{}\n", new_field_idx + 1, str).as_bytes())
-                    .unwrap();
+    let mut inputs = args
+        .input_basename
+        .iter()
+        .enumerate()
+        .map(|(i, basename)| InputConfig {
+            basename: basename.clone(),
+            // found by sequence number with --start-seqno or --seqno-start
+            start_field: args
+                .start_field
+                .get(i)
+                .copied()
+                .or(args.start_field_all)
+                .unwrap_or(1),
+            start_seq_no: args.start_seqno.get(i).copied(),
+            sample_offset: args.sample_offset.get(i).copied().unwrap_or(0),
+            vshift: args.vshift.get(i).copied().unwrap_or(0),
+            weight: args.input_weight.get(i).copied().unwrap_or(1),
+            planes: Planes::Both,
+            invert: false,
+        })
+        .collect::<Vec<_>>();
+    if let Some(path) = &args.input_list {
+        inputs.extend(input_list::read(path, args.start_field_all.unwrap_or(1))?);
+    }
+    let count = inputs.len();
+    let only = [
+        ("--luma-only", &args.luma_only, Planes::LumaOnly),
+        ("--chroma-only", &args.chroma_only, Planes::ChromaOnly),
+    ];
+    for (option, numbers, planes) in only {
+        for &n in numbers {
+            let input = n
+                .checked_sub(1)
+                .and_then(|i| inputs.get_mut(i))
+                .ok_or_else(|| {
+                    StackError::InvalidOption(format!(
+                        "{option} {n} isn't one of the {count} inputs"
+                    ))
+                })?;
+            if input.planes != Planes::Both && input.planes != planes {
+                return Err(StackError::InvalidOption(format!(
+                    "Input #{n} can't be both luma only and chroma only"
+                )));
             }
-            let sum = rmse_psnr.iter().sum::<f32>();
-            for (i, &v) in rmse_psnr.iter().enumerate() {
-                let avg_of_others = (sum - v) / ((inputs.len() - 1) as f32);
-                if v < 32. && v < avg_of_others - 5. {
-                    rmse_bad_in_a_row[i] += 1;
-                    if rmse_bad_in_a_row[i] % RMSE_WARN_THRESHOLD == 0 {
-                        warn!(
-                        "RMSE pSNR on input #{} has been very high for {} fields: {}. Bad source or desync?",
-                        i + 1,
-                            rmse_bad_in_a_row[i],
-                        v
-                    );
-                    }
-                } else {
-                    rmse_bad_in_a_row[i] = 0;
-                }
+            input.planes = planes;
+        }
+    }
+    for &n in &args.invert {
+        let input = n
+            .checked_sub(1)
+            .and_then(|i| inputs.get_mut(i))
+            .ok_or_else(|| {
+                StackError::InvalidOption(format!("--invert {n} isn't one of the {count} inputs"))
+            })?;
+        input.invert = true;
+    }
+    let config = StackConfig {
+        seq_no_start: args.seqno_start,
+        seq_no_end: args.seqno_end,
+        max_fields: args
+            .analyze
+            .or(args.max_frames.map(|frames| frames * 2))
+            .unwrap_or(args.max_fields),
+        max_duration: args.max_duration,
+        skip_output_fields: args.skip_output_fields,
+        decimate: args.decimate as usize,
+        dropout_threshold: args.dropout_threshold,
+        keep_reference_dropouts: args.keep_reference_dropouts,
+        dupes_to_drops: args.dupes_to_drops,
+        drop_on_max_rmse: args.drop_on_max_rmse,
+        preserve_seq_no: args.preserve_seqno,
+        mode: args.mode,
+        even_median: args.even_median,
+        median_space: args.median_space,
+        weighted: args.weighted,
+        edge_from_best: args.edge_from_best,
+        level_match: args.level_match,
+        base_input: args.base_input.unwrap_or(1) - 1,
+        reference_input: args.reference_input - 1,
+        halign_range: args.halign_range,
+        fix_field_order: args.fix_field_order,
+        align_by_seq_no: args.align_by_seq_no,
+        tail: args.tail,
+        size_mismatch: args.size_mismatch,
+        input_endian: args.input_endian,
+        reject_duplicate_inputs: args.no_duplicate_inputs,
+        bpsnr_window: args.bpsnr_window.as_deref().map(|w| (w[0], w[1])),
+        rmse_window: args.rmse_window.as_deref().map(|w| (w[0], w[1])),
+        rmse_edge_taper: args.rmse_edge_taper,
+        line_metrics_every: if args.line_metrics_csv.is_some() {
+            args.line_metrics_every as usize
+        } else {
+            0
+        },
+        rmse_warn: RmseWarn {
+            psnr: args.rmse_warn_psnr,
+            delta: args.rmse_warn_delta,
+            high_delta: args.rmse_warn_high_delta,
+            streak: args.rmse_warn_streak,
+        },
+        exclude_bad_inputs: args.exclude_bad_inputs,
+        temporal_prefilter: args.temporal_prefilter,
+        side_metadata: if args.side_metadata_vote {
+            SideMetadata::Vote
+        } else {
+            SideMetadata::Input(args.side_metadata_input.unwrap_or(args.reference_input) - 1)
+        },
+        vits_metrics: args.vits_metrics,
+        metadata_version: args.metadata_version,
+        detect_dropouts: args.detect_dropouts,
+        interpolate_common_dropouts: args.interpolate_common_dropouts,
+        confidence_output: args.confidence_output.clone(),
+        diff_output: args.diff_output.clone(),
+        compress_output: args.compress_output,
+        output_bits: args.output_bits,
+        efm_passthrough: args.efm_passthrough,
+        resume: args.resume,
+        overwrite: args.force,
+        analyze: args.analyze.is_some(),
+        threads: args.threads,
+        memory_budget: args.memory_budget.map(|mib| mib << 20),
+        mmap_inputs: args.max_memory_fail_safe,
+        ..StackConfig::new(inputs, args.output_basename.clone().unwrap_or_default())
+    };
+
+    let mut observer = CliObserver {
+        resume: args.resume,
+        force: args.force,
+        show_progress: !args.no_progress && std::io::stderr().is_terminal(),
+        progress: progress.clone(),
+        rmse_warn: config.rmse_warn,
+        inputs: 0,
+        fieldmap_csv: args.fieldmap_csv,
+        decisions_csv: args.decisions_csv,
+        events_csv: args.events_csv,
+        metrics_csv: args.metrics_csv,
+        metrics_json: args.metrics_json,
+        line_metrics_csv: args.line_metrics_csv,
+        out_metrics: None,
+        out_metrics_json: None,
+        out_fieldmap: None,
+        out_decisions: None,
+        out_line_metrics: None,
+        out_events: None,
+        interrupted: Arc::new(AtomicBool::new(false)),
+    };
+
+    // these are only written at the end, don't let an existing one fail a finished run
+    if !args.resume && !args.force {
+        let json = |basename: &String| PathBuf::from(format!("{basename}.tbc.json"));
+        let outputs = args
+            .output_basename
+            .iter()
+            .filter(|_| args.analyze.is_none())
+            .chain(&args.confidence_output)
+            .chain(&args.diff_output);
+        for path in outputs.map(json).chain(args.summary_json.clone()) {
+            if path.exists() {
+                return Err(StackError::OutputExists { path });
             }
         }
+    }
 
-        out_luma
-            .write_all(unsafe { to_bytes(&new_luma[0..field_size]) })
-            .unwrap();
-        if let Some(out_chroma) = out_chroma.as_mut() {
-            out_chroma
-                .write_all(unsafe { to_bytes(&new_chroma[0..field_size]) })
-                .unwrap();
+    let interrupted = observer.interrupted.clone();
+    ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
         }
-        out_fields.push(new_field.clone());
+        warn!("Interrupted, stopping after the field being written. Press Ctrl-C again to quit right away, leaving the output without metadata");
+    })
+    .map_err(io::Error::other)?;
+
+    let now = Instant::now();
+
+    // a run that stopped partway still gets the metadata of what it wrote, to keep it usable
+    let (report, failure) = match tbc_raw_stack::stack_with_observer(&config, &mut observer) {
+        Ok(report) => (report, None),
+        Err(StackError::Incomplete { report, source }) => (*report, Some(*source)),
+        Err(e) => return Err(e),
+    };
+    observer.finish()?;
+    match &failure {
+        _ if args.analyze.is_some() => {}
+        Some(StackError::Interrupted) => warn!(
+            "Stopped after {} output fields, which are complete and get their metadata written. Add --resume to carry on",
+            report.metadata.fields.len()
+        ),
+        // the error itself is printed last
+        Some(_) => error!(
+            "Stopped after {} output fields, which are complete and get their metadata written. Add --resume to carry on once the problem is fixed",
+            report.metadata.fields.len()
+        ),
+        None => {}
     }
 
-    let frames = out_fields.len() / 2;
+    let fields = report.metadata.fields.len();
+    let frames = fields / 2;
     let secs = now.elapsed().as_secs_f64();
     let fps = frames as f64 / secs;
     info!("Processed {frames} frames in {secs}s ({fps} FPS)");
+    let system = &report.metadata.video_parameters.system;
+    let count = |kind| report.fields.iter().filter(|f| f.kind == kind).count();
+    info!(
+        "The output is {} of {system} video, with {} dupes written and {} fields dropped",
+        format_duration(fields as f64 / (system.frame_rate() * 2.)),
+        count(FieldKind::Dupe),
+        count(FieldKind::Dropped)
+    );
+    log_summary(&report.inputs);
+    if args.analyze.is_some() {
+        log_analysis(&report.fields, &config.rmse_warn, report.inputs.len());
+    }
 
-    for (idx, field) in out_fields.iter_mut().enumerate() {
-        field.is_first_field = idx % 2 == 0;
+    if let Some(path) = &args.summary_json {
+        let mut file = BufWriter::new(create(path, args.resume || args.force)?);
+        serde_json::to_writer_pretty(&mut file, &report.inputs).map_err(io::Error::from)?;
+        file.flush()?;
     }
 
-    let mut out_meta = inputs[0].metadata.clone();
-    out_meta.video_parameters.number_of_sequential_fields = out_fields.len();
-    out_meta.fields = out_fields;
+    // the confidence map and difference get the same metadata, so they open like the output
+    for basename in args
+        .output_basename
+        .clone()
+        .filter(|_| args.analyze.is_none())
+        .into_iter()
+        .chain(args.confidence_output)
+        .chain(args.diff_output)
+    {
+        let meta_path = PathBuf::from(basename + ".tbc.json");
+        let meta_file = create(&meta_path, args.resume || args.force)?;
+        let mut meta_file = BufWriter::new(meta_file);
+        serde_json::to_writer(&mut meta_file, &report.metadata).map_err(io::Error::from)?;
+        meta_file.flush()?;
+    }
 
-    let meta_str = serde_json::to_string(&out_meta).unwrap();
-    let mut meta_file = File::create_new(args.output_basename.clone() + ".tbc.json")
-        .expect("Can't create metadata file");
-    meta_file
-        .write_all(meta_str.as_bytes())
-        .expect("Can't write to metadata file");
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    if let Some(basename) = args.output_basename.filter(|_| args.verify) {
+        verify(basename)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,176 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reads the `.tbc.json` of an input without keeping all of its fields in memory. On a long tape
+//! those add up to a lot, for every input. The header is read up front, and the fields are parsed
+//! again in the background as stacking reaches them.
+
+use crate::tbc_metadata::{Field, TbcMetadata, VideoParameters};
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/// How many fields the background parser may get ahead of stacking.
+const FIELDS_AHEAD: usize = 64;
+
+/// Reads the metadata in `reader` without its fields, returning it with the count of fields.
+/// Every field is parsed, so a bad one is reported right away rather than when stacking gets
+/// there.
+pub fn read_header(reader: impl Read) -> Result<(TbcMetadata, usize), serde_json::Error> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let (video_parameters, count, other) = deserializer.deserialize_map(MetadataVisitor {
+        fields: CountFields,
+    })?;
+    let metadata = TbcMetadata {
+        video_parameters,
+        fields: vec![],
+        other,
+    };
+    Ok((metadata, count))
+}
+
+/// The fields of a `.tbc.json`, parsed on a background thread ahead of being needed.
+pub struct FieldStream {
+    receiver: Receiver<Result<Field, serde_json::Error>>,
+}
+
+impl FieldStream {
+    /// Starts parsing the fields in `file`, from the one at index `start` on.
+    pub fn spawn(file: File, start: usize) -> Self {
+        let (sender, receiver) = sync_channel(FIELDS_AHEAD);
+        thread::spawn(move || {
+            let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(file));
+            let sent = deserializer.deserialize_map(MetadataVisitor {
+                fields: SendFields {
+                    skip: start,
+                    sender: &sender,
+                },
+            });
+            if let Err(e) = sent {
+                // fails if stacking is over already, which is what stopped us then
+                let _ = sender.send(Err(e));
+            }
+        });
+        FieldStream { receiver }
+    }
+
+    /// The next field, `None` after the last one.
+    pub fn next(&mut self) -> Result<Option<Field>, serde_json::Error> {
+        match self.receiver.recv() {
+            Ok(field) => field.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Visits the top level object of a `.tbc.json`, handing its `fields` array to `fields` and
+/// keeping everything else.
+struct MetadataVisitor<S> {
+    fields: S,
+}
+
+impl<'de, S: DeserializeSeed<'de>> Visitor<'de> for MetadataVisitor<S> {
+    type Value = (
+        VideoParameters,
+        S::Value,
+        HashMap<String, serde_json::Value>,
+    );
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TBC metadata")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut seed = Some(self.fields);
+        let mut video_parameters = None;
+        let mut fields = None;
+        let mut other = HashMap::new();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "videoParameters" => video_parameters = Some(map.next_value()?),
+                "fields" => {
+                    let seed = seed
+                        .take()
+                        .ok_or_else(|| de::Error::duplicate_field("fields"))?;
+                    fields = Some(map.next_value_seed(seed)?);
+                }
+                _ => {
+                    other.insert(key, map.next_value()?);
+                }
+            }
+        }
+        Ok((
+            video_parameters.ok_or_else(|| de::Error::missing_field("videoParameters"))?,
+            fields.ok_or_else(|| de::Error::missing_field("fields"))?,
+            other,
+        ))
+    }
+}
+
+/// Parses the `fields` array only to count its elements.
+struct CountFields;
+
+impl<'de> DeserializeSeed<'de> for CountFields {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for CountFields {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of fields")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<usize, A::Error> {
+        let mut count = 0;
+        while seq.next_element::<Field>()?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Sends the elements of the `fields` array from index `skip` on through `sender`.
+struct SendFields<'a> {
+    skip: usize,
+    sender: &'a SyncSender<Result<Field, serde_json::Error>>,
+}
+
+impl<'de> DeserializeSeed<'de> for SendFields<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SendFields<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of fields")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        for _ in 0..self.skip {
+            if seq.next_element::<IgnoredAny>()?.is_none() {
+                return Ok(());
+            }
+        }
+        while let Some(field) = seq.next_element::<Field>()? {
+            if self.sender.send(Ok(field)).is_err() {
+                return Err(de::Error::custom("no longer needed"));
+            }
+        }
+        Ok(())
+    }
+}
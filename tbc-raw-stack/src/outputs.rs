@@ -0,0 +1,261 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use serde::Serialize;
+use serde_derive::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use tbc_raw_stack::{FieldKind, FieldReport, RmseWarn, StackError};
+
+/// Writes a JSON document of the form `{"run": <header>, "<key>": [<items>...]}`, one item at a
+/// time, so that long runs don't have to keep every item in memory.
+pub struct JsonArrayWriter<W: Write> {
+    out: W,
+    first: bool,
+}
+
+impl<W: Write> JsonArrayWriter<W> {
+    pub fn new<H: Serialize>(mut out: W, header: &H, key: &str) -> io::Result<Self> {
+        out.write_all(b"{\"run\":")?;
+        serde_json::to_writer(&mut out, header)?;
+        write!(out, ",{}:[", serde_json::to_string(key)?)?;
+        Ok(JsonArrayWriter { out, first: true })
+    }
+
+    pub fn push<T: Serialize>(&mut self, item: &T) -> io::Result<()> {
+        if !self.first {
+            self.out.write_all(b",")?;
+        }
+        self.first = false;
+        self.out.write_all(b"\n")?;
+        serde_json::to_writer(&mut self.out, item)?;
+        Ok(())
+    }
+
+    /// Closes the document and flushes it.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(b"\n]}\n")?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldMetrics<'a> {
+    /// 1-based output field index
+    pub field: usize,
+    /// RMSE pSNR of each input against the stacked field
+    pub rmse_psnr: &'a [f32],
+    /// RMSE pSNR of each input's chroma, left out without chroma
+    #[serde(skip_serializing_if = "<[f32]>::is_empty")]
+    pub chroma_rmse_psnr: &'a [f32],
+    #[serde(rename = "bPSNR")]
+    pub bpsnr: Option<f64>,
+    /// bPSNR of each input's field, left out if unknown
+    #[serde(rename = "inputBPSNR", skip_serializing_if = "<[f32]>::is_empty")]
+    pub input_bpsnr: &'a [f32],
+    /// Whether this field is a dupe of the previous output field
+    pub dupe: bool,
+    /// 1-based indices of inputs that had a dupe skipped at this field
+    pub input_dupes: Vec<usize>,
+    /// Count of dropouts in the merged dropout list
+    pub dropouts: usize,
+}
+
+/// A row of the decisions CSV for `report`: the output field index, what was done with it
+/// (`stacked`, `passthrough`, `dupe` or `dropped`), a column for each input with 1 if it had a dupe
+/// skipped, then a column for each input with 1 if it counted as bad for the RMSE warning. The bad
+/// flags are left empty for fields that weren't stacked.
+pub fn decision_row(report: &FieldReport, rmse_warn: &RmseWarn, inputs: usize) -> String {
+    let status = match report.kind {
+        FieldKind::Stacked => "stacked",
+        FieldKind::Passthrough => "passthrough",
+        FieldKind::Dupe => "dupe",
+        FieldKind::Dropped => "dropped",
+        FieldKind::Resumed => "resumed",
+    };
+    let flag = |v: bool| if v { "1" } else { "0" };
+    let dupes = (0..inputs).map(|i| flag(report.input_dupes.contains(&i)));
+    let stacked = report.kind == FieldKind::Stacked && !report.rmse_psnr.is_empty();
+    let bad = (0..inputs).map(|i| {
+        if stacked {
+            flag(rmse_warn.is_bad(&report.rmse_psnr, i))
+        } else {
+            ""
+        }
+    });
+    let mut row = vec![report.field.to_string(), status.to_string()];
+    row.extend(dupes.chain(bad).map(str::to_string));
+    row.join(",") + "\n"
+}
+
+/// A row of the line metrics CSV: the 1-based output field and line index, then the RMSE pSNR of
+/// each input on that line.
+pub fn line_metrics_row(field: usize, line: usize, psnr: &[f32]) -> String {
+    let mut row = vec![field.to_string(), (line + 1).to_string()];
+    row.extend(psnr.iter().map(|v| v.to_string()));
+    row.join(",") + "\n"
+}
+
+/// Opens a CSV output keyed by 1-based output field index for appending, dropping the rows past
+/// the first `fields` fields and any partially written row.
+pub fn open_csv(path: &Path, fields: usize) -> Result<File, StackError> {
+    let old = std::fs::read(path).unwrap_or_default();
+    let kept = old
+        .split_inclusive(|&c| c == b'\n')
+        .filter(|line| {
+            let idx = std::str::from_utf8(line)
+                .ok()
+                .and_then(|l| l.split(',').next())
+                .and_then(|v| v.parse::<usize>().ok());
+            line.ends_with(b"\n") && idx.is_some_and(|idx| idx <= fields)
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    let mut file = File::create(path).map_err(|source| StackError::Create {
+        path: path.into(),
+        source,
+    })?;
+    file.write_all(&kept)?;
+    Ok(file)
+}
+
+/// How many output fields apart two events of the same kind in the same input can be to count as
+/// one.
+const EVENT_GAP: usize = 25;
+
+/// A run of output fields with the same problem, a row of the events CSV.
+struct Event {
+    kind: &'static str,
+    /// 0-based, `None` for the output's own events
+    input: Option<usize>,
+    start: usize,
+    end: usize,
+    /// Count of fields with the problem in the run, which may have gaps.
+    fields: usize,
+    /// The first and last field of the input in the run.
+    input_fields: Option<(usize, usize)>,
+    lowest_psnr: Option<f32>,
+}
+
+impl Event {
+    fn row(&self) -> String {
+        let mut detail = vec![match self.fields {
+            1 => "1 field".to_string(),
+            n => format!("{n} fields"),
+        }];
+        match self.input_fields {
+            Some((first, last)) if first == last => detail.push(format!("input field {first}")),
+            Some((first, last)) => detail.push(format!("input fields {first}-{last}")),
+            None => {}
+        }
+        if let Some(psnr) = self.lowest_psnr {
+            detail.push(format!("lowest RMSE pSNR {psnr:.2}"));
+        }
+        let input = self.input.map_or(String::new(), |i| (i + 1).to_string());
+        format!(
+            "{},{},{input},{},{}\n",
+            self.start,
+            self.end,
+            self.kind,
+            detail.join("; ")
+        )
+    }
+}
+
+/// Collects the problems of a run from its field reports into runs of fields, for the events
+/// CSV: the inputs counting as bad for the RMSE warning, their skipped dupes, the fields standing
+/// in for missing ones, and the dupes written and fields dropped in the output.
+#[derive(Default)]
+pub struct Events {
+    open: Vec<Event>,
+    done: Vec<Event>,
+    last_sources: Option<Vec<usize>>,
+}
+
+impl Events {
+    pub fn add(&mut self, report: &FieldReport, rmse_warn: &RmseWarn) {
+        let field = report.field;
+        let source = |i: usize| (report.sources.as_ref()).map(|s| s[i]).filter(|&f| f != 0);
+        match report.kind {
+            FieldKind::Dupe => self.note("output-dupe", None, field, None, None),
+            FieldKind::Dropped => self.note("output-drop", None, field, None, None),
+            _ => {}
+        }
+        for &i in &report.input_dupes {
+            self.note("dupe", Some(i), field, source(i), None);
+        }
+        if report.kind == FieldKind::Stacked {
+            for (i, &psnr) in report.rmse_psnr.iter().enumerate() {
+                if rmse_warn.is_bad(&report.rmse_psnr, i) {
+                    self.note("bad", Some(i), field, source(i), Some(psnr));
+                }
+            }
+        }
+        let Some(sources) = &report.sources else {
+            return;
+        };
+        if let Some(last) = &self.last_sources {
+            // the same field again stands in for missing ones
+            let held = (0..sources.len()).filter(|&i| sources[i] != 0 && sources[i] == last[i]);
+            for i in held.collect::<Vec<_>>() {
+                self.note("gap", Some(i), field, Some(sources[i]), None);
+            }
+        }
+        self.last_sources = Some(sources.clone());
+    }
+
+    fn note(
+        &mut self,
+        kind: &'static str,
+        input: Option<usize>,
+        field: usize,
+        input_field: Option<usize>,
+        psnr: Option<f32>,
+    ) {
+        let open = self
+            .open
+            .iter()
+            .position(|e| e.kind == kind && e.input == input);
+        if let Some(event) = open.map(|i| &mut self.open[i]) {
+            if field <= event.end + EVENT_GAP {
+                event.end = field;
+                event.fields += 1;
+                if let Some(f) = input_field {
+                    let (first, _) = event.input_fields.get_or_insert((f, f));
+                    event.input_fields = Some((*first, f));
+                }
+                if let Some(psnr) = psnr {
+                    event.lowest_psnr = Some(event.lowest_psnr.map_or(psnr, |p| p.min(psnr)));
+                }
+                return;
+            }
+        }
+        if let Some(i) = open {
+            self.done.push(self.open.swap_remove(i));
+        }
+        self.open.push(Event {
+            kind,
+            input,
+            start: field,
+            end: field,
+            fields: 1,
+            input_fields: input_field.map(|f| (f, f)),
+            lowest_psnr: psnr,
+        });
+    }
+
+    /// Writes out the events in the order they started.
+    pub fn write(mut self, out: &mut impl Write) -> io::Result<()> {
+        self.done.append(&mut self.open);
+        self.done
+            .sort_by_key(|e| (e.start, e.input.map_or(0, |i| i + 1), e.kind));
+        for event in &self.done {
+            out.write_all(event.row().as_bytes())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use std::io::{self, Write};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Log writer that hides the progress bar while a log line is printed, so the two don't garble
+/// each other on the terminal.
+#[derive(Clone)]
+pub struct ProgressWriter(pub ProgressBar);
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.suspend(|| io::stderr().write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.suspend(|| io::stderr().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for ProgressWriter {
+    type Writer = ProgressWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Progress bar style counting output fields, showing throughput in frames per second.
+pub fn style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{elapsed_precise} [{wide_bar}] {pos}/{len} fields, {fps} FPS, ETA {eta}",
+    )
+    .unwrap()
+    .with_key(
+        "fps",
+        |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+            write!(w, "{:.1}", state.per_sec() / 2.).unwrap()
+        },
+    )
+    .progress_chars("=> ")
+}
@@ -0,0 +1,238 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::worker::to_bytes_mut;
+use crate::Endian;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const FLAC_MAGIC: &[u8; 4] = b"fLaC";
+
+/// Reads the fields of an input `.tbc` file, either raw or compressed with FLAC like
+/// ld-compress does. The samples read are always native, whatever the byte order of the file.
+pub enum TbcReader {
+    Raw {
+        file: BufReader<File>,
+        /// Whether the samples of the file are of the other byte order.
+        swap: bool,
+    },
+    /// A raw file mapped into memory, read straight from the page cache without a buffer of its
+    /// own.
+    Mapped {
+        map: Mmap,
+        /// Offset of the next sample in bytes.
+        pos: usize,
+        swap: bool,
+    },
+    #[cfg(feature = "flac")]
+    Flac(Box<FlacTbc>),
+}
+
+impl TbcReader {
+    /// Opens `path`, detecting whether it is compressed. `buffer_size` is the read buffer size and
+    /// `endian` the byte order of a raw file.
+    pub fn open(path: &Path, buffer_size: usize, endian: Endian) -> io::Result<Self> {
+        Ok(match Self::open_raw(path)? {
+            Ok(file) => TbcReader::Raw {
+                file: BufReader::with_capacity(buffer_size, file),
+                swap: endian.swaps(),
+            },
+            Err(flac) => flac,
+        })
+    }
+
+    /// Opens `path` like [`open`](Self::open), but maps a raw file into memory instead of reading
+    /// it through a buffer. A compressed file is still decoded as it is read.
+    pub fn open_mapped(path: &Path, endian: Endian) -> io::Result<Self> {
+        Ok(match Self::open_raw(path)? {
+            Ok(file) => {
+                // SAFETY: the inputs aren't expected to change while they are being stacked, the
+                // same as with reading them. If one is truncated anyway, reading past its new end
+                // faults instead of failing with an error.
+                let map = unsafe { Mmap::map(&file)? };
+                #[cfg(unix)]
+                map.advise(memmap2::Advice::Sequential)?;
+                TbcReader::Mapped {
+                    map,
+                    pos: 0,
+                    swap: endian.swaps(),
+                }
+            }
+            Err(flac) => flac,
+        })
+    }
+
+    /// Opens `path`, returning the file if it is raw, or the reader decoding it if it is
+    /// compressed.
+    fn open_raw(path: &Path) -> io::Result<Result<File, Self>> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 4];
+        let is_flac = match file.read_exact(&mut magic) {
+            Ok(()) => &magic == FLAC_MAGIC,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(0))?;
+        if is_flac {
+            return Ok(Err(Self::open_flac(file)?));
+        }
+        Ok(Ok(file))
+    }
+
+    #[cfg(feature = "flac")]
+    fn open_flac(file: File) -> io::Result<Self> {
+        Ok(TbcReader::Flac(Box::new(FlacTbc::new(file)?)))
+    }
+
+    #[cfg(not(feature = "flac"))]
+    fn open_flac(_file: File) -> io::Result<Self> {
+        Err(io::Error::other(
+            "the file is FLAC compressed, which needs the `flac` feature enabled",
+        ))
+    }
+
+    /// Fills `samples` with the next samples of the file.
+    pub fn read(&mut self, samples: &mut [u16]) -> io::Result<()> {
+        match self {
+            TbcReader::Raw { file, swap } => {
+                file.read_exact(unsafe { to_bytes_mut(samples) })?;
+                if *swap {
+                    swap_bytes(samples);
+                }
+                Ok(())
+            }
+            TbcReader::Mapped { map, pos, swap } => {
+                let bytes = unsafe { to_bytes_mut(samples) };
+                let end = *pos + bytes.len();
+                let mapped = map.get(*pos..end).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+                })?;
+                bytes.copy_from_slice(mapped);
+                *pos = end;
+                if *swap {
+                    swap_bytes(samples);
+                }
+                Ok(())
+            }
+            #[cfg(feature = "flac")]
+            TbcReader::Flac(flac) => {
+                let count = samples.len();
+                flac.read(Some(samples), count)
+            }
+        }
+    }
+
+    /// Size of all the samples in the file in bytes, uncompressed. `None` if the file doesn't say,
+    /// FLAC streams may leave it out.
+    pub fn byte_len(&self) -> io::Result<Option<u64>> {
+        match self {
+            TbcReader::Raw { file, .. } => Ok(Some(file.get_ref().metadata()?.len())),
+            TbcReader::Mapped { map, .. } => Ok(Some(map.len() as u64)),
+            #[cfg(feature = "flac")]
+            TbcReader::Flac(flac) => Ok(flac.samples.map(|samples| samples * 2)),
+        }
+    }
+
+    /// Moves past the next `count` samples without reading them.
+    pub fn skip(&mut self, count: usize) -> io::Result<()> {
+        match self {
+            TbcReader::Raw { file, .. } => file.seek_relative((count * 2) as i64),
+            TbcReader::Mapped { pos, .. } => {
+                *pos += count * 2;
+                Ok(())
+            }
+            #[cfg(feature = "flac")]
+            TbcReader::Flac(flac) => flac.read(None, count),
+        }
+    }
+}
+
+/// Reads `samples` from `file`, a stacker output, which is always little-endian.
+pub fn read_output(file: &mut impl Read, samples: &mut [u16]) -> io::Result<()> {
+    file.read_exact(unsafe { to_bytes_mut(samples) })?;
+    if Endian::Le.swaps() {
+        swap_bytes(samples);
+    }
+    Ok(())
+}
+
+fn swap_bytes(samples: &mut [u16]) {
+    for sample in samples {
+        *sample = sample.swap_bytes();
+    }
+}
+
+/// A FLAC compressed `.tbc`: a single channel of 16-bit samples, stored signed as FLAC requires.
+/// FLAC frames can only be found by decoding the ones before, so seeking means decoding and
+/// throwing the samples away.
+#[cfg(feature = "flac")]
+pub struct FlacTbc {
+    reader: claxon::FlacReader<File>,
+    block: claxon::Block,
+    /// Samples of `block` already consumed.
+    pos: usize,
+    /// Count of samples in the stream, if known.
+    samples: Option<u64>,
+}
+
+#[cfg(feature = "flac")]
+impl FlacTbc {
+    fn new(file: File) -> io::Result<Self> {
+        let reader = claxon::FlacReader::new(file).map_err(flac_error)?;
+        let info = reader.streaminfo();
+        if info.channels != 1 || info.bits_per_sample != 16 {
+            return Err(io::Error::other(format!(
+                "expected 1 channel of 16-bit samples in the FLAC stream, found {} of {}-bit",
+                info.channels, info.bits_per_sample
+            )));
+        }
+        let samples = info.samples;
+        Ok(FlacTbc {
+            reader,
+            block: claxon::Block::empty(),
+            pos: 0,
+            samples,
+        })
+    }
+
+    /// Consumes the next `count` samples, storing them in `out` if given.
+    fn read(&mut self, mut out: Option<&mut [u16]>, count: usize) -> io::Result<()> {
+        let mut done = 0;
+        while done < count {
+            if self.pos == self.block.duration() as usize {
+                let buffer = std::mem::replace(&mut self.block, claxon::Block::empty());
+                self.block = self
+                    .reader
+                    .blocks()
+                    .read_next_or_eof(buffer.into_buffer())
+                    .map_err(flac_error)?
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+                    })?;
+                self.pos = 0;
+            }
+            let available = &self.block.channel(0)[self.pos..];
+            let n = available.len().min(count - done);
+            if let Some(out) = out.as_mut() {
+                for (o, &s) in out[done..done + n].iter_mut().zip(available) {
+                    // undo the signed conversion, the same as `flac --sign=unsigned` does
+                    *o = (s + 0x8000) as u16;
+                }
+            }
+            self.pos += n;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "flac")]
+fn flac_error(e: claxon::Error) -> io::Error {
+    match e {
+        claxon::Error::IoError(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
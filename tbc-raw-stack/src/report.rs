@@ -0,0 +1,230 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::tbc_metadata::{System, TbcMetadata};
+use crate::{StackConfig, StackError};
+use serde_derive::Serialize;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RunInput {
+    pub basename: String,
+    /// 1-based, as given on the command line
+    pub start_field: usize,
+    pub sample_offset: isize,
+    pub vshift: isize,
+    pub field_count: usize,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RunInfo {
+    pub system: System,
+    pub field_width: usize,
+    pub field_height: usize,
+    pub inputs: Vec<RunInput>,
+}
+
+/// What happened to an output field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldKind {
+    /// Stacked from the inputs
+    Stacked,
+    /// Copied from the only input left, once the others ended
+    Passthrough,
+    /// The previous output field written again
+    Dupe,
+    /// The inputs were consumed, but nothing was written
+    Dropped,
+    /// Already written by the run being resumed
+    Resumed,
+}
+
+#[derive(Clone, Debug)]
+pub struct FieldReport {
+    /// 1-based output field index. Dropped fields share it with the next written field.
+    pub field: usize,
+    pub kind: FieldKind,
+    /// 1-based field index taken from each input, 0 for the inputs not taking part, `None` for
+    /// dupes
+    pub sources: Option<Vec<usize>>,
+    /// Indices of the inputs that had a dupe skipped at this field, or for a dropped field, also
+    /// the dupe that led to dropping it
+    pub input_dupes: Vec<usize>,
+    /// RMSE pSNR of each input against the stacked field, empty if unknown
+    pub rmse_psnr: Vec<f32>,
+    /// RMSE pSNR of each input's chroma against the stacked chroma, on the same scale as the luma,
+    /// empty if unknown or there is no chroma
+    pub chroma_rmse_psnr: Vec<f32>,
+    /// RMSE pSNR of each input on each line of the field, lines first, for the stacked fields
+    /// measured by line, empty for the others
+    pub line_rmse_psnr: Vec<Vec<f32>>,
+    /// Black pSNR of the written field
+    pub bpsnr: Option<f64>,
+    /// Black pSNR of each input's field as it went into the stack, after alignment, empty if
+    /// unknown
+    pub input_bpsnr: Vec<f32>,
+    /// Count of dropouts in the merged dropout list
+    pub dropouts: usize,
+}
+
+/// How well an input did over the whole run, to help decide which captures are worth keeping.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InputSummary {
+    pub basename: String,
+    /// Mean RMSE pSNR against the stacked fields, `None` if nothing was stacked
+    pub mean_rmse_psnr: Option<f32>,
+    /// Median RMSE pSNR against the stacked fields, `None` if nothing was stacked
+    pub median_rmse_psnr: Option<f32>,
+    /// Count of fields where the input counted as bad for the RMSE warning
+    pub bad_fields: usize,
+    /// Count of dupes skipped in the input
+    pub dupes: usize,
+    /// Count of gaps in the input's sequence numbers, each standing in for one or more missing
+    /// fields
+    pub gaps: usize,
+    /// Count of fields where the input matched the stacked field worst of all inputs
+    pub outlier_fields: usize,
+}
+
+impl InputSummary {
+    /// Summarizes each input from the reports of the fields stacked in this run.
+    pub(crate) fn collect(
+        config: &StackConfig,
+        reports: &[FieldReport],
+        dupes: &[usize],
+        gaps: &[usize],
+    ) -> Vec<InputSummary> {
+        // dupes repeat the metrics of the previous field, don't count those twice
+        let stacked = reports
+            .iter()
+            .filter(|r| r.kind == FieldKind::Stacked && !r.rmse_psnr.is_empty())
+            .collect::<Vec<_>>();
+        config
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                let mut values = stacked.iter().map(|r| r.rmse_psnr[i]).collect::<Vec<_>>();
+                values.sort_unstable_by(f32::total_cmp);
+                let mean_rmse_psnr =
+                    (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32);
+                let median_rmse_psnr = (!values.is_empty()).then(|| {
+                    let mid = values.len() / 2;
+                    if values.len() % 2 == 0 {
+                        (values[mid - 1] + values[mid]) / 2.
+                    } else {
+                        values[mid]
+                    }
+                });
+                let outlier_fields = stacked
+                    .iter()
+                    .filter(|r| {
+                        let v = r.rmse_psnr[i];
+                        r.rmse_psnr
+                            .iter()
+                            .enumerate()
+                            .all(|(j, &other)| j == i || v < other)
+                    })
+                    .count();
+                InputSummary {
+                    basename: input.basename.clone(),
+                    mean_rmse_psnr,
+                    median_rmse_psnr,
+                    bad_fields: stacked
+                        .iter()
+                        .filter(|r| config.rmse_warn.is_bad(&r.rmse_psnr, i))
+                        .count(),
+                    dupes: dupes[i],
+                    gaps: gaps[i],
+                    outlier_fields,
+                }
+            })
+            .collect()
+    }
+}
+
+/// How the output was stacked, kept as `stackInfo` in its `.tbc.json` so it documents itself.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StackInfo<'a> {
+    /// The version of tbc-raw-stack that wrote it
+    version: &'static str,
+    /// Count of output fields that repeat the one before them
+    dupes_written: usize,
+    inputs: Vec<StackInfoInput<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StackInfoInput<'a> {
+    basename: &'a str,
+    /// 1-based
+    start_field: usize,
+    dupes: usize,
+    gaps: usize,
+}
+
+/// The `stackInfo` of an output stacked from `config`, given the reports of its fields and how
+/// each input did.
+pub(crate) fn stack_info(
+    config: &StackConfig,
+    reports: &[FieldReport],
+    inputs: &[InputSummary],
+) -> serde_json::Value {
+    let info = StackInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        dupes_written: reports.iter().filter(|r| r.kind == FieldKind::Dupe).count(),
+        inputs: config
+            .inputs
+            .iter()
+            .zip(inputs)
+            .map(|(input, summary)| StackInfoInput {
+                basename: &input.basename,
+                start_field: input.start_field,
+                dupes: summary.dupes,
+                gaps: summary.gaps,
+            })
+            .collect(),
+    };
+    serde_json::to_value(info).expect("the stack info serializes to JSON")
+}
+
+/// The outcome of a successful [`stack`](crate::stack).
+#[derive(Clone, Debug)]
+pub struct StackReport {
+    /// Metadata of the written output, ready to be saved as its `.tbc.json`
+    pub metadata: TbcMetadata,
+    /// Every output field in order, including dropped ones
+    pub fields: Vec<FieldReport>,
+    /// How each input did, in the order of the config's inputs
+    pub inputs: Vec<InputSummary>,
+}
+
+/// Hooks for following a [`stack_with_observer`](crate::stack_with_observer) run while it
+/// progresses, e.g. to stream metrics to disk or to drive a progress bar.
+pub trait StackObserver: Send {
+    /// Called once the inputs are opened, before anything is written. `fields` is how many output
+    /// fields to expect at most, the first `resumed_fields` of which were written by the run being
+    /// resumed.
+    ///
+    /// Returning an error stops the run with that error, this applies to [`field`](Self::field)
+    /// as well, which stops it as [`StackError::Incomplete`].
+    fn start(
+        &mut self,
+        _run: &RunInfo,
+        _fields: usize,
+        _resumed_fields: usize,
+    ) -> Result<(), StackError> {
+        Ok(())
+    }
+
+    /// Called for every output field, in order.
+    fn field(&mut self, _report: &FieldReport) -> Result<(), StackError> {
+        Ok(())
+    }
+}
+
+impl StackObserver for () {}
@@ -0,0 +1,111 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::StackError;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+
+/// The arguments that must match between a run and its resumption, saved next to the output.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeInfo {
+    pub input_basename: Vec<String>,
+    pub start_field: Vec<usize>,
+    pub sample_offset: Vec<isize>,
+    pub vshift: Vec<isize>,
+    pub input_weight: Vec<usize>,
+    pub planes: Vec<String>,
+    pub invert: Vec<bool>,
+    pub mode: String,
+    pub even_median: String,
+    pub median_space: String,
+    pub input_endian: String,
+    pub weighted: bool,
+    pub edge_from_best: bool,
+    pub level_match: bool,
+    pub base_input: usize,
+    pub reference_input: usize,
+    pub dupes_to_drops: bool,
+    pub drop_on_max_rmse: Option<f32>,
+    pub dropout_threshold: usize,
+    pub keep_reference_dropouts: bool,
+    pub halign_range: usize,
+    pub fix_field_order: bool,
+    pub align_by_seq_no: bool,
+    pub tail: String,
+    pub skip_output_fields: usize,
+    pub decimate: usize,
+    pub output_bits: u32,
+    pub rmse_window: Option<(usize, usize)>,
+    pub exclude_bad_inputs: bool,
+    pub temporal_prefilter: usize,
+    pub interpolate_common_dropouts: bool,
+    pub confidence_output: Option<String>,
+    pub diff_output: Option<String>,
+}
+
+impl ResumeInfo {
+    pub fn path(output_basename: &str) -> String {
+        output_basename.to_string() + ".resume.json"
+    }
+
+    pub fn save(&self, output_basename: &str) -> Result<(), StackError> {
+        let path = Self::path(output_basename);
+        let file = File::create(&path).map_err(|source| StackError::Create {
+            path: path.into(),
+            source,
+        })?;
+        serde_json::to_writer(file, self).map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    /// Fails if the saved arguments don't match `self`.
+    pub fn check(&self, output_basename: &str) -> Result<(), StackError> {
+        let path = Self::path(output_basename);
+        // a missing file most likely means the output wasn't created by a previous run
+        let file = File::open(&path).map_err(|source| StackError::Open {
+            path: path.clone().into(),
+            source,
+        })?;
+        let saved: ResumeInfo =
+            serde_json::from_reader(file).map_err(|source| StackError::BadMetadata {
+                path: path.into(),
+                source,
+            })?;
+        if saved != *self {
+            return Err(StackError::ResumeMismatch {
+                previous: format!("{:?}", saved),
+                current: format!("{:?}", self),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Opens an existing output for appending after its first `fields` complete fields, dropping any
+/// partial trailing field.
+pub fn open_output(path: &str, field_bytes: usize, fields: usize) -> Result<File, StackError> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|source| StackError::Open {
+            path: path.into(),
+            source,
+        })?;
+    file.set_len((field_bytes * fields) as u64)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+/// Count of complete fields in an existing output file.
+pub fn complete_fields(path: &str, field_bytes: usize) -> Result<usize, StackError> {
+    let len = std::fs::metadata(path)
+        .map_err(|source| StackError::Open {
+            path: path.into(),
+            source,
+        })?
+        .len();
+    Ok(len as usize / field_bytes)
+}
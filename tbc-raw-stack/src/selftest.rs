@@ -0,0 +1,120 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Checks the median kernels against a plain implementation on synthetic fields, and measures
+//! their speed, without needing any captures.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use tbc_raw_stack::{StackError, MAX_INPUT_STREAMS, MIN_INPUT_STREAMS};
+use tracing::{error, info};
+
+/// An NTSC field, the smaller of the two, rounded up to whole blocks of the kernels.
+const FIELD_SIZE: usize = (910 * 263usize).next_multiple_of(32);
+
+/// Tiny deterministic xorshift64 PRNG, so a failure can be reproduced.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u16 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        (x >> 48) as u16
+    }
+
+    /// Samples around mid-grey with a little noise, and now and then a dropout at either end
+    /// of the range, so the median has outliers to reject and ties to break.
+    fn fill(&mut self, field: &mut [u16]) {
+        for sample in field {
+            let r = self.next();
+            *sample = match r % 64 {
+                0 => 0,
+                1 => u16::MAX,
+                _ => 30000 + r % 256,
+            };
+        }
+    }
+}
+
+/// The median of every column of `inputs` and each input's squared error against it, the
+/// straightforward way.
+fn reference(inputs: &[Vec<u16>]) -> (Vec<u16>, Vec<u64>) {
+    let n = inputs.len();
+    let mut sse = vec![0u64; n];
+    let mut column = vec![0u16; n];
+    let out = (0..FIELD_SIZE)
+        .map(|i| {
+            for (c, input) in column.iter_mut().zip(inputs) {
+                *c = input[i];
+            }
+            column.sort_unstable();
+            let median = if n % 2 == 1 {
+                column[n / 2]
+            } else {
+                (column[n / 2 - 1] as u32 + column[n / 2] as u32).div_ceil(2) as u16
+            };
+            for (sse, input) in sse.iter_mut().zip(inputs) {
+                *sse += (input[i].abs_diff(median) as u64).pow(2);
+            }
+            median
+        })
+        .collect();
+    (out, sse)
+}
+
+/// Medians `fields` synthetic fields of every count of inputs, checking the first of each
+/// against [`reference`], and logs the speed for each count. Fails if any disagreed.
+pub fn run(fields: usize) -> Result<(), StackError> {
+    info!(
+        "Testing the median kernels compiled for {} instructions, the CPU supports {}",
+        median::INSTRUCTION_SET,
+        median::supported_instruction_set()
+    );
+    let fields = fields.max(1);
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut failures = 0;
+    for n in MIN_INPUT_STREAMS..=MAX_INPUT_STREAMS {
+        let mut inputs = vec![vec![0u16; FIELD_SIZE]; n];
+        let mut out = vec![0u16; FIELD_SIZE];
+        let mut sse = vec![0u64; n];
+        let mut elapsed = Duration::ZERO;
+        for field in 0..fields {
+            for input in &mut inputs {
+                rng.fill(input);
+            }
+            let slices = inputs.iter().map(Vec::as_slice).collect::<Vec<_>>();
+            sse.fill(0);
+            let start = Instant::now();
+            median::batch_n_even(
+                black_box(&mut out),
+                black_box(&slices),
+                &mut sse,
+                median::EvenMedian::Average,
+            );
+            elapsed += start.elapsed();
+            if field != 0 {
+                continue;
+            }
+            let (want_out, want_sse) = reference(&inputs);
+            if out != want_out || sse != want_sse {
+                error!("The median of {n} inputs doesn't match the plain implementation");
+                failures += 1;
+            }
+        }
+        let bytes = (fields * n * FIELD_SIZE * 2) as f64;
+        info!(
+            "{n:>2} inputs: {:>8.1} MB/s of input, {:>7.1} fields/s",
+            bytes / elapsed.as_secs_f64() / 1e6,
+            fields as f64 / elapsed.as_secs_f64()
+        );
+    }
+    if failures != 0 {
+        return Err(StackError::SelfTest { failures });
+    }
+    info!("The median kernels match the plain implementation");
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::tbc_metadata::{self, Field};
+use crate::{SideMetadata, VitsMetricsMode};
+use std::collections::HashMap;
+
+/// Per-field keys describing what was decoded from the picture rather than the picture itself:
+/// VBI data (frame numbers, timecodes), NTSC-specific data (closed captions, FM code, white flag)
+/// and the field phase.
+pub const SIDE_METADATA_KEYS: [&str; 3] = ["vbi", "ntsc", "fieldPhaseID"];
+
+/// Replaces the side metadata of `field` with the one selected from the input fields.
+pub fn select(field: &mut Field, fields: &[Field], mode: SideMetadata) {
+    for key in SIDE_METADATA_KEYS {
+        let value = match mode {
+            SideMetadata::Input(i) => fields[i].other.get(key),
+            SideMetadata::Vote => vote(fields.iter().filter_map(|f| f.other.get(key))),
+        };
+        match value {
+            Some(value) => field.other.insert(key.to_string(), value.clone()),
+            None => field.other.remove(key),
+        };
+    }
+}
+
+/// The chroma subcarrier phase of `field` (`fieldPhaseID`), if the decoder recorded it.
+pub fn field_phase(field: &Field) -> Option<i64> {
+    field.other.get("fieldPhaseID")?.as_i64()
+}
+
+/// The VITS metrics of an output field whose black pSNR measures `bpsnr`, with the others made
+/// of those of the input `fields` as `mode` says, `reference` being the reference input's index.
+pub fn vits_metrics(
+    bpsnr: f64,
+    fields: &[Field],
+    reference: usize,
+    mode: VitsMetricsMode,
+) -> tbc_metadata::VitsMetrics {
+    let mut other = match mode {
+        VitsMetricsMode::Recompute => HashMap::new(),
+        VitsMetricsMode::Reference | VitsMetricsMode::Average => fields[reference]
+            .vits_metrics
+            .as_ref()
+            .map_or_else(HashMap::new, |m| m.other.clone()),
+    };
+    if mode == VitsMetricsMode::Average {
+        for (key, value) in other.iter_mut().filter(|(_, value)| value.is_number()) {
+            let values = fields
+                .iter()
+                .filter_map(|f| f.vits_metrics.as_ref()?.other.get(key)?.as_f64())
+                .collect::<Vec<_>>();
+            *value = (values.iter().sum::<f64>() / values.len() as f64).into();
+        }
+    }
+    tbc_metadata::VitsMetrics { bpsnr, other }
+}
+
+/// The most common of `values`, ties going to the one seen first.
+fn vote<'a, T: PartialEq>(values: impl Iterator<Item = &'a T>) -> Option<&'a T> {
+    let mut counts: Vec<(&T, usize)> = vec![];
+    for value in values {
+        match counts.iter_mut().find(|(v, _)| *v == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    // max_by_key returns the last maximum, so look for the first one explicitly
+    let max = counts.iter().map(|(_, count)| *count).max()?;
+    counts
+        .into_iter()
+        .find(|(_, count)| *count == max)
+        .map(|(v, _)| v)
+}
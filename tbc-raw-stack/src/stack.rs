@@ -0,0 +1,2099 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::compress::{FlacEncoder, TbcWriter};
+use crate::efm::copy_efm;
+use crate::input_files::InputFiles;
+use crate::levels::LevelMatch;
+use crate::metadata_reader::{self, FieldStream};
+use crate::reader::{read_output, TbcReader};
+use crate::report::{
+    stack_info, FieldKind, InputSummary, RunInfo, RunInput, StackObserver, StackReport,
+};
+use crate::resume::{self, ResumeInfo};
+use crate::system::{SystemConstants, KERNEL_LANES};
+use crate::tbc_metadata::{self, System, TbcMetadata, REQUIRED_VIDEO_PARAMETERS};
+use crate::worker::{
+    stack_worker, FieldBuffer, FieldBuffers, Job, JobResult, PastField, StackParams, Timing, Work,
+};
+use crate::writer::{Exclusion, Stalls, Writer, STALL_FACTOR};
+use crate::{
+    Endian, EvenMedian, InputConfig, MedianSpace, MetadataVersion, Planes, SideMetadata,
+    SizeMismatch, StackConfig, StackError, StackMode, TailMode, MAX_INPUT_STREAMS,
+    MIN_INPUT_STREAMS,
+};
+use serde::de;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, span, warn, Level};
+
+// 355 255 PAL samples * 512 * 2 channels = ~347 MB per input
+// 347 MB * (15 input + 1 output) = 5.552 GB total memory usage
+// since 512 is also the default sector size, it may help with storage stuff too...
+// a memory budget makes the buffers smaller, but never larger than this
+const IO_BUFFER_MULTIPLIER: usize = 512;
+
+/// How many fields later the writer's decision to leave an input out of the stack, or take it
+/// back, applies. Fixed rather than following the thread count so the output doesn't depend on
+/// it, and well above the fields in flight so the dispatcher rarely has to wait for the writer.
+const EXCLUSION_LAG: usize = 32;
+
+struct InputTbc {
+    index: usize,
+    /// The metadata without its fields, which are read as they are reached.
+    metadata: TbcMetadata,
+    json_path: PathBuf,
+    field_count: usize,
+    /// Count of fields in the metadata, fewer than `field_count` if the files hold more with
+    /// [`SizeMismatch::Extend`].
+    listed_fields: usize,
+    fields: FieldStream,
+    /// The metadata of the field at `field_index`, `None` once past the last one.
+    field: Option<tbc_metadata::Field>,
+    /// The `.tbc` and `_chroma.tbc` files.
+    files: InputFiles,
+    field_index: usize,
+    dupe_count: usize,
+    /// 1 if the input starts on a second field, which counts towards `dupe_count` as if a dupe
+    /// came before it.
+    start_parity: usize,
+    /// Count of gaps in the sequence numbers of the fields used so far.
+    gap_count: usize,
+    last_seq_no: usize,
+    /// Whether the field order matched the reference input's at the last stacked field.
+    field_order_ok: bool,
+    /// The luma and chroma of the current field, if it was already read from the files. Only
+    /// used when the field stands in for missing ones.
+    held: Option<(Box<FieldBuffer>, Option<Box<FieldBuffer>>)>,
+    /// How many earlier fields of the same parity the temporal prefilter takes, 0 for none.
+    temporal_prefilter: usize,
+    /// The fields last read from the files, the latest first, for the temporal prefilter.
+    past: VecDeque<Arc<PastField>>,
+}
+
+impl InputTbc {
+    fn open(
+        index: usize,
+        config: &InputConfig,
+        size_mismatch: SizeMismatch,
+        endian: Endian,
+        io_buffer: Option<usize>,
+        mmap: bool,
+        temporal_prefilter: usize,
+    ) -> Result<Self, StackError> {
+        let p = &config.basename;
+        let json = p.clone() + ".tbc.json";
+        let tbc = p.clone() + ".tbc";
+        let chroma = p.clone() + "_chroma.tbc";
+
+        let open_json = || {
+            File::open(&json).map_err(|source| StackError::Open {
+                path: json.clone().into(),
+                source,
+            })
+        };
+        let bad_metadata = |source| StackError::BadMetadata {
+            path: json.clone().into(),
+            source,
+        };
+        let (metadata, fields) =
+            metadata_reader::read_header(BufReader::new(open_json()?)).map_err(bad_metadata)?;
+
+        if fields == 0 {
+            return Err(StackError::EmptyInput {
+                input: index,
+                start_field: config.start_field,
+                fields,
+            });
+        }
+        if !(1..=fields).contains(&config.start_field) {
+            return Err(StackError::StartField {
+                input: index,
+                start_field: config.start_field,
+                fields,
+            });
+        }
+        let start_field = config.start_field - 1;
+
+        let field_size =
+            metadata.video_parameters.field_height * metadata.video_parameters.field_width;
+        // how many fields the shortest of the files holds, if known
+        let mut file_fields: Option<usize> = None;
+        let mut open = |path: String| -> Result<TbcReader, StackError> {
+            let file = if mmap {
+                TbcReader::open_mapped(path.as_ref(), endian)
+            } else {
+                TbcReader::open(path.as_ref(), io_buffer_size(io_buffer, field_size), endian)
+            };
+            let file = file.map_err(|source| StackError::Open {
+                path: path.clone().into(),
+                source,
+            })?;
+            // a truncated file would only fail once stacking gets to its end
+            let expected = (field_size * 2 * fields) as u64;
+            let found = file.byte_len().map_err(|source| StackError::Open {
+                path: path.clone().into(),
+                source,
+            })?;
+            if let Some(found) = found {
+                let found_fields = (found / (field_size as u64 * 2)) as usize;
+                file_fields = Some(file_fields.map_or(found_fields, |f| f.min(found_fields)));
+            }
+            if let Some(found) = found.filter(|&found| found != expected) {
+                let e = StackError::InputSize {
+                    input: index,
+                    path: path.into(),
+                    fields,
+                    expected,
+                    found,
+                };
+                match size_mismatch {
+                    SizeMismatch::Error => return Err(e),
+                    SizeMismatch::Warn | SizeMismatch::Extend => warn!("{e}"),
+                }
+            }
+            Ok(file)
+        };
+        let mut tbc_file = open(tbc)?;
+        let mut chroma_file = if std::fs::exists(&chroma).unwrap_or(true) {
+            Some(open(chroma)?)
+        } else {
+            None
+        };
+        let field_count = match (size_mismatch, file_fields) {
+            (SizeMismatch::Extend, Some(found)) => found,
+            (_, found) => found.map_or(fields, |found| found.min(fields)),
+        };
+        if start_field >= field_count {
+            // the files end before it, the metadata alone would make it look fine
+            return Err(StackError::EmptyInput {
+                input: index,
+                start_field: config.start_field,
+                fields: field_count,
+            });
+        }
+        if field_count > fields {
+            warn!(
+                "Input #{} has {} fields past the end of its metadata, making up their metadata",
+                index + 1,
+                field_count - fields
+            );
+        }
+        for file in std::iter::once(&mut tbc_file).chain(chroma_file.as_mut()) {
+            file.skip(field_size * start_field)
+                .map_err(|source| StackError::Read {
+                    input: index,
+                    source,
+                })?;
+        }
+        let mut field_stream = FieldStream::spawn(open_json()?, start_field);
+        let field = field_stream.next().map_err(bad_metadata)?;
+        // dupes before the start field make its position disagree with its field order
+        let positional = start_field % 2;
+        let start_parity = field
+            .as_ref()
+            .map_or(positional, |f| usize::from(!f.is_first_field));
+        if start_parity != positional {
+            let kind = |parity| if parity == 0 { "first" } else { "second" };
+            warn!(
+                "Input #{} starts on a {} field, though field {} would be a {} field going by its position. The fields before it have dupes or field order breaks, going by its metadata",
+                index + 1,
+                kind(start_parity),
+                config.start_field,
+                kind(positional)
+            );
+        }
+        Ok(InputTbc {
+            index,
+            metadata,
+            json_path: json.into(),
+            field_count,
+            listed_fields: fields,
+            fields: field_stream,
+            field,
+            files: InputFiles::new(tbc_file, chroma_file, config.invert),
+            field_index: start_field,
+            dupe_count: start_parity,
+            start_parity,
+            gap_count: 0,
+            last_seq_no: 0,
+            field_order_ok: true,
+            held: None,
+            temporal_prefilter,
+            past: VecDeque::new(),
+        })
+    }
+
+    /// The metadata of the current field.
+    fn field(&self) -> &tbc_metadata::Field {
+        self.field.as_ref().expect("input already ended")
+    }
+
+    /// Whether the input is past its last field.
+    fn ended(&self) -> bool {
+        self.field_index >= self.field_count
+    }
+
+    /// Moves on to the next field.
+    fn next_field(&mut self) -> Result<(), StackError> {
+        self.field_index += 1;
+        self.field = if self.ended() {
+            None
+        } else if self.field_index >= self.listed_fields {
+            // past the end of the metadata, carry on from the last field
+            let last = self.field();
+            Some(tbc_metadata::Field {
+                is_first_field: !last.is_first_field,
+                seq_no: last.seq_no + 1,
+                vits_metrics: None,
+                drop_outs: None,
+                other: Default::default(),
+            })
+        } else {
+            let field = self.fields.next().and_then(|field| {
+                field.ok_or_else(|| de::Error::custom("the file changed while stacking"))
+            });
+            Some(field.map_err(|source| StackError::BadMetadata {
+                path: self.json_path.clone(),
+                source,
+            })?)
+        };
+        Ok(())
+    }
+
+    /// Count of fields missing before the current one, going by their sequence numbers.
+    fn missing_fields(&self) -> usize {
+        if self.last_seq_no == 0 {
+            // nothing to compare the first field to
+            return 0;
+        }
+        let seq_no = self.field().seq_no;
+        seq_no.saturating_sub(self.last_seq_no + 1)
+    }
+
+    /// Moves on after the current field was used for an output field. If fields are missing
+    /// before it, it stays, and gets used again in place of the next missing one.
+    fn advance(&mut self) -> Result<(), StackError> {
+        if self.missing_fields() != 0 {
+            self.last_seq_no += 1;
+        } else {
+            self.last_seq_no = self.field().seq_no;
+            self.next_field()?;
+        }
+        Ok(())
+    }
+
+    /// Leaves out the current field, a dupe or one out of order, and moves on to the next one.
+    /// Returns whether the input has fields left.
+    fn skip_field(&mut self, field_size: usize) -> Result<bool, StackError> {
+        self.skip(field_size)?;
+        self.next_field()?;
+        Ok(!self.ended())
+    }
+
+    /// Moves past the current field without reading it.
+    fn skip(&mut self, field_size: usize) -> Result<(), StackError> {
+        if self.missing_fields() != 0 {
+            // it is needed again, keep it around instead
+            if self.held.is_none() {
+                let mut luma = Box::<FieldBuffer>::default();
+                let mut chroma = self.files.has_chroma().then(Box::<FieldBuffer>::default);
+                self.read_into(&mut luma, chroma.as_mut(), field_size)?;
+                self.held = Some((luma, chroma));
+            }
+            return Ok(());
+        }
+        if self.held.take().is_some() {
+            // the files are past it already
+            return Ok(());
+        }
+        if self.temporal_prefilter != 0 {
+            // the fields to come are filtered with it
+            let mut past = self.recycle_past();
+            self.read_files(&mut past.luma, past.chroma.as_mut(), field_size)?;
+            self.past.push_front(Arc::new(past));
+            return Ok(());
+        }
+        self.files
+            .skip(field_size)
+            .map_err(|source| StackError::Read {
+                input: self.index,
+                source,
+            })
+    }
+
+    /// Starts reading the current field on the input's reader thread, if it comes from the
+    /// files, to be taken with [`read`](Self::read).
+    fn request_read(&mut self, field_size: usize) -> Result<(), StackError> {
+        if self.held.is_some() {
+            return Ok(());
+        }
+        self.files
+            .request(field_size)
+            .map_err(|source| StackError::Read {
+                input: self.index,
+                source,
+            })
+    }
+
+    /// Reads the current field into `luma` and `chroma`, the input's buffers. Returns how long
+    /// reading the files took.
+    fn read(
+        &mut self,
+        luma: &mut Box<FieldBuffer>,
+        mut chroma: Option<&mut Box<FieldBuffer>>,
+        field_size: usize,
+    ) -> Result<Duration, StackError> {
+        if let Some((held_luma, held_chroma)) = &self.held {
+            luma.0[0..field_size].copy_from_slice(&held_luma.0[0..field_size]);
+            if let (Some(chroma), Some(held_chroma)) = (chroma, held_chroma) {
+                chroma.0[0..field_size].copy_from_slice(&held_chroma.0[0..field_size]);
+            }
+            if self.missing_fields() == 0 {
+                self.held = None;
+            }
+            return Ok(Duration::ZERO);
+        }
+        let read = self.read_into(luma, chroma.as_deref_mut(), field_size)?;
+        if self.missing_fields() != 0 {
+            // it is needed again
+            let keep = |field: &FieldBuffer| {
+                let mut kept = Box::<FieldBuffer>::default();
+                kept.0[0..field_size].copy_from_slice(&field.0[0..field_size]);
+                kept
+            };
+            self.held = Some((keep(luma), chroma.map(|c| keep(c))));
+        }
+        Ok(read)
+    }
+
+    /// Reads the next field of the files into `luma` and `chroma`, keeping a copy for the
+    /// temporal prefilter.
+    fn read_into(
+        &mut self,
+        luma: &mut Box<FieldBuffer>,
+        mut chroma: Option<&mut Box<FieldBuffer>>,
+        field_size: usize,
+    ) -> Result<Duration, StackError> {
+        let read = self.read_files(luma, chroma.as_deref_mut(), field_size)?;
+        if self.temporal_prefilter != 0 {
+            let mut past = self.recycle_past();
+            past.luma.0[0..field_size].copy_from_slice(&luma.0[0..field_size]);
+            if let (Some(past), Some(chroma)) = (past.chroma.as_mut(), chroma) {
+                past.0[0..field_size].copy_from_slice(&chroma.0[0..field_size]);
+            }
+            self.past.push_front(Arc::new(past));
+        }
+        Ok(read)
+    }
+
+    /// Buffers for the next field to keep for the temporal prefilter, those of the oldest kept
+    /// field if it is no longer needed.
+    fn recycle_past(&mut self) -> PastField {
+        if self.past.len() > self.temporal_prefilter * 2 {
+            if let Some(Ok(past)) = self.past.pop_back().map(Arc::try_unwrap) {
+                return past;
+            }
+        }
+        PastField {
+            luma: Box::default(),
+            chroma: self.files.has_chroma().then(Box::default),
+        }
+    }
+
+    /// The kept fields of the same parity before the last one read, the latest first.
+    fn past_fields(&self) -> Vec<Arc<PastField>> {
+        self.past.iter().skip(2).step_by(2).cloned().collect()
+    }
+
+    /// Reads the next field of the files into `luma` and `chroma`, returning how long it took.
+    fn read_files(
+        &mut self,
+        luma: &mut Box<FieldBuffer>,
+        chroma: Option<&mut Box<FieldBuffer>>,
+        field_size: usize,
+    ) -> Result<Duration, StackError> {
+        self.files
+            .read(luma, chroma, field_size)
+            .map_err(|source| StackError::Read {
+                input: self.index,
+                source,
+            })
+    }
+}
+
+/// Walks the inputs field by field, deciding what to do with each output field and reading the
+/// inputs for the workers.
+struct Dispatcher<'a> {
+    config: &'a StackConfig,
+    inputs: Vec<InputTbc>,
+    field_size: usize,
+    /// Count of fields already written by the run being resumed.
+    resumed_fields: usize,
+    /// Readers for the already written output, to rebuild the metadata of the resumed fields
+    resumed_luma: Option<BufReader<File>>,
+    resumed_chroma: Option<BufReader<File>>,
+    /// The input passed through on its own once the others ended, with `--tail passthrough`.
+    tail_input: Option<usize>,
+    /// How many fields to output (0 = all), after the duration limit is applied.
+    max_fields: usize,
+    /// The sequence number each input started on, with `align_by_seq_no`.
+    first_seq_nos: Vec<usize>,
+    /// The writer's decisions on which inputs to leave out, one after every stacked field, with
+    /// `exclude_bad_inputs`.
+    exclusions: Option<Receiver<Vec<usize>>>,
+    /// The inputs left out of the stack, as of the last decision taken in.
+    excluded: Vec<usize>,
+    /// Count of fields dispatched for stacking, and of decisions taken in.
+    stacked: usize,
+    decisions: usize,
+    /// Whether the inputs were checked for inverted luma, which is done on the first stacked field.
+    polarity_checked: bool,
+    /// Measures the levels of the inputs, with `level_match`.
+    levels: Option<LevelMatch>,
+}
+
+impl Dispatcher<'_> {
+    /// Takes in the decisions on which inputs to leave out up to [`EXCLUSION_LAG`] fields before
+    /// the next stacked one, the output field `field_idx`, waiting for the writer if needed.
+    /// Returns `false` if the writer is gone.
+    fn update_excluded(&mut self, field_idx: usize) -> bool {
+        let Some(exclusions) = &self.exclusions else {
+            return true;
+        };
+        while self.decisions + EXCLUSION_LAG <= self.stacked {
+            let Ok(excluded) = exclusions.recv() else {
+                return false;
+            };
+            self.decisions += 1;
+            for &i in excluded.iter().filter(|i| !self.excluded.contains(i)) {
+                warn!(
+                    "Leaving input #{} out of the stack from field {}, it matched poorly for too long",
+                    i + 1,
+                    field_idx + 1
+                );
+            }
+            for &i in self.excluded.iter().filter(|i| !excluded.contains(i)) {
+                info!(
+                    "Stacking input #{} again from field {}, it matches the others again",
+                    i + 1,
+                    field_idx + 1
+                );
+            }
+            self.excluded = excluded;
+        }
+        self.stacked += 1;
+        true
+    }
+
+    /// Warns about the inputs whose luma, going by its mean level on the field in `buffers`, is
+    /// nearer that of most of the others inverted than as it is.
+    fn check_polarity(&self, buffers: &FieldBuffers) {
+        let means = (self.config.inputs.iter().zip(&buffers.in_luma).enumerate())
+            .filter(|(_, (input, _))| input.planes.luma())
+            .map(|(i, (_, luma))| {
+                let field = &luma.0[0..self.field_size];
+                let sum = field.iter().map(|&v| v as u64).sum::<u64>();
+                (i, sum as f64 / field.len() as f64)
+            })
+            .collect::<Vec<_>>();
+        let max = u16::MAX as f64;
+        let inverted = means
+            .iter()
+            .filter(|&&(i, mean)| {
+                let against = means.iter().filter(|&&(j, other)| {
+                    // levels a quarter of the range apart aren't just a brighter capture
+                    j != i
+                        && (mean - other).abs() > max / 4.
+                        && (max - mean - other).abs() < (mean - other).abs()
+                });
+                against.count() * 2 > means.len() - 1
+            })
+            .collect::<Vec<_>>();
+        if !inverted.is_empty() && inverted.len() == means.len() {
+            warn!(
+                "The inputs' luma look inverted against each other, black and white swapped in some of them. Stack those inverted"
+            );
+            return;
+        }
+        for &(i, mean) in inverted {
+            warn!(
+                "The luma of input #{} looks inverted against the others, black and white swapped, its mean level is {mean:.0}. Stack it inverted if it is",
+                i + 1
+            );
+        }
+    }
+
+    /// The inputs still being read: all of them, or only the one passed through.
+    fn active(&mut self) -> impl Iterator<Item = &mut InputTbc> {
+        let tail_input = self.tail_input;
+        self.inputs
+            .iter_mut()
+            .filter(move |i| tail_input.is_none_or(|t| t == i.index))
+    }
+
+    /// Reads the current field of every active input into `buffers`, timing each read in `read`.
+    /// The reads are all started before any is waited for, so that the inputs with reader threads
+    /// wait on their drives together rather than in turn.
+    fn read_active(
+        &mut self,
+        buffers: &mut FieldBuffers,
+        read: &mut [Duration],
+    ) -> Result<(), StackError> {
+        let field_size = self.field_size;
+        for input in self.active() {
+            input.request_read(field_size)?;
+        }
+        let tail_input = self.tail_input;
+        // inputs without chroma have no chroma buffer
+        let chroma = (buffers.in_chroma.iter_mut())
+            .map(Some)
+            .chain(std::iter::repeat_with(|| None));
+        let reads = (self.inputs.iter_mut())
+            .zip(buffers.in_luma.iter_mut())
+            .zip(chroma)
+            .zip(read)
+            .filter(|(((input, _), _), _)| tail_input.is_none_or(|t| t == input.index));
+        for (((input, luma), chroma), read) in reads {
+            *read = input.read(luma, chroma, field_size)?;
+        }
+        Ok(())
+    }
+
+    /// Whether every active input has a field left. Once an input ends, the input with the most
+    /// fields left is passed through alone if the tail is kept.
+    fn fields_left(&mut self) -> bool {
+        if self.active().all(|i| !i.ended()) {
+            return true;
+        }
+        if self.tail_input.is_some() || self.config.tail == TailMode::Stop {
+            return false;
+        }
+        let Some(longest) = self
+            .inputs
+            .iter()
+            .filter(|i| !i.ended())
+            .max_by_key(|i| (i.field_count - i.field_index, Reverse(i.index)))
+        else {
+            return false;
+        };
+        info!(
+            "An input ended, passing through the remaining {} fields of input #{}",
+            longest.field_count - longest.field_index,
+            longest.index + 1
+        );
+        self.tail_input = Some(longest.index);
+        true
+    }
+
+    /// Warns about inputs whose current field is of a different field order than the reference
+    /// input's, skipping a field in them if asked to. Returns false if an input ended while doing
+    /// so.
+    fn check_field_order(&mut self) -> Result<bool, StackError> {
+        let field_kind = |first: bool| if first { "first" } else { "second" };
+        let reference = self.config.reference_input;
+        let expected = { self.inputs[reference].field().is_first_field };
+        for input in self.inputs.iter_mut().filter(|i| i.index != reference) {
+            if input.missing_fields() != 0 {
+                // standing in for a missing field, which had the right order
+                continue;
+            }
+            let field = input.field();
+            let (is_first_field, seq_no) = (field.is_first_field, field.seq_no);
+            if is_first_field == expected {
+                input.field_order_ok = true;
+                continue;
+            }
+            if input.field_order_ok {
+                warn!(
+                    "Field order mismatch in input #{}: field {} is a {} field, but input #{} is on a {} field",
+                    input.index + 1,
+                    input.field_index + 1,
+                    field_kind(is_first_field),
+                    reference + 1,
+                    field_kind(expected)
+                );
+            }
+            if self.config.fix_field_order {
+                warn!(
+                    "Skipping field {} of input #{} to fix its field order",
+                    input.field_index + 1,
+                    input.index + 1
+                );
+                input.last_seq_no = seq_no;
+                if !input.skip_field(self.field_size)? {
+                    return Ok(false);
+                }
+            } else {
+                // only warn again once it recovered and broke again
+                input.field_order_ok = false;
+            }
+        }
+        Ok(true)
+    }
+
+    /// The sequence number the current field of `input` stands for, which is past the field's
+    /// own while it is standing in for missing ones.
+    fn current_seq_no(input: &InputTbc) -> usize {
+        match input.missing_fields() {
+            0 => input.field().seq_no,
+            _ => input.last_seq_no + 1,
+        }
+    }
+
+    /// Brings every input to the field whose sequence number is as far from its first as the
+    /// reference input's is. Fields an input is behind on are skipped, and counted in
+    /// `input_dupes`. An input that is ahead has its current field used again until the
+    /// reference catches up. Returns false if an input ended while skipping.
+    fn align_by_seq_no(&mut self, input_dupes: &mut Vec<usize>) -> Result<bool, StackError> {
+        let reference = self.config.reference_input;
+        let reference_seq_no = Self::current_seq_no(&self.inputs[reference]);
+        let steps = reference_seq_no as isize - self.first_seq_nos[reference] as isize;
+        for input in self.inputs.iter_mut().filter(|i| i.index != reference) {
+            let target = self.first_seq_nos[input.index].saturating_add_signed(steps);
+            // anything newer than the field before the target is missing, and gets stood in for
+            input.last_seq_no = target.saturating_sub(1);
+            while input.field().seq_no < target {
+                warn!(
+                    "Input #{} is behind input #{}, skipping its field {}",
+                    input.index + 1,
+                    reference + 1,
+                    input.field_index + 1
+                );
+                if !input_dupes.contains(&input.index) {
+                    input_dupes.push(input.index);
+                }
+                input.dupe_count += 1;
+                if !input.skip_field(self.field_size)? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// The metadata of the current field of input `index`.
+    fn current_field(&self, index: usize) -> tbc_metadata::Field {
+        self.inputs[index].field().clone()
+    }
+
+    /// The metadata of the current field of every input.
+    fn current_fields(&self) -> Vec<tbc_metadata::Field> {
+        (0..self.inputs.len())
+            .map(|i| self.current_field(i))
+            .collect()
+    }
+
+    /// Sends a job for every output field to `jobs`, taking buffers for them from `pool`. Stops
+    /// early without an error if the writer went away, it has the error to report.
+    fn run(
+        &mut self,
+        jobs: Sender<Job>,
+        pool: Receiver<Box<FieldBuffers>>,
+    ) -> Result<(), StackError> {
+        let field_size = self.field_size;
+        let max_fields = self.max_fields;
+        let mut skip_left = self.config.skip_output_fields;
+        // whether the next output field is left out by decimation, counting it
+        let decimate = self.config.decimate;
+        let mut position = 0usize;
+        let mut decimated_out = || {
+            position += 1;
+            !(position - 1).is_multiple_of(decimate)
+        };
+
+        let mut dupes_written = 0usize;
+        let mut drop_next = false;
+        // the dupes that led to dropping the next field, reported with it
+        let mut dropped_dupes = vec![];
+        let mut seq = 0usize;
+        let mut new_field_idx = 0usize;
+
+        loop {
+            let _span = span!(Level::INFO, "field", idx = new_field_idx + 1).entered();
+
+            if max_fields != 0 && new_field_idx == max_fields {
+                // we exported the requested count of fields
+                break;
+            }
+
+            if !self.fields_left() {
+                // one of the inputs ended
+                break;
+            }
+
+            let mut should_write_dupe = false;
+            let mut input_dupes = std::mem::take(&mut dropped_dupes);
+            // when aligning by sequence number, the others simply follow the reference
+            let align_by_seq_no = self.config.align_by_seq_no && self.tail_input.is_none();
+            let reference = self.config.reference_input;
+            for f in self
+                .active()
+                .filter(|f| !align_by_seq_no || f.index == reference)
+            {
+                if f.field().seq_no <= f.last_seq_no {
+                    input_dupes.push(f.index);
+                    warn!(
+                        "Dupe in input #{}, at field {}",
+                        f.index + 1,
+                        f.field_index + 1
+                    );
+                    if f.dupe_count % 2 == dupes_written % 2 {
+                        // we only actually write out a dupe if it looks "new"
+                        should_write_dupe = true;
+                    }
+                    f.dupe_count += 1;
+                    // an input ending here is caught below
+                    f.skip_field(field_size)?;
+                }
+            }
+
+            // let's check it again after the dupe skipping
+            if !self.fields_left() {
+                break;
+            }
+
+            let job = if should_write_dupe {
+                dupes_written += 1;
+                if self.config.dupes_to_drops {
+                    warn!("Dropping dupe field and the following one");
+                    drop_next = true;
+                    dropped_dupes = input_dupes;
+                    continue;
+                } else if skip_left != 0 {
+                    // the dupe would have been an output field
+                    skip_left -= 1;
+                    continue;
+                } else if decimated_out() {
+                    continue;
+                } else {
+                    warn!("Writing out dupe");
+                }
+                if new_field_idx < self.resumed_fields {
+                    // the dupe is in the output too, skip past it
+                    self.resumed_luma
+                        .as_mut()
+                        .unwrap()
+                        .seek_relative((field_size * 2) as i64)?;
+                    if let Some(chroma) = self.resumed_chroma.as_mut() {
+                        chroma.seek_relative((field_size * 2) as i64)?;
+                    }
+                }
+                Job {
+                    seq,
+                    field_idx: new_field_idx,
+                    sources: None,
+                    input_dupes,
+                    work: Work::Dupe,
+                    timing: Timing::default(),
+                }
+            } else {
+                if align_by_seq_no && !self.align_by_seq_no(&mut input_dupes)? {
+                    // an input ended while catching up
+                    break;
+                }
+
+                // a single input has nothing to be out of order with
+                if self.tail_input.is_none() && !self.check_field_order()? {
+                    // an input ended while fixing its field order
+                    break;
+                }
+
+                for input in self.active() {
+                    let missing = input.missing_fields();
+                    if missing != 0 && input.held.is_none() {
+                        warn!(
+                            "Gap in input #{}, {} field(s) missing before field {}, using that field in their place",
+                            input.index + 1,
+                            missing,
+                            input.field_index + 1
+                        );
+                        input.gap_count += 1;
+                    }
+                }
+
+                let left_out = !drop_next && (skip_left != 0 || decimated_out());
+                if left_out {
+                    // not part of the output, only read past it
+                    for i in self.active() {
+                        i.skip(field_size)?;
+                    }
+                    for i in self.active() {
+                        i.advance()?;
+                    }
+                    skip_left = skip_left.saturating_sub(1);
+                    continue;
+                }
+
+                let tail_input = self.tail_input;
+                let sources = self
+                    .inputs
+                    .iter()
+                    .map(|i| match tail_input {
+                        Some(t) if t != i.index => 0,
+                        _ => i.field_index + 1,
+                    })
+                    .collect();
+
+                let mut timing = Timing::default();
+                let work = if new_field_idx < self.resumed_fields && !drop_next {
+                    // already stacked, skip the inputs and read back what we wrote
+                    for i in self.active() {
+                        i.skip(field_size)?;
+                    }
+                    let Ok(mut buffers) = pool.recv() else {
+                        break;
+                    };
+                    read_output(
+                        self.resumed_luma.as_mut().unwrap(),
+                        &mut buffers.out_luma.0[0..field_size],
+                    )?;
+                    if let Some(chroma) = self.resumed_chroma.as_mut() {
+                        read_output(chroma, &mut buffers.out_chroma.0[0..field_size])?;
+                    }
+                    match tail_input {
+                        Some(input) => Work::Passthrough {
+                            buffers,
+                            field: self.current_field(input),
+                            input,
+                            resumed: true,
+                            level: None,
+                        },
+                        None => Work::Resumed {
+                            buffers,
+                            fields: self.current_fields(),
+                        },
+                    }
+                } else if drop_next {
+                    // no need to stack a field we're throwing away
+                    for i in self.active() {
+                        i.skip(field_size)?;
+                    }
+                    Work::Drop
+                } else {
+                    let Ok(mut buffers) = pool.recv() else {
+                        break;
+                    };
+                    timing.read = vec![Duration::ZERO; self.inputs.len()];
+                    self.read_active(&mut buffers, &mut timing.read)?;
+                    match tail_input {
+                        Some(input) => Work::Passthrough {
+                            level: self.levels.as_ref().map(|l| l.corrections()[input]),
+                            buffers,
+                            field: self.current_field(input),
+                            input,
+                            resumed: false,
+                        },
+                        None => {
+                            if !self.polarity_checked {
+                                self.check_polarity(&buffers);
+                                self.polarity_checked = true;
+                            }
+                            if !self.update_excluded(new_field_idx) {
+                                break;
+                            }
+                            Work::Stack {
+                                levels: self.levels.as_mut().map(|l| l.update(&buffers.in_luma)),
+                                buffers,
+                                fields: self.current_fields(),
+                                excluded: self.excluded.clone(),
+                                past: self.inputs.iter().map(InputTbc::past_fields).collect(),
+                            }
+                        }
+                    }
+                };
+
+                for i in self.active() {
+                    i.advance()?;
+                }
+
+                Job {
+                    seq,
+                    field_idx: new_field_idx,
+                    sources: Some(sources),
+                    input_dupes,
+                    work,
+                    timing,
+                }
+            };
+
+            let dropped = matches!(job.work, Work::Drop);
+            if jobs.send(job).is_err() {
+                break;
+            }
+            seq += 1;
+
+            if dropped {
+                drop_next = false;
+            } else {
+                new_field_idx += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds the fields of input `index` from sequence number `start` to `end`, for
+/// [`InputConfig::start_seq_no`], [`StackConfig::seq_no_start`] and [`StackConfig::seq_no_end`].
+/// Returns the 1-based start field, `start_field` if there is no `start`, and how many fields the
+/// input has up to `end`. The input's own start sequence number must be one of its fields, a
+/// `start` for every input may fall in a gap and start on the field after it.
+fn find_seq_nos(
+    index: usize,
+    input: &InputConfig,
+    start: Option<usize>,
+    end: Option<usize>,
+) -> Result<(usize, Option<usize>), StackError> {
+    let start = input.start_seq_no.or(start);
+    let json = PathBuf::from(input.basename.clone() + ".tbc.json");
+    let file = File::open(&json).map_err(|source| StackError::Open {
+        path: json.clone(),
+        source,
+    })?;
+    let mut fields = FieldStream::spawn(file, 0);
+    let (mut first, mut last) = (None, 0);
+    let (mut start_field, mut exact_start_field, mut end_fields) = (None, None, None);
+    let mut idx = 0;
+    while let Some(field) = fields.next().map_err(|source| StackError::BadMetadata {
+        path: json.clone(),
+        source,
+    })? {
+        let seq_no = field.seq_no;
+        first.get_or_insert(seq_no);
+        last = last.max(seq_no);
+        if start.is_some_and(|start| seq_no >= start) {
+            start_field.get_or_insert(idx + 1);
+        }
+        if start == Some(seq_no) {
+            exact_start_field.get_or_insert(idx + 1);
+        }
+        if end.is_some_and(|end| seq_no > end) {
+            end_fields.get_or_insert(idx);
+        }
+        idx += 1;
+    }
+
+    let first = first.unwrap_or(0);
+    let not_covered = |seq_no| StackError::SeqNoRange {
+        input: index,
+        seq_no,
+        first,
+        last,
+    };
+    let start_field = match start {
+        Some(start) if start < first || start > last => return Err(not_covered(start)),
+        Some(seq_no) if input.start_seq_no.is_some() => {
+            exact_start_field.ok_or(StackError::SeqNoMissing {
+                input: index,
+                seq_no,
+            })?
+        }
+        Some(_) => start_field.unwrap(),
+        None => input.start_field,
+    };
+    let end_fields = match end {
+        Some(end) if end < first || end > last => return Err(not_covered(end)),
+        // an input skipping both ends of a short range has none of it
+        Some(end) if end_fields.is_some_and(|fields| fields < start_field) => {
+            return Err(not_covered(end))
+        }
+        Some(_) => Some(end_fields.unwrap_or(idx)),
+        None => None,
+    };
+    Ok((start_field, end_fields))
+}
+
+/// Checks that every input has the same system and field dimensions as the `reference` one, as
+/// the samples would be misinterpreted otherwise.
+fn check_inputs_match(inputs: &[InputTbc], reference: usize) -> Result<(), StackError> {
+    let reference_index = reference;
+    let reference = &inputs[reference].metadata.video_parameters;
+    for input in inputs.iter().filter(|i| i.index != reference_index) {
+        let params = &input.metadata.video_parameters;
+        let mismatch = |what, expected: String, found: String| StackError::InputMismatch {
+            input: input.index,
+            reference: reference_index,
+            what,
+            expected,
+            found,
+        };
+        if params.system != reference.system {
+            return Err(mismatch(
+                "system",
+                reference.system.to_string(),
+                params.system.to_string(),
+            ));
+        }
+        if params.field_width != reference.field_width {
+            return Err(mismatch(
+                "field width",
+                reference.field_width.to_string(),
+                params.field_width.to_string(),
+            ));
+        }
+        if params.field_height != reference.field_height {
+            return Err(mismatch(
+                "field height",
+                reference.field_height.to_string(),
+                params.field_height.to_string(),
+            ));
+        }
+
+        // captures of the same tape should be roughly the same length
+        let (a, b) = (inputs[reference_index].field_count, input.field_count);
+        if a.abs_diff(b) > a.max(b) / 10 {
+            warn!(
+                "Input #{} has {} fields, but input #{} has {}. Are these captures of the same tape?",
+                input.index + 1,
+                b,
+                reference_index + 1,
+                a
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Looks for inputs given twice, which would outweigh the others: the same file, or files of the
+/// same size starting with the same field. Warns about them, or fails with
+/// `config.reject_duplicate_inputs`.
+fn check_duplicates(config: &StackConfig, inputs: &[InputTbc]) -> Result<(), StackError> {
+    let paths = config
+        .inputs
+        .iter()
+        .map(|i| PathBuf::from(i.basename.clone() + ".tbc"))
+        .collect::<Vec<_>>();
+    let canonical = paths
+        .iter()
+        .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+        .collect::<Vec<_>>();
+    let sizes = inputs
+        .iter()
+        .map(|i| {
+            i.files.byte_len().map_err(|source| StackError::Read {
+                input: i.index,
+                source,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // only hashed when the sizes match, that's rare enough
+    let mut hashes = vec![None; inputs.len()];
+    let mut first_field_hash = |i: usize| -> Result<u64, StackError> {
+        if let Some(hash) = hashes[i] {
+            return Ok(hash);
+        }
+        let params = &inputs[i].metadata.video_parameters;
+        let mut field = vec![0u16; params.field_width * params.field_height];
+        // the byte order makes no difference to whether the fields are the same
+        TbcReader::open(&paths[i], field.len() * 2, Endian::Native)
+            .and_then(|mut tbc| tbc.read(&mut field))
+            .map_err(|source| StackError::Read { input: i, source })?;
+        let mut hasher = DefaultHasher::new();
+        field.hash(&mut hasher);
+        let hash = hasher.finish();
+        hashes[i] = Some(hash);
+        Ok(hash)
+    };
+    for input in 0..inputs.len() {
+        for other in 0..input {
+            let reason = if canonical[input] == canonical[other] {
+                "is the same file as"
+            } else if sizes[input].is_some()
+                && sizes[input] == sizes[other]
+                && first_field_hash(input)? == first_field_hash(other)?
+            {
+                "has the same size and first field as"
+            } else {
+                continue;
+            };
+            let e = StackError::DuplicateInput {
+                input,
+                other,
+                reason,
+            };
+            if config.reject_duplicate_inputs {
+                return Err(e);
+            }
+            warn!("{e}");
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Creates a new output file, or opens an existing one for appending after `resumed_fields`
+/// fields when resuming.
+fn open_output(
+    config: &StackConfig,
+    path: &str,
+    field_bytes: usize,
+    resumed_fields: usize,
+    io_buffer: Option<usize>,
+) -> Result<TbcWriter, StackError> {
+    if config.analyze {
+        return Ok(TbcWriter::Discard);
+    }
+    if config.compress_output {
+        return Ok(TbcWriter::Flac(FlacEncoder::spawn(
+            path.into(),
+            config.overwrite,
+        )?));
+    }
+    let file = if config.resume {
+        resume::open_output(path, field_bytes, resumed_fields)?
+    } else {
+        let file = if config.overwrite {
+            File::create(path)
+        } else {
+            File::create_new(path)
+        };
+        file.map_err(|source| match source.kind() {
+            io::ErrorKind::AlreadyExists => StackError::OutputExists { path: path.into() },
+            _ => StackError::Create {
+                path: path.into(),
+                source,
+            },
+        })?
+    };
+    Ok(TbcWriter::Raw(BufWriter::with_capacity(
+        io_buffer_size(io_buffer, field_bytes / 2),
+        file,
+    )))
+}
+
+/// How many sets of field buffers the dispatcher may have in flight with `threads` workers.
+fn pool_size(threads: usize) -> usize {
+    threads + 2
+}
+
+/// Splits `config.memory_budget` between the field buffers of `threads` workers and the I/O
+/// buffers of the `.tbc` files, returning the size of the latter for each file. `None` without a
+/// budget.
+fn budget_io_buffers(config: &StackConfig, threads: usize) -> Result<Option<usize>, StackError> {
+    let Some(budget) = config.memory_budget else {
+        return Ok(None);
+    };
+    // count every input as having chroma, the budget is an upper bound
+    let outputs = 2
+        + usize::from(config.confidence_output.is_some())
+        + usize::from(config.diff_output.is_some());
+    let files = config.inputs.len() * 2 + outputs;
+    // mapped inputs are read from the page cache, only the outputs have I/O buffers then
+    let buffered = if config.mmap_inputs { outputs } else { files };
+    // one set of field buffers per file, for each of the pool_size() + 1 in the pool, the fields
+    // the inputs keep for the temporal prefilter, and the one each reader thread reads into
+    let past = match config.temporal_prefilter {
+        0 => 0,
+        n => (n * 2 + 1) * config.inputs.len() * 2,
+    };
+    let readers = buffered - outputs;
+    let field_buffers =
+        ((pool_size(threads) + 1) * files + past + readers) * size_of::<FieldBuffer>();
+    // anything less than a field per file would make reads tiny
+    let needed = field_buffers + buffered * size_of::<FieldBuffer>();
+    if budget < needed {
+        return Err(StackError::InvalidOption(format!(
+            "Memory budget is too small, at least {} MiB is needed with {} inputs and {} worker threads",
+            needed.div_ceil(1 << 20),
+            config.inputs.len(),
+            threads
+        )));
+    }
+    Ok(Some((budget - field_buffers) / buffered))
+}
+
+/// Size of the I/O buffer of a `.tbc` file with fields of `field_size` samples, `budgeted` by
+/// [`budget_io_buffers`] if given.
+fn io_buffer_size(budgeted: Option<usize>, field_size: usize) -> usize {
+    let default = field_size * IO_BUFFER_MULTIPLIER;
+    budgeted.map_or(default, |size| size.min(default))
+}
+
+/// Stacks the inputs described by `config`, writing the output `.tbc` and `_chroma.tbc` files.
+///
+/// Returns the output metadata and the per-field metrics, the caller decides where those go. A
+/// read or write error once stacking has started stops the run with [`StackError::Incomplete`],
+/// which still has them for the fields written.
+pub fn stack(config: &StackConfig) -> Result<StackReport, StackError> {
+    stack_with_observer(config, &mut ())
+}
+
+/// Like [`stack`], but reports each field to `observer` as soon as it is written.
+pub fn stack_with_observer(
+    config: &StackConfig,
+    observer: &mut dyn StackObserver,
+) -> Result<StackReport, StackError> {
+    if !(MIN_INPUT_STREAMS..=MAX_INPUT_STREAMS).contains(&config.inputs.len()) {
+        return Err(StackError::InputCount(config.inputs.len()));
+    }
+
+    if config.mode == StackMode::TrimmedMean && config.inputs.len() < 4 {
+        return Err(StackError::InvalidOption(
+            "Trimmed mean needs at least 4 inputs, use median with 3 inputs instead".into(),
+        ));
+    }
+
+    let threads = config.threads.unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    let threads = threads.max(1);
+    // which kernels a slow run got is the first question about it
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    info!(
+        "Stacking with {threads} worker threads on {cpus} logical CPUs, using {} instructions",
+        median::INSTRUCTION_SET
+    );
+    let supported = median::supported_instruction_set();
+    if supported != median::INSTRUCTION_SET {
+        info!(
+            "The CPU supports {supported} too, build with RUSTFLAGS=\"-C target-cpu=native\" to \
+             use it"
+        );
+    }
+    let io_buffer = budget_io_buffers(config, threads)?;
+
+    // the inputs with their start fields found by sequence number, for the rest to go by
+    let resolved;
+    let mut end_fields = vec![None; config.inputs.len()];
+    let by_seq_no = config.seq_no_start.is_some()
+        || config.seq_no_end.is_some()
+        || config.inputs.iter().any(|i| i.start_seq_no.is_some());
+    let config = if by_seq_no {
+        if let Some((start, end)) = config.seq_no_start.zip(config.seq_no_end) {
+            if end < start {
+                return Err(StackError::InvalidOption(
+                    "The sequence number range ends before it starts".into(),
+                ));
+            }
+        }
+        let mut config = config.clone();
+        for (i, input) in config.inputs.iter_mut().enumerate() {
+            let (start_field, end) =
+                find_seq_nos(i, input, config.seq_no_start, config.seq_no_end)?;
+            info!(
+                "Input #{} has the sequence numbers in fields {start_field} to {}",
+                i + 1,
+                end.map_or("its end".into(), |end| end.to_string())
+            );
+            input.start_field = start_field;
+            end_fields[i] = end;
+        }
+        resolved = config;
+        &resolved
+    } else {
+        config
+    };
+
+    let mut inputs = config
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            InputTbc::open(
+                i,
+                input,
+                config.size_mismatch,
+                config.input_endian,
+                io_buffer,
+                config.mmap_inputs,
+                config.temporal_prefilter,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (input, end) in inputs.iter_mut().zip(end_fields) {
+        // stops the input there, like the end of its files would
+        if let Some(end) = end {
+            input.field_count = input.field_count.min(end);
+        }
+    }
+
+    let reference = config.reference_input;
+    if reference >= inputs.len() {
+        return Err(StackError::InvalidOption(format!(
+            "Reference input #{} doesn't exist",
+            reference + 1
+        )));
+    }
+    if config.mode == StackMode::DropoutFill && config.base_input >= inputs.len() {
+        return Err(StackError::InvalidOption(format!(
+            "Base input #{} doesn't exist",
+            config.base_input + 1
+        )));
+    }
+
+    check_inputs_match(&inputs, reference)?;
+    check_duplicates(config, &inputs)?;
+
+    if inputs[reference].dupe_count != 0 {
+        return Err(StackError::ReferenceFieldOrder { input: reference });
+    }
+
+    let system = inputs[reference].metadata.video_parameters.system.clone();
+    if config.metadata_version == Some(MetadataVersion::Legacy) && system == System::PalM {
+        return Err(StackError::InvalidOption(
+            "PAL-M can't be written in the legacy metadata schema, which only tells PAL from NTSC"
+                .into(),
+        ));
+    }
+    let metadata_input = match config.mode {
+        StackMode::DropoutFill => config.base_input,
+        _ => reference,
+    };
+    let video_parameters = &inputs[metadata_input].metadata.video_parameters;
+    let missing = REQUIRED_VIDEO_PARAMETERS
+        .into_iter()
+        .filter(|key| !video_parameters.other.contains_key(*key))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        warn!(
+            "The metadata of input #{} has no {}, which ld-decode's tools need to decode the picture, and the output's won't either",
+            metadata_input + 1,
+            missing.join(", ")
+        );
+    }
+
+    // ld-decode's LaserDisc and composite captures come without chroma, the output only gets it
+    // if every input has it
+    let have_chroma = inputs.iter().all(|i| i.files.has_chroma());
+    if !have_chroma {
+        if inputs.iter().all(|i| !i.files.has_chroma()) {
+            info!("The inputs have no _chroma.tbc, stacking luma only");
+        } else {
+            for input in inputs.iter_mut() {
+                if !input.files.drop_chroma() {
+                    warn!(
+                        "Input #{} has no _chroma.tbc, stacking luma only and leaving out the chroma of the others",
+                        input.index + 1
+                    );
+                }
+            }
+        }
+    }
+
+    if config.weighted && !matches!(config.mode, StackMode::Median | StackMode::Mean) {
+        return Err(StackError::InvalidOption(
+            "Weighting is only supported with median and mean".into(),
+        ));
+    }
+
+    let weights = config.inputs.iter().map(|i| i.weight).collect::<Vec<_>>();
+    if weights.iter().any(|&w| w != 1) {
+        if weights.contains(&0) {
+            return Err(StackError::InvalidOption(
+                "Input weights must be at least 1".into(),
+            ));
+        }
+        if config.weighted {
+            return Err(StackError::InvalidOption(
+                "Input weights can't be combined with automatic weighting".into(),
+            ));
+        }
+        if config.mode == StackMode::DropoutFill {
+            return Err(StackError::InvalidOption(
+                "Input weights aren't supported with dropout fill".into(),
+            ));
+        }
+        if weights.iter().sum::<usize>() > MAX_INPUT_STREAMS {
+            return Err(StackError::InvalidOption(format!(
+                "The input weights add up to {}, more than the {MAX_INPUT_STREAMS} inputs that can \
+                 be combined",
+                weights.iter().sum::<usize>()
+            )));
+        }
+    }
+
+    if config.inputs.iter().any(|i| i.planes != Planes::Both) {
+        if config.mode == StackMode::DropoutFill {
+            return Err(StackError::InvalidOption(
+                "Luma or chroma only inputs aren't supported with dropout fill".into(),
+            ));
+        }
+        let min = match config.mode {
+            StackMode::TrimmedMean => 4,
+            _ => MIN_INPUT_STREAMS,
+        };
+        let count = |takes_part: fn(Planes) -> bool| {
+            config
+                .inputs
+                .iter()
+                .filter(|i| takes_part(i.planes))
+                .count()
+        };
+        let mut planes = vec![("luma", count(Planes::luma))];
+        if have_chroma {
+            planes.push(("chroma", count(Planes::chroma)));
+        }
+        for (plane, count) in planes {
+            if count < min {
+                return Err(StackError::InvalidOption(format!(
+                    "Only {count} inputs take part in the {plane}, at least {min} are needed"
+                )));
+            }
+        }
+        if let Some(i) = config
+            .inputs
+            .iter()
+            .position(|i| i.planes == Planes::ChromaOnly)
+            .filter(|_| !have_chroma)
+        {
+            return Err(StackError::InvalidOption(format!(
+                "Input #{} only takes part in the chroma, but the stack has none",
+                i + 1
+            )));
+        }
+    }
+
+    if config.temporal_prefilter >= MAX_INPUT_STREAMS {
+        return Err(StackError::InvalidOption(format!(
+            "The temporal prefilter can take at most {} earlier fields",
+            MAX_INPUT_STREAMS - 1
+        )));
+    }
+
+    if config.median_space != MedianSpace::Raw && config.mode == StackMode::DropoutFill {
+        return Err(StackError::InvalidOption(
+            "Combining in linear light isn't supported with dropout fill".into(),
+        ));
+    }
+
+    if config.edge_from_best && config.mode == StackMode::DropoutFill {
+        return Err(StackError::InvalidOption(
+            "Taking the edges from the best input isn't supported with dropout fill".into(),
+        ));
+    }
+
+    if config.exclude_bad_inputs && config.mode == StackMode::DropoutFill {
+        return Err(StackError::InvalidOption(
+            "Leaving out bad inputs isn't supported with dropout fill".into(),
+        ));
+    }
+
+    // when filling dropouts, the threshold is for the other inputs agreeing on the base input's
+    let voters = match config.mode {
+        StackMode::DropoutFill => inputs.len() - 1,
+        _ => inputs.len(),
+    };
+    let dropout_threshold = config.dropout_threshold.unwrap_or(voters.div_ceil(2));
+    // more than can agree would never mark a dropout, leaving an output that looks clean
+    if dropout_threshold > voters {
+        return Err(StackError::InvalidOption(format!(
+            "Dropout threshold {dropout_threshold} is more than the {voters} inputs that can agree on a dropout"
+        )));
+    }
+
+    let field_width = inputs[reference].metadata.video_parameters.field_width;
+    let field_height = inputs[reference].metadata.video_parameters.field_height;
+    let field_size = field_width * field_height;
+    let field_size_rounded = field_size.div_ceil(32) * 32;
+    let sys = SystemConstants::for_field(&system, field_width, field_height).with_windows(
+        config.bpsnr_window,
+        config.rmse_window,
+        field_size,
+    )?;
+
+    // the duration is cut at a whole frame, for the output to end on a second field
+    let max_fields = match config.max_duration {
+        Some(duration) => {
+            let fields = (duration.as_secs_f64() * sys.frame_rate) as usize * 2;
+            if fields == 0 {
+                return Err(StackError::InvalidOption(
+                    "Maximum duration is shorter than a frame".into(),
+                ));
+            }
+            match config.max_fields {
+                0 => fields,
+                max_fields => fields.min(max_fields),
+            }
+        }
+        None => config.max_fields,
+    };
+
+    if !config.skip_output_fields.is_multiple_of(2) {
+        return Err(StackError::InvalidOption(
+            "The count of output fields to skip must be even, for the output to start on a first \
+             field"
+                .into(),
+        ));
+    }
+
+    if !(8..=16).contains(&config.output_bits) {
+        return Err(StackError::InvalidOption(format!(
+            "Output samples can be rounded to 8 to 16 bits, not {}",
+            config.output_bits
+        )));
+    }
+
+    if config.decimate == 0 {
+        return Err(StackError::InvalidOption(
+            "Decimation must keep every 1st field or fewer".into(),
+        ));
+    }
+
+    if config.halign_range >= sys.useful_start_sample
+        || config.halign_range > field_size - sys.useful_end_sample
+    {
+        return Err(StackError::InvalidOption(
+            "Horizontal alignment range is too large".into(),
+        ));
+    }
+
+    if let Some(i) = config
+        .inputs
+        .iter()
+        .position(|i| i.sample_offset.unsigned_abs() >= field_width)
+    {
+        return Err(StackError::InvalidOption(format!(
+            "Sample offset of input #{} is larger than the field width",
+            i + 1
+        )));
+    }
+    if let Some(i) = config
+        .inputs
+        .iter()
+        .position(|i| i.vshift.unsigned_abs() >= field_height)
+    {
+        return Err(StackError::InvalidOption(format!(
+            "Vertical shift of input #{} is larger than the field height",
+            i + 1
+        )));
+    }
+
+    let rmse_edge_taper = config.rmse_edge_taper.div_ceil(KERNEL_LANES) * KERNEL_LANES;
+    if rmse_edge_taper * 2 > sys.useful_end_sample - sys.useful_start_sample {
+        return Err(StackError::InvalidOption(format!(
+            "RMSE edge taper of {rmse_edge_taper} samples doesn't fit twice in the RMSE window of \
+             {} samples",
+            sys.useful_end_sample - sys.useful_start_sample
+        )));
+    }
+
+    if config.rmse_warn.streak == 0 {
+        return Err(StackError::InvalidOption(
+            "RMSE warning streak must be at least 1".into(),
+        ));
+    }
+
+    if config.align_by_seq_no && config.fix_field_order {
+        return Err(StackError::InvalidOption(
+            "Aligning by sequence number keeps the field order of the reference input already, \
+             it can't be combined with fixing the field order"
+                .into(),
+        ));
+    }
+
+    if config.compress_output && config.resume {
+        return Err(StackError::InvalidOption(
+            "Compressed output can't be resumed, FLAC files can't be appended to".into(),
+        ));
+    }
+
+    if config.drop_on_max_rmse.is_some() && config.resume {
+        return Err(StackError::InvalidOption(
+            "Frames dropped for the inputs disagreeing can't be resumed, the fields written don't \
+             line up with the inputs anymore"
+                .into(),
+        ));
+    }
+    if config
+        .drop_on_max_rmse
+        .is_some_and(|psnr| !psnr.is_finite())
+    {
+        return Err(StackError::InvalidOption(
+            "The RMSE pSNR to drop frames below must be a finite number".into(),
+        ));
+    }
+
+    if config.detect_dropouts.is_some() && config.resume {
+        return Err(StackError::InvalidOption(
+            "Dropouts can't be detected when resuming, the already written fields aren't \
+             compared to the inputs again"
+                .into(),
+        ));
+    }
+
+    if config.analyze && (config.resume || config.compress_output) {
+        return Err(StackError::InvalidOption(
+            "Analyzing doesn't write any output to resume or compress".into(),
+        ));
+    }
+    if config.analyze && config.efm_passthrough {
+        return Err(StackError::InvalidOption(
+            "Analyzing doesn't write any output to pass the EFM data through to".into(),
+        ));
+    }
+    if config.analyze && config.confidence_output.is_some() {
+        return Err(StackError::InvalidOption(
+            "Analyzing doesn't write any output, not even a confidence map".into(),
+        ));
+    }
+    if config.analyze && config.diff_output.is_some() {
+        return Err(StackError::InvalidOption(
+            "Analyzing doesn't write any output to compare the reference input to".into(),
+        ));
+    }
+
+    if let SideMetadata::Input(i) = config.side_metadata {
+        if i >= inputs.len() {
+            return Err(StackError::InvalidOption(format!(
+                "Side metadata input #{} doesn't exist",
+                i + 1
+            )));
+        }
+    }
+
+    let luma_path = config.output_basename.clone() + ".tbc";
+    let chroma_path = config.output_basename.clone() + "_chroma.tbc";
+    let spread_path = config
+        .confidence_output
+        .as_ref()
+        .map(|basename| basename.clone() + ".tbc");
+    let diff_path = config
+        .diff_output
+        .as_ref()
+        .map(|basename| basename.clone() + ".tbc");
+    let efm_path = config
+        .efm_passthrough
+        .then(|| config.output_basename.clone() + ".efm");
+    let field_bytes = field_size * 2;
+    if config.mmap_inputs {
+        info!(
+            "Mapping the inputs into memory, using {} KiB of I/O buffer for each output .tbc file",
+            io_buffer_size(io_buffer, field_size) >> 10
+        );
+    } else {
+        info!(
+            "Using {} KiB of I/O buffer for each .tbc file",
+            io_buffer_size(io_buffer, field_size) >> 10
+        );
+    }
+
+    let resume_info = ResumeInfo {
+        input_basename: config.inputs.iter().map(|i| i.basename.clone()).collect(),
+        start_field: config.inputs.iter().map(|i| i.start_field).collect(),
+        sample_offset: config.inputs.iter().map(|i| i.sample_offset).collect(),
+        vshift: config.inputs.iter().map(|i| i.vshift).collect(),
+        input_weight: config.inputs.iter().map(|i| i.weight).collect(),
+        planes: config
+            .inputs
+            .iter()
+            .map(|i| format!("{:?}", i.planes))
+            .collect(),
+        invert: config.inputs.iter().map(|i| i.invert).collect(),
+        mode: format!("{:?}", config.mode),
+        even_median: format!("{:?}", config.even_median),
+        median_space: format!("{:?}", config.median_space),
+        input_endian: format!("{:?}", config.input_endian),
+        weighted: config.weighted,
+        edge_from_best: config.edge_from_best,
+        level_match: config.level_match,
+        base_input: config.base_input,
+        reference_input: reference,
+        dupes_to_drops: config.dupes_to_drops,
+        drop_on_max_rmse: config.drop_on_max_rmse,
+        dropout_threshold,
+        keep_reference_dropouts: config.keep_reference_dropouts,
+        halign_range: config.halign_range,
+        fix_field_order: config.fix_field_order,
+        align_by_seq_no: config.align_by_seq_no,
+        tail: format!("{:?}", config.tail),
+        skip_output_fields: config.skip_output_fields,
+        decimate: config.decimate,
+        output_bits: config.output_bits,
+        rmse_window: config.rmse_window,
+        exclude_bad_inputs: config.exclude_bad_inputs,
+        temporal_prefilter: config.temporal_prefilter,
+        interpolate_common_dropouts: config.interpolate_common_dropouts,
+        confidence_output: config.confidence_output.clone(),
+        diff_output: config.diff_output.clone(),
+    };
+    let resumed_fields = if config.resume {
+        resume_info.check(&config.output_basename)?;
+        let mut fields = resume::complete_fields(&luma_path, field_bytes)?;
+        if have_chroma {
+            fields = fields.min(resume::complete_fields(&chroma_path, field_bytes)?);
+        }
+        for path in spread_path.iter().chain(&diff_path) {
+            fields = fields.min(resume::complete_fields(path, field_bytes)?);
+        }
+        info!("Resuming after {fields} already written fields");
+        fields
+    } else {
+        0
+    };
+
+    let first_seq_nos = inputs
+        .iter()
+        .map(|i| i.field.as_ref().map_or(0, |f| f.seq_no))
+        .collect();
+
+    let remaining = inputs
+        .iter()
+        .map(|i| i.field_count.saturating_sub(i.field_index));
+    let remaining = match config.tail {
+        TailMode::Stop => remaining.min(),
+        TailMode::Passthrough => remaining.max(),
+    }
+    .unwrap()
+    .saturating_sub(config.skip_output_fields)
+    .div_ceil(config.decimate);
+    let total = if max_fields != 0 {
+        remaining.min(max_fields)
+    } else {
+        remaining
+    };
+    let run = RunInfo {
+        system: system.clone(),
+        field_width,
+        field_height,
+        inputs: inputs
+            .iter()
+            .map(|i| RunInput {
+                basename: config.inputs[i.index].basename.clone(),
+                start_field: config.inputs[i.index].start_field,
+                sample_offset: config.inputs[i.index].sample_offset,
+                vshift: config.inputs[i.index].vshift,
+                field_count: i.field_count,
+            })
+            .collect(),
+    };
+    if !config.resume && !config.overwrite && !config.analyze {
+        // fail before anything gets written, not halfway through creating the outputs
+        let outputs = [Some(&luma_path), have_chroma.then_some(&chroma_path)];
+        let side_outputs = [spread_path.as_ref(), diff_path.as_ref(), efm_path.as_ref()];
+        for path in outputs.into_iter().chain(side_outputs).flatten() {
+            if std::fs::exists(path).unwrap_or(false) {
+                return Err(StackError::OutputExists { path: path.into() });
+            }
+        }
+    }
+    observer.start(&run, total, resumed_fields)?;
+
+    if !config.resume && !config.analyze {
+        resume_info.save(&config.output_basename)?;
+    }
+    let open_resumed = |path: &str| -> Result<BufReader<File>, StackError> {
+        Ok(BufReader::new(File::open(path).map_err(|source| {
+            StackError::Open {
+                path: path.into(),
+                source,
+            }
+        })?))
+    };
+    let resumed_luma = if resumed_fields != 0 {
+        Some(open_resumed(&luma_path)?)
+    } else {
+        None
+    };
+    let resumed_chroma = if resumed_fields != 0 && have_chroma {
+        Some(open_resumed(&chroma_path)?)
+    } else {
+        None
+    };
+
+    let out_luma = open_output(config, &luma_path, field_bytes, resumed_fields, io_buffer)?;
+    let out_chroma = if have_chroma {
+        Some(open_output(
+            config,
+            &chroma_path,
+            field_bytes,
+            resumed_fields,
+            io_buffer,
+        )?)
+    } else {
+        None
+    };
+
+    let out_spread = spread_path
+        .as_ref()
+        .map(|path| open_output(config, path, field_bytes, resumed_fields, io_buffer))
+        .transpose()?;
+    let out_diff = diff_path
+        .as_ref()
+        .map(|path| open_output(config, path, field_bytes, resumed_fields, io_buffer))
+        .transpose()?;
+
+    let params = StackParams {
+        mode: config.mode,
+        sample_offsets: config.inputs.iter().map(|i| i.sample_offset).collect(),
+        vshifts: config.inputs.iter().map(|i| i.vshift).collect(),
+        input_weights: config.inputs.iter().map(|i| i.weight).collect(),
+        planes: config.inputs.iter().map(|i| i.planes).collect(),
+        weighted: config.weighted,
+        edge_from_best: config.edge_from_best,
+        base_input: match config.mode {
+            StackMode::DropoutFill => config.base_input,
+            _ => reference,
+        },
+        sys,
+        field_width,
+        field_height,
+        field_size,
+        field_size_rounded,
+        dropout_threshold,
+        keep_reference_dropouts: config.keep_reference_dropouts,
+        have_chroma,
+        halign_range: config.halign_range,
+        rmse_edge_taper,
+        line_metrics_every: config.line_metrics_every,
+        side_metadata: config.side_metadata,
+        vits_metrics: config.vits_metrics,
+        detect_dropouts: config.detect_dropouts.map(|ire| sys.ire_to_samples(ire)),
+        interpolate_common_dropouts: config.interpolate_common_dropouts,
+        linear_light: (config.median_space == MedianSpace::Linear)
+            .then(|| sys.linear_light_table()),
+        even_median: match config.even_median {
+            EvenMedian::Avg => median::EvenMedian::Average,
+            EvenMedian::Low => median::EvenMedian::Low,
+            EvenMedian::High => median::EvenMedian::High,
+        },
+    };
+
+    let (decisions_tx, decisions_rx) = config
+        .exclude_bad_inputs
+        .then(channel::<Vec<usize>>)
+        .unzip();
+    let writer = Writer {
+        sys,
+        field_width,
+        field_size,
+        output_bits: config.output_bits,
+        rounded: vec![],
+        out_luma,
+        out_chroma,
+        out_spread,
+        out_diff,
+        out_fields: Vec::new(),
+        reports: Vec::new(),
+        resumed_fields,
+        observer,
+        rmse_warn: config.rmse_warn,
+        rmse_bad_in_a_row: vec![0usize; inputs.len()],
+        rmse_high_in_a_row: vec![0usize; inputs.len()],
+        phase_mismatches: 0,
+        stalls: Stalls::default(),
+        exclusion: decisions_tx.map(|decisions| Exclusion {
+            decisions,
+            excluded: vec![],
+            good_in_a_row: vec![0; inputs.len()],
+            min_inputs: match config.mode {
+                StackMode::TrimmedMean => 4,
+                _ => MIN_INPUT_STREAMS,
+            },
+        }),
+        last: None,
+        drop_on_max_rmse: config.drop_on_max_rmse,
+        frame: vec![],
+        disagreement_drops: 0,
+    };
+
+    // mapped inputs are read straight from memory, the others each get a reader thread
+    for input in inputs.iter_mut() {
+        input.files.spawn_reader();
+    }
+    let mut dispatcher = Dispatcher {
+        config,
+        inputs,
+        field_size,
+        resumed_fields,
+        resumed_luma,
+        resumed_chroma,
+        tail_input: None,
+        max_fields,
+        first_seq_nos,
+        exclusions: decisions_rx,
+        excluded: vec![],
+        stacked: 0,
+        decisions: 0,
+        polarity_checked: false,
+        levels: config
+            .level_match
+            .then(|| LevelMatch::new(params.planes.len(), params.base_input, &sys)),
+    };
+
+    // Buffers circulate from the dispatcher through a worker to the writer, then back here. The
+    // pool size bounds how far the dispatcher may run ahead of the writer. The writer holds on to
+    // one extra set for writing dupes.
+    let pool_size = pool_size(threads);
+    let (pool_tx, pool_rx) = sync_channel::<Box<FieldBuffers>>(pool_size + 1);
+    for _ in 0..pool_size + 1 {
+        pool_tx
+            .send(Box::new(FieldBuffers::new(
+                dispatcher.inputs.len(),
+                have_chroma,
+                spread_path.is_some(),
+                diff_path.is_some(),
+            )))
+            .unwrap();
+    }
+    let (job_tx, job_rx) = channel::<Job>();
+    let job_rx = Mutex::new(job_rx);
+    let (result_tx, result_rx) = channel::<JobResult>();
+
+    let (written, dispatched) = thread::scope(|s| {
+        for _ in 0..threads {
+            let params = &params;
+            let job_rx = &job_rx;
+            let result_tx = result_tx.clone();
+            s.spawn(move || stack_worker(params, job_rx, result_tx));
+        }
+        drop(result_tx);
+        let writer = s.spawn(move || writer.run(result_rx, pool_tx));
+
+        // everything dispatched so far still gets written if the dispatcher fails
+        let dispatched = dispatcher.run(job_tx, pool_rx);
+        (writer.join().unwrap(), dispatched)
+    });
+    let (
+        Writer {
+            out_luma,
+            out_chroma,
+            out_spread,
+            out_diff,
+            mut out_fields,
+            reports,
+            phase_mismatches,
+            stalls,
+            disagreement_drops,
+            ..
+        },
+        written,
+    ) = written;
+    // an output error is what stopped the dispatcher, if both failed
+    let mut failure = written.and(dispatched).err();
+    let outputs = [Some(out_luma), out_chroma, out_spread, out_diff];
+    for output in outputs.into_iter().flatten() {
+        if let Err(e) = output.finish() {
+            failure.get_or_insert(e);
+        }
+    }
+    if failure.is_some() && !config.analyze && !config.compress_output {
+        // what a failed write left of the next field, so the fields written are all there is
+        let paths = [Some(&luma_path), have_chroma.then_some(&chroma_path)];
+        let side_paths = [spread_path.as_ref(), diff_path.as_ref()];
+        for path in paths.into_iter().chain(side_paths).flatten() {
+            if let Err(e) = resume::open_output(path, field_bytes, out_fields.len()) {
+                warn!("Couldn't trim {path} to the fields written: {e}");
+            }
+        }
+    }
+    if config.efm_passthrough && failure.is_none() {
+        // the fields of the reference input from the first to the last one in the output
+        let base = params.base_input;
+        let fields = reports
+            .iter()
+            .filter_map(|r| r.sources.as_ref())
+            .map(|s| s[base])
+            .filter(|&f| f != 0);
+        match fields.clone().min().zip(fields.max()) {
+            Some((first, last)) => copy_efm(
+                &config.inputs[base].basename,
+                first - 1..last,
+                &config.output_basename,
+                config.overwrite || config.resume,
+            )?,
+            None => {
+                warn!("No field of the reference input is in the output, not copying its EFM data")
+            }
+        }
+    }
+
+    for (idx, field) in out_fields.iter_mut().enumerate() {
+        field.is_first_field = idx % 2 == 0;
+        if !config.preserve_seq_no {
+            field.seq_no = idx + 1;
+        }
+    }
+    if config.preserve_seq_no {
+        // dupes written repeat the field before them, its seqNo too
+        let mut not_increasing = (1..out_fields.len())
+            .filter(|&idx| out_fields[idx].seq_no <= out_fields[idx - 1].seq_no);
+        if let Some(first) = not_increasing.next() {
+            warn!(
+                "The output's seqNo doesn't increase at {} field(s), first at output field {}, which ld-decode's tools may take as dupes",
+                not_increasing.count() + 1,
+                first + 1
+            );
+        }
+    }
+
+    let stacked = reports.iter().filter(|r| r.kind == FieldKind::Stacked);
+    if config.dropout_threshold.is_some_and(|t| t > 1)
+        && stacked.clone().next().is_some()
+        && stacked.map(|r| r.dropouts).sum::<usize>() == 0
+    {
+        warn!(
+            "No dropouts were marked in any stacked field with a dropout threshold of {}, lower it if the inputs have dropouts",
+            params.dropout_threshold
+        );
+    }
+
+    if phase_mismatches > 1 {
+        warn!(
+            "The inputs disagreed on fieldPhaseID in {phase_mismatches} of the stacked fields, the output's chroma may not decode right in those"
+        );
+    }
+
+    if disagreement_drops != 0 {
+        warn!(
+            "{disagreement_drops} fields were dropped as every input disagreed with them, check the start fields"
+        );
+    }
+
+    if stalls.count > 1 {
+        warn!(
+            "{} fields took over {}x as long as the ones before them, see the warnings above for which stage held them up",
+            stalls.count,
+            STALL_FACTOR
+        );
+    }
+
+    if out_fields.is_empty() && failure.is_none() {
+        warn!("No output fields were written, the output is empty. Check the start fields, and that the output fields skipped don't cover all of the stack");
+    }
+
+    let dupes = dispatcher
+        .inputs
+        .iter()
+        .map(|i| i.dupe_count - i.start_parity)
+        .collect::<Vec<_>>();
+    let gaps = dispatcher
+        .inputs
+        .iter()
+        .map(|i| i.gap_count)
+        .collect::<Vec<_>>();
+    let inputs = InputSummary::collect(config, &reports, &dupes, &gaps);
+
+    let mut metadata = dispatcher.inputs[params.base_input].metadata.clone();
+    metadata.video_parameters.number_of_sequential_fields = out_fields.len();
+    if let Some(version) = config.metadata_version {
+        metadata.video_parameters.version = version;
+    }
+    metadata.fields = out_fields;
+    metadata
+        .other
+        .insert("stackInfo".into(), stack_info(config, &reports, &inputs));
+
+    let report = StackReport {
+        metadata,
+        fields: reports,
+        inputs,
+    };
+    match failure {
+        Some(source) => Err(StackError::Incomplete {
+            report: Box::new(report),
+            source: Box::new(source),
+        }),
+        None => Ok(report),
+    }
+}
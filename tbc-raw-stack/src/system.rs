@@ -0,0 +1,242 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::tbc_metadata::System;
+use crate::StackError;
+use tracing::{info, warn};
+
+/// The highest pSNR reported, that of a field or line without any error, which would be infinite
+/// otherwise.
+pub const MAX_PSNR: f32 = 100.;
+
+/// Sample count the median kernels work in for `u16`, which the RMSE window has to be aligned to.
+pub(crate) const KERNEL_LANES: usize = median::BLOCK_BYTES / 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SystemConstants {
+    /// Start sample for calculating black pSNR
+    pub black_start_sample: usize,
+
+    /// End sample for calculating black pSNR
+    pub black_end_sample: usize,
+
+    /// Start sample for calculating RMSE pSNR
+    pub useful_start_sample: usize,
+
+    /// End sample for calculating RMSE pSNR
+    pub useful_end_sample: usize,
+
+    /// Difference between black and white
+    pub psnr_scale: f32,
+
+    /// Sample value of white
+    pub white_level: u16,
+
+    /// Frames per second
+    pub frame_rate: f64,
+
+    /// Where the sample positions above are in the field's lines
+    pub geometry: LineGeometry,
+}
+
+/// The lines the black pSNR and RMSE windows are on, so they can be placed in a field sampled at a
+/// different rate than the nominal one the sample positions are for.
+#[derive(Clone, Copy, Debug)]
+pub struct LineGeometry {
+    /// Field width the sample positions are for
+    pub field_width: usize,
+
+    /// Line of the black pSNR window (0-based)
+    pub black_line: usize,
+
+    /// First line of the RMSE window (0-based)
+    pub useful_start_line: usize,
+
+    /// Line the RMSE window ends before (0-based)
+    pub useful_end_line: usize,
+}
+
+impl SystemConstants {
+    pub fn for_system(system: &System) -> &'static SystemConstants {
+        match system {
+            System::Pal => &SYSTEM_PAL,
+            System::Ntsc => &SYSTEM_NTSC,
+            System::PalM => &SYSTEM_PALM,
+        }
+    }
+
+    /// The constants for the system, with the windows placed on the same lines of a field of the
+    /// given dimensions, and scaled along them if the field is wider or narrower than nominal.
+    /// Falls back to the nominal sample positions if the field doesn't have those lines.
+    pub fn for_field(system: &System, field_width: usize, field_height: usize) -> SystemConstants {
+        let sys = *Self::for_system(system);
+        let geometry = sys.geometry;
+        if field_width == geometry.field_width {
+            return sys;
+        }
+        if field_width == 0 || field_height < geometry.useful_end_line {
+            warn!(
+                "Can't find the pSNR windows in a {field_width}x{field_height} {system} field, \
+                 using the sample positions of a {} samples wide one",
+                geometry.field_width
+            );
+            return sys;
+        }
+
+        // the position along the line scales with the width, the line stays the same
+        let nominal = geometry.field_width;
+        let scale = |sample: usize, line: usize| {
+            line * field_width + (sample - line * nominal) * field_width / nominal
+        };
+        let mut scaled = sys;
+        scaled.black_start_sample = scale(sys.black_start_sample, geometry.black_line);
+        scaled.black_end_sample = scale(sys.black_end_sample, geometry.black_line);
+        scaled.useful_start_sample =
+            (geometry.useful_start_line * field_width).div_ceil(KERNEL_LANES) * KERNEL_LANES;
+        scaled.useful_end_sample =
+            geometry.useful_end_line * field_width / KERNEL_LANES * KERNEL_LANES;
+        scaled.geometry.field_width = field_width;
+        info!(
+            "Field width {field_width} isn't the nominal {nominal} of {system}, pSNR windows \
+             scaled to it"
+        );
+        scaled
+    }
+
+    /// These constants with the black pSNR and RMSE windows replaced by the given sample ranges,
+    /// if any. The RMSE window is aligned to whole blocks of the median kernels, widening it
+    /// unless that would go past the end of the field.
+    pub fn with_windows(
+        &self,
+        bpsnr_window: Option<(usize, usize)>,
+        rmse_window: Option<(usize, usize)>,
+        field_size: usize,
+    ) -> Result<SystemConstants, StackError> {
+        let check = |name: &str, (start, end): (usize, usize)| {
+            if start >= end || end > field_size {
+                return Err(StackError::InvalidOption(format!(
+                    "{name} window {start}..{end} must be a non-empty range within the field of \
+                     {field_size} samples"
+                )));
+            }
+            Ok((start, end))
+        };
+        let mut sys = *self;
+        if let Some(window) = bpsnr_window {
+            (sys.black_start_sample, sys.black_end_sample) = check("Black pSNR", window)?;
+        }
+        if let Some(window) = rmse_window {
+            let (start, end) = check("RMSE", window)?;
+            sys.useful_start_sample = start / KERNEL_LANES * KERNEL_LANES;
+            sys.useful_end_sample = (end.div_ceil(KERNEL_LANES) * KERNEL_LANES)
+                .min(field_size / KERNEL_LANES * KERNEL_LANES);
+            if sys.useful_start_sample >= sys.useful_end_sample {
+                return Err(StackError::InvalidOption(format!(
+                    "RMSE window {start}..{end} is too short"
+                )));
+            }
+            if (sys.useful_start_sample, sys.useful_end_sample) != (start, end) {
+                info!(
+                    "RMSE window aligned to {}..{} to fit the median kernel",
+                    sys.useful_start_sample, sys.useful_end_sample
+                );
+            }
+        }
+        Ok(sys)
+    }
+
+    /// The pSNR of an RMS error of `error`, at most [`MAX_PSNR`]. A NaN error, which there is no
+    /// pSNR for, gets it too, as `min` skips NaN.
+    pub fn error_to_psnr(&self, error: f32) -> f32 {
+        (20. * (self.psnr_scale / error).log10()).min(MAX_PSNR)
+    }
+
+    /// The linear light of every sample value, black being 0 and white 1: the inverse of the
+    /// BT.709 transfer function, carried on below black by its linear toe so that sync and
+    /// overshoots keep their order. Increasing, so each value can be looked up back.
+    pub fn linear_light_table(&self) -> Vec<f32> {
+        let black = self.white_level as f64 - self.psnr_scale as f64;
+        (0..=u16::MAX)
+            .map(|v| {
+                let x = (v as f64 - black) / self.psnr_scale as f64;
+                let linear = if x < 0.081 {
+                    x / 4.5
+                } else {
+                    ((x + 0.099) / 1.099).powf(1. / 0.45)
+                };
+                linear as f32
+            })
+            .collect()
+    }
+
+    /// A difference of `ire` IRE in sample values, the black to white range being 100 IRE.
+    pub fn ire_to_samples(&self, ire: f32) -> u16 {
+        (ire * self.psnr_scale / 100.)
+            .round()
+            .clamp(0., u16::MAX as f32) as u16
+    }
+}
+
+const SYSTEM_PAL: SystemConstants = SystemConstants {
+    black_start_sample: 24048,
+    black_end_sample: 24928, // 24 935 originally but we pick a nicer number
+    useful_start_sample: 61312, // line 55
+    useful_end_sample: 258752, // line 229
+    psnr_scale: 0.7 * (0xD300 - 0x0100) as f32,
+    white_level: 0xD300,
+    frame_rate: 25.,
+    geometry: LineGeometry {
+        field_width: 1135,
+        black_line: 21,
+        useful_start_line: 54,
+        useful_end_line: 228,
+    },
+};
+
+const SYSTEM_NTSC: SystemConstants = SystemConstants {
+    black_start_sample: 144,    // 143 originally
+    black_end_sample: 432,      // 429 originally
+    useful_start_sample: 27328, // line 31
+    useful_end_sample: 209280,  // line 231
+    psnr_scale: 0.75 * (0xC800 - 0x0400) as f32,
+    white_level: 0xC800,
+    frame_rate: 30000. / 1001.,
+    geometry: LineGeometry {
+        field_width: 910,
+        black_line: 0,
+        useful_start_line: 30,
+        useful_end_line: 230,
+    },
+};
+
+// PAL-M shares NTSC's 525-line geometry and levels, but lines are 909 samples
+const SYSTEM_PALM: SystemConstants = SystemConstants {
+    black_start_sample: 144,    // 143 originally
+    black_end_sample: 432,      // 428 originally
+    useful_start_sample: 27296, // line 31
+    useful_end_sample: 209056,  // line 231
+    psnr_scale: 0.75 * (0xC800 - 0x0400) as f32,
+    white_level: 0xC800,
+    frame_rate: 30000. / 1001.,
+    geometry: LineGeometry {
+        field_width: 909,
+        black_line: 0,
+        useful_start_line: 30,
+        useful_end_line: 230,
+    },
+};
+
+pub fn calculate_bpsnr(field: &[u16], constants: &SystemConstants) -> f32 {
+    let region = &field[constants.black_start_sample..constants.black_end_sample];
+    let len = region.len();
+    let sum = region.iter().map(|&v| v as u64).sum::<u64>();
+    let mean = sum as f32 / len as f32;
+    let mut variance = 0f32;
+    for &v in region {
+        let dev = v as f32 - mean;
+        variance += dev * dev;
+    }
+    let stddev = (variance / len as f32).sqrt();
+    constants.error_to_psnr(stddev)
+}
@@ -2,8 +2,10 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use crate::MetadataVersion;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum System {
@@ -15,22 +17,106 @@ pub enum System {
     PalM,
 }
 
+impl fmt::Display for System {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            System::Pal => "PAL",
+            System::Ntsc => "NTSC",
+            System::PalM => "PAL-M",
+        })
+    }
+}
+
+impl System {
+    /// Frames per second, twice as many fields.
+    pub fn frame_rate(&self) -> f64 {
+        crate::system::SystemConstants::for_system(self).frame_rate
+    }
+}
+
+/// The `videoParameters` of either [`MetadataVersion`], written back in the one of `version`.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(try_from = "RawVideoParameters", into = "RawVideoParameters")]
 pub struct VideoParameters {
-    #[serde(rename = "numberOfSequentialFields")]
     pub number_of_sequential_fields: usize,
+    pub system: System,
+    pub field_width: usize,
+    pub field_height: usize,
+    /// Which of the schemas names the system.
+    pub version: MetadataVersion,
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+/// The keys of `videoParameters` ld-decode's tools need to decode the picture, besides the ones
+/// [`VideoParameters`] has.
+pub const REQUIRED_VIDEO_PARAMETERS: [&str; 7] = [
+    "sampleRate",
+    "colourBurstStart",
+    "colourBurstEnd",
+    "activeVideoStart",
+    "activeVideoEnd",
+    "white16bIre",
+    "black16bIre",
+];
+
+/// `videoParameters` as stored, with the system named either way.
+#[derive(Serialize, Deserialize)]
+struct RawVideoParameters {
+    #[serde(rename = "numberOfSequentialFields")]
+    number_of_sequential_fields: usize,
 
     #[serde(rename = "system")]
-    pub system: System,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<System>,
+
+    #[serde(rename = "isSourcePal")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_source_pal: Option<bool>,
 
     #[serde(rename = "fieldWidth")]
-    pub field_width: usize,
+    field_width: usize,
 
     #[serde(rename = "fieldHeight")]
-    pub field_height: usize,
+    field_height: usize,
 
     #[serde(flatten)]
-    pub other: HashMap<String, serde_json::Value>,
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl TryFrom<RawVideoParameters> for VideoParameters {
+    type Error = String;
+
+    fn try_from(raw: RawVideoParameters) -> Result<Self, String> {
+        // `system` wins over a leftover `isSourcePal`, which can't tell PAL-M
+        let (system, version) = match (raw.system, raw.is_source_pal) {
+            (Some(system), _) => (system, MetadataVersion::Current),
+            (None, Some(true)) => (System::Pal, MetadataVersion::Legacy),
+            (None, Some(false)) => (System::Ntsc, MetadataVersion::Legacy),
+            (None, None) => return Err("videoParameters has neither system nor isSourcePal".into()),
+        };
+        Ok(VideoParameters {
+            number_of_sequential_fields: raw.number_of_sequential_fields,
+            system,
+            field_width: raw.field_width,
+            field_height: raw.field_height,
+            version,
+            other: raw.other,
+        })
+    }
+}
+
+impl From<VideoParameters> for RawVideoParameters {
+    fn from(v: VideoParameters) -> Self {
+        let legacy = v.version == MetadataVersion::Legacy;
+        RawVideoParameters {
+            number_of_sequential_fields: v.number_of_sequential_fields,
+            system: (!legacy).then_some(v.system.clone()),
+            is_source_pal: legacy.then_some(v.system == System::Pal),
+            field_width: v.field_width,
+            field_height: v.field_height,
+            other: v.other,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -44,6 +130,7 @@ pub struct VitsMetrics {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct DropOuts {
+    /// 1-based, as ld-decode writes it and ld-analyse reads it
     #[serde(rename = "fieldLine")]
     pub field_line: Vec<usize>,
 
@@ -54,6 +141,23 @@ pub struct DropOuts {
     pub endx: Vec<usize>,
 }
 
+impl DropOuts {
+    /// The line of dropout `j` as a 0-based index into the field's lines, `None` if it isn't one
+    /// of its `field_height` lines.
+    pub fn line_index(&self, j: usize, field_height: usize) -> Option<usize> {
+        self.field_line[j]
+            .checked_sub(1)
+            .filter(|&line| line < field_height)
+    }
+
+    /// Adds a dropout from `startx` to `endx` on the line of 0-based index `line`.
+    pub fn push(&mut self, line: usize, startx: usize, endx: usize) {
+        self.field_line.push(line + 1);
+        self.startx.push(startx);
+        self.endx.push(endx);
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Field {
     #[serde(rename = "isFirstField")]
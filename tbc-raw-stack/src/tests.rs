@@ -0,0 +1,880 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Tests of where stacking ends: how many output fields inputs of slightly different lengths,
+//! start fields and dupes at their ends make, and of the RMSE metrics and samples of the stacked
+//! fields. Each test writes small synthetic captures to a directory of its own under the
+//! system's temporary directory.
+
+use super::{
+    stack, stack_with_observer, Endian, FieldKind, FieldReport, InputConfig, MedianSpace,
+    MetadataVersion, Planes, RmseWarn, SizeMismatch, StackConfig, StackError, StackMode,
+    StackObserver, TailMode,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const FIELD_WIDTH: usize = 64;
+const FIELD_HEIGHT: usize = 263;
+const FIELD_SIZE: usize = FIELD_WIDTH * FIELD_HEIGHT;
+
+/// A directory for the captures and output of one test, removed when dropped.
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("tbc-raw-stack-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    fn basename(&self, name: &str) -> String {
+        self.0.join(name).to_str().unwrap().to_string()
+    }
+
+    /// Writes a capture `name` whose fields have the sequence numbers `seq_nos`, starting on a
+    /// first field, and returns its basename.
+    fn capture(&self, name: &str, seq_nos: &[usize]) -> String {
+        let basename = self.basename(name);
+        let fields = seq_nos
+            .iter()
+            .enumerate()
+            .map(|(i, seq_no)| format!(r#"{{"isFirstField":{},"seqNo":{seq_no}}}"#, i % 2 == 0))
+            .collect::<Vec<_>>()
+            .join(",");
+        let json = format!(
+            r#"{{"videoParameters":{{"numberOfSequentialFields":{},"system":"NTSC","fieldWidth":{FIELD_WIDTH},"fieldHeight":{FIELD_HEIGHT}}},"fields":[{fields}]}}"#,
+            seq_nos.len()
+        );
+        fs::write(basename.clone() + ".tbc.json", json).unwrap();
+        // a different picture for every field and capture, so none look like duplicates
+        let salt = name.bytes().map(usize::from).sum::<usize>();
+        let samples = (0..seq_nos.len() * FIELD_SIZE)
+            .map(|i| (16000 + (i * 7 + salt) % 97) as u16)
+            .collect::<Vec<_>>();
+        write_samples(basename.clone() + ".tbc", &samples);
+        basename
+    }
+
+    /// Writes `count` captures `a`, `b` and so on, each of `fields` fields numbered from 1, and
+    /// returns their basenames.
+    fn captures(&self, count: usize, fields: usize) -> Vec<String> {
+        (b'a'..)
+            .take(count)
+            .map(|name| self.capture(&char::from(name).to_string(), &seq_nos(fields)))
+            .collect()
+    }
+
+    /// A config stacking `captures`, each given with its start field, into `out`.
+    fn config(&self, captures: &[(&str, usize)]) -> StackConfig {
+        let inputs = captures
+            .iter()
+            .map(|&(basename, start_field)| InputConfig {
+                basename: basename.to_string(),
+                start_field,
+                start_seq_no: None,
+                sample_offset: 0,
+                vshift: 0,
+                weight: 1,
+                planes: Planes::Both,
+                invert: false,
+            })
+            .collect();
+        let mut config = StackConfig::new(inputs, self.basename("out"));
+        config.threads = Some(2);
+        config
+    }
+
+    /// A config stacking `captures`, all from `start_field`, into `out`.
+    fn config_at(&self, captures: &[String], start_field: usize) -> StackConfig {
+        let captures = captures
+            .iter()
+            .map(|c| (c.as_str(), start_field))
+            .collect::<Vec<_>>();
+        self.config(&captures)
+    }
+
+    /// Stacks with `config`, returning the count of output fields after checking that the
+    /// output file and metadata agree on it.
+    fn output_fields(&self, config: &StackConfig) -> usize {
+        let report = stack(config).unwrap();
+        let fields = report.metadata.fields.len();
+        let written = fs::metadata(self.basename("out") + ".tbc").unwrap().len();
+        assert_eq!(written, (fields * FIELD_SIZE * 2) as u64);
+        fields
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn seq_nos(count: usize) -> Vec<usize> {
+    (1..=count).collect()
+}
+
+/// Reads the little-endian samples of the `.tbc` file at `path`.
+fn read_samples(path: impl AsRef<Path>) -> Vec<u16> {
+    fs::read(path)
+        .unwrap()
+        .chunks_exact(2)
+        .map(|s| u16::from_le_bytes([s[0], s[1]]))
+        .collect()
+}
+
+/// Writes `samples` to the `.tbc` file at `path`, little-endian.
+fn write_samples(path: impl AsRef<Path>, samples: &[u16]) {
+    let bytes = samples
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect::<Vec<_>>();
+    fs::write(path, bytes).unwrap();
+}
+
+/// Gives the first field of the capture `basename` the `dropOuts` of `dropouts`.
+fn set_dropouts(basename: &str, dropouts: &serde_json::Value) {
+    let path = basename.to_string() + ".tbc.json";
+    let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    json["fields"][0]["dropOuts"] = dropouts.clone();
+    fs::write(&path, json.to_string()).unwrap();
+}
+
+#[test]
+fn ends_with_the_shortest_input() {
+    let dir = TestDir::new("shortest");
+    let a = dir.capture("a", &seq_nos(10));
+    let b = dir.capture("b", &seq_nos(11));
+    let c = dir.capture("c", &seq_nos(10));
+    assert_eq!(
+        dir.output_fields(&dir.config(&[(&a, 1), (&b, 1), (&c, 1)])),
+        10
+    );
+    // the same the other way around, whichever input is the reference
+    let mut config = dir.config(&[(&b, 1), (&a, 1), (&c, 1)]);
+    config.overwrite = true;
+    assert_eq!(dir.output_fields(&config), 10);
+    config.reference_input = 1;
+    assert_eq!(dir.output_fields(&config), 10);
+}
+
+#[test]
+fn start_fields_count_towards_the_length() {
+    let dir = TestDir::new("start");
+    let a = dir.capture("a", &seq_nos(12));
+    let b = dir.capture("b", &seq_nos(11));
+    // 10 fields from a's start field on, one more from b's
+    assert_eq!(dir.output_fields(&dir.config(&[(&a, 3), (&b, 1)])), 10);
+    let mut config = dir.config(&[(&a, 5), (&b, 1)]);
+    config.overwrite = true;
+    assert_eq!(dir.output_fields(&config), 8);
+    config.inputs[1].start_field = 5;
+    assert_eq!(dir.output_fields(&config), 7);
+}
+
+#[test]
+fn passthrough_keeps_the_last_field() {
+    let dir = TestDir::new("passthrough");
+    let a = dir.capture("a", &seq_nos(10));
+    let b = dir.capture("b", &seq_nos(11));
+    let mut config = dir.config(&[(&a, 1), (&b, 1)]);
+    config.tail = TailMode::Passthrough;
+    assert_eq!(dir.output_fields(&config), 11);
+}
+
+#[test]
+fn max_fields_past_the_end_stops_at_the_end() {
+    let dir = TestDir::new("max");
+    let a = dir.capture("a", &seq_nos(10));
+    let b = dir.capture("b", &seq_nos(11));
+    let mut config = dir.config(&[(&a, 1), (&b, 1)]);
+    config.max_fields = 11;
+    assert_eq!(dir.output_fields(&config), 10);
+    config.max_fields = 10;
+    config.overwrite = true;
+    assert_eq!(dir.output_fields(&config), 10);
+    config.max_fields = 9;
+    assert_eq!(dir.output_fields(&config), 9);
+}
+
+#[test]
+fn dupe_as_the_last_field_is_not_written() {
+    let dir = TestDir::new("dupe");
+    let a = dir.capture("a", &seq_nos(10));
+    // the extra field repeats the last one
+    let b = dir.capture("b", &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 10]);
+    let mut config = dir.config(&[(&a, 1), (&b, 1)]);
+    config.tail = TailMode::Passthrough;
+    assert_eq!(dir.output_fields(&config), 10);
+}
+
+#[test]
+fn dupe_before_the_end_is_written() {
+    let dir = TestDir::new("dupe-before");
+    let a = dir.capture("a", &seq_nos(10));
+    // a dupe in the middle makes up for the extra field
+    let b = dir.capture("b", &[1, 2, 3, 4, 5, 5, 6, 7, 8, 9, 10]);
+    assert_eq!(dir.output_fields(&dir.config(&[(&a, 1), (&b, 1)])), 11);
+}
+
+#[test]
+fn decimation_keeps_the_first_of_every_n() {
+    let dir = TestDir::new("decimate");
+    let a = dir.capture("a", &seq_nos(10));
+    let b = dir.capture("b", &seq_nos(10));
+    let mut config = dir.config(&[(&a, 1), (&b, 1)]);
+    config.decimate = 3;
+    assert_eq!(dir.output_fields(&config), 4);
+    config.decimate = 5;
+    config.overwrite = true;
+    assert_eq!(dir.output_fields(&config), 2);
+}
+
+#[test]
+fn rmse_matches_a_plain_median() {
+    let dir = TestDir::new("rmse");
+    let captures = dir.captures(3, 4);
+    let mut config = dir.config_at(&captures, 1);
+    // the samples outside the window are combined separately, their error must not leak in
+    let window = 10 * FIELD_WIDTH..200 * FIELD_WIDTH;
+    config.rmse_window = Some((window.start, window.end));
+    let report = stack(&config).unwrap();
+
+    let samples = (captures.iter())
+        .map(|basename| read_samples(basename.clone() + ".tbc"))
+        .collect::<Vec<_>>();
+    // NTSC's black to white range
+    let scale = 0.75 * (0xC800 - 0x0400) as f64;
+    for (f, field) in report.fields.iter().enumerate() {
+        let mut sse = [0u64; 3];
+        for i in window.clone().map(|i| f * FIELD_SIZE + i) {
+            let mut column = samples.iter().map(|s| s[i]).collect::<Vec<_>>();
+            column.sort_unstable();
+            for (sse, s) in sse.iter_mut().zip(&samples) {
+                *sse += (s[i].abs_diff(column[1]) as u64).pow(2);
+            }
+        }
+        for (k, &sse) in sse.iter().enumerate() {
+            let rmse = (sse as f64 / window.len() as f64).sqrt();
+            // capped, the median input is close to the median here
+            let psnr = (20. * (scale / rmse).log10()).min(100.);
+            let got = field.rmse_psnr[k] as f64;
+            assert!(
+                (got - psnr).abs() < 1e-3,
+                "field {f} input {k}: {got} != {psnr}"
+            );
+        }
+    }
+}
+
+#[test]
+fn linear_median_of_odd_inputs_is_the_raw_one() {
+    let dir = TestDir::new("linear");
+    let a = dir.capture("a", &seq_nos(4));
+    let b = dir.capture("b", &seq_nos(4));
+    let c = dir.capture("c", &seq_nos(4));
+    let mut config = dir.config(&[(&a, 1), (&b, 1), (&c, 1)]);
+    stack(&config).unwrap();
+    let raw = fs::read(dir.basename("out") + ".tbc").unwrap();
+    // the middle value is the same in any increasing transform, and must come back unchanged
+    config.median_space = MedianSpace::Linear;
+    config.overwrite = true;
+    stack(&config).unwrap();
+    assert!(raw == fs::read(dir.basename("out") + ".tbc").unwrap());
+}
+
+#[test]
+fn identical_inputs_have_the_highest_psnr() {
+    let dir = TestDir::new("identical");
+    let a = dir.capture("a", &seq_nos(4));
+    // the same capture twice, and once more under another name
+    let copy = dir.basename("copy");
+    fs::copy(a.clone() + ".tbc", copy.clone() + ".tbc").unwrap();
+    fs::copy(a.clone() + ".tbc.json", copy.clone() + ".tbc.json").unwrap();
+    let report = stack(&dir.config(&[(&a, 1), (&a, 1), (&copy, 1)])).unwrap();
+    for field in &report.fields {
+        assert_eq!(field.rmse_psnr, [100.; 3]);
+        assert!(field.bpsnr.unwrap().is_finite());
+    }
+    for input in &report.inputs {
+        assert_eq!(input.mean_rmse_psnr, Some(100.));
+        assert_eq!(input.bad_fields, 0);
+    }
+}
+
+#[test]
+fn a_psnr_that_isnt_finite_doesnt_decide_for_the_others() {
+    let warn = RmseWarn::default();
+    let rmse_psnr = [f32::NAN, 40., 20.];
+    assert!(!warn.is_bad(&rmse_psnr, 0));
+    assert!(!warn.is_bad(&rmse_psnr, 1));
+    assert!(warn.is_bad(&rmse_psnr, 2));
+    assert!(!warn.is_bad(&[f32::INFINITY, 20.], 1));
+}
+
+#[test]
+fn a_duplicate_pair_is_suspiciously_high() {
+    let warn = RmseWarn::default();
+    // two copies of a capture make the median, the third barely counts
+    let rmse_psnr = [100., 100., 35.];
+    assert!(warn.is_high(&rmse_psnr, 0));
+    assert!(warn.is_high(&rmse_psnr, 1));
+    assert!(!warn.is_high(&rmse_psnr, 2));
+    assert!(!warn.is_bad(&rmse_psnr, 2));
+    // a better capture isn't
+    assert!(!warn.is_high(&[44., 38., 36.], 0));
+    assert!(!warn.is_high(&[f32::NAN, 20.], 1));
+}
+
+#[test]
+fn big_endian_inputs_stack_the_same() {
+    let dir = TestDir::new("endian");
+    let captures = dir.captures(3, 4);
+    let mut config = dir.config_at(&captures, 1);
+    stack(&config).unwrap();
+    let little = fs::read(dir.basename("out") + ".tbc").unwrap();
+
+    for basename in &captures {
+        let mut samples = fs::read(basename.clone() + ".tbc").unwrap();
+        for sample in samples.chunks_exact_mut(2) {
+            sample.swap(0, 1);
+        }
+        fs::write(basename.clone() + ".tbc", samples).unwrap();
+    }
+    config.input_endian = Endian::Be;
+    config.overwrite = true;
+    stack(&config).unwrap();
+    // the output is little-endian either way
+    assert!(little == fs::read(dir.basename("out") + ".tbc").unwrap());
+}
+
+/// Fails the run once it gets to field `.0`.
+struct FailAt(usize);
+
+impl StackObserver for FailAt {
+    fn field(&mut self, report: &FieldReport) -> Result<(), StackError> {
+        if report.field == self.0 {
+            return Err(StackError::InvalidOption("failed".into()));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn a_run_that_stops_reports_the_fields_written() {
+    let dir = TestDir::new("incomplete");
+    let a = dir.capture("a", &seq_nos(10));
+    let b = dir.capture("b", &seq_nos(10));
+    let c = dir.capture("c", &seq_nos(10));
+    let config = dir.config(&[(&a, 1), (&b, 1), (&c, 1)]);
+    let Err(StackError::Incomplete { report, source }) =
+        stack_with_observer(&config, &mut FailAt(4))
+    else {
+        panic!("the run didn't stop");
+    };
+    assert!(matches!(*source, StackError::InvalidOption(_)));
+    // the field reported last is written already
+    let fields = report.metadata.fields.len();
+    assert_eq!(fields, 4);
+    assert_eq!(
+        report.metadata.video_parameters.number_of_sequential_fields,
+        fields
+    );
+    let written = fs::metadata(dir.basename("out") + ".tbc").unwrap().len();
+    assert_eq!(written, (fields * FIELD_SIZE * 2) as u64);
+}
+
+#[test]
+fn vshift_undoes_an_input_a_line_off() {
+    let dir = TestDir::new("vshift");
+    let captures = dir.captures(3, 4);
+    let mut config = dir.config_at(&captures, 1);
+    stack(&config).unwrap();
+    let aligned = fs::read(dir.basename("out") + ".tbc").unwrap();
+
+    // decoded a line too high, every line of it is the next one of the others'
+    let path = captures[2].clone() + ".tbc";
+    let mut samples = fs::read(&path).unwrap();
+    for field in samples.chunks_exact_mut(FIELD_SIZE * 2) {
+        field.copy_within(FIELD_WIDTH * 2.., 0);
+    }
+    fs::write(&path, samples).unwrap();
+    config.inputs[2].vshift = 1;
+    config.overwrite = true;
+    stack(&config).unwrap();
+    let shifted = fs::read(dir.basename("out") + ".tbc").unwrap();
+
+    // only the top line, made up from the one below it, can differ
+    for (a, s) in aligned
+        .chunks_exact(FIELD_SIZE * 2)
+        .zip(shifted.chunks_exact(FIELD_SIZE * 2))
+    {
+        assert!(a[FIELD_WIDTH * 2..] == s[FIELD_WIDTH * 2..]);
+    }
+}
+
+#[test]
+fn diff_output_is_the_magnified_difference_to_the_reference() {
+    let dir = TestDir::new("diff");
+    let captures = dir.captures(3, 4);
+    let mut config = dir.config_at(&captures, 1);
+    config.diff_output = Some(dir.basename("diff"));
+    stack(&config).unwrap();
+
+    let out = read_samples(dir.basename("out") + ".tbc");
+    let reference = read_samples(captures[0].clone() + ".tbc");
+    let diff = read_samples(dir.basename("diff") + ".tbc");
+    assert_eq!(diff.len(), out.len());
+    for (i, &d) in diff.iter().enumerate() {
+        assert_eq!(d, out[i].abs_diff(reference[i]) * 4);
+    }
+}
+
+#[test]
+fn dropouts_on_the_first_and_last_lines_survive_the_merge() {
+    let dir = TestDir::new("dropout-lines");
+    let captures = dir.captures(3, 4);
+    // fieldLine counts from 1 as in ld-decode, so 0 is no line of the field
+    let dropouts = serde_json::json!({
+        "fieldLine": [0, 1, FIELD_HEIGHT],
+        "startx": [3, 0, 5],
+        "endx": [9, 10, 20],
+    });
+    for capture in &captures {
+        set_dropouts(capture, &dropouts);
+    }
+    let config = dir.config_at(&captures, 1);
+    let report = stack(&config).unwrap();
+    let merged = report.metadata.fields[0].drop_outs.as_ref().unwrap();
+    assert_eq!(merged.field_line, [1, FIELD_HEIGHT]);
+    assert_eq!(merged.startx, [0, 5]);
+    assert_eq!(merged.endx, [10, 20]);
+}
+
+#[test]
+fn dropouts_move_with_the_horizontal_alignment() {
+    let dir = TestDir::new("halign-dropouts");
+    let captures = dir.captures(3, 4);
+    let samples = read_samples(captures[0].clone() + ".tbc");
+    // b has the picture of a two samples to the right, c a level above it
+    let mut shifted = samples.clone();
+    for field in shifted.chunks_exact_mut(FIELD_SIZE) {
+        field.copy_within(0..FIELD_SIZE - 2, 2);
+    }
+    write_samples(captures[1].clone() + ".tbc", &shifted);
+    let above = samples.iter().map(|s| s + 1).collect::<Vec<_>>();
+    write_samples(captures[2].clone() + ".tbc", &above);
+    // the same dropout in b and c, where their pictures have it
+    let dropout = |startx: usize| {
+        let endx = startx + 10;
+        serde_json::json!({"fieldLine": [20], "startx": [startx], "endx": [endx]})
+    };
+    set_dropouts(&captures[1], &dropout(12));
+    set_dropouts(&captures[2], &dropout(10));
+
+    let mut config = dir.config_at(&captures, 1);
+    config.halign_range = 4;
+    let report = stack(&config).unwrap();
+    let merged = report.metadata.fields[0].drop_outs.as_ref().unwrap();
+    assert_eq!(merged.field_line, [20]);
+    assert_eq!(merged.startx, [10]);
+    assert_eq!(merged.endx, [20]);
+}
+
+#[test]
+fn mapped_inputs_stack_the_same() {
+    let dir = TestDir::new("mmap");
+    let captures = dir.captures(3, 6);
+    let mut config = dir.config_at(&captures, 3);
+    let fields = dir.output_fields(&config);
+    let buffered = fs::read(dir.basename("out") + ".tbc").unwrap();
+
+    config.mmap_inputs = true;
+    config.overwrite = true;
+    assert_eq!(dir.output_fields(&config), fields);
+    assert!(fs::read(dir.basename("out") + ".tbc").unwrap() == buffered);
+}
+
+#[test]
+fn reference_dropouts_are_kept_below_the_threshold() {
+    let dir = TestDir::new("keep-reference-dropouts");
+    let captures = dir.captures(3, 4);
+    let dropouts = serde_json::json!({"fieldLine": [20], "startx": [4], "endx": [12]});
+    set_dropouts(&captures[0], &dropouts);
+    let mut config = dir.config_at(&captures, 1);
+    let report = stack(&config).unwrap();
+    let merged = report.metadata.fields[0].drop_outs.as_ref();
+    assert!(merged.is_none_or(|d| d.field_line.is_empty()));
+
+    config.keep_reference_dropouts = true;
+    config.overwrite = true;
+    let report = stack(&config).unwrap();
+    let kept = report.metadata.fields[0].drop_outs.as_ref().unwrap();
+    assert_eq!(kept.field_line, [20]);
+    assert_eq!((kept.startx[0], kept.endx[0]), (4, 12));
+}
+
+#[test]
+fn an_input_without_fields_to_stack_is_named() {
+    let dir = TestDir::new("empty-input");
+    let a = dir.capture("a", &seq_nos(4));
+    let b = dir.capture("b", &seq_nos(4));
+    let empty = dir.capture("empty", &[]);
+    let config = dir.config(&[(&a, 1), (&b, 1), (&empty, 1)]);
+    assert!(matches!(
+        stack(&config),
+        Err(StackError::EmptyInput { input: 2, .. })
+    ));
+
+    // the metadata lists 4 fields, but the files only hold the first
+    let truncated = dir.capture("truncated", &seq_nos(4));
+    let file = fs::File::options()
+        .write(true)
+        .open(truncated.clone() + ".tbc")
+        .unwrap();
+    file.set_len((FIELD_SIZE * 2) as u64).unwrap();
+    let mut config = dir.config(&[(&a, 1), (&b, 1), (&truncated, 3)]);
+    config.size_mismatch = SizeMismatch::Warn;
+    assert!(matches!(
+        stack(&config),
+        Err(StackError::EmptyInput {
+            input: 2,
+            start_field: 3,
+            fields: 1
+        })
+    ));
+}
+
+#[test]
+fn skipping_every_output_field_writes_empty_metadata() {
+    let dir = TestDir::new("no-output-fields");
+    let captures = dir.captures(3, 4);
+    let mut config = dir.config_at(&captures, 1);
+    config.skip_output_fields = 10;
+    assert_eq!(dir.output_fields(&config), 0);
+}
+
+#[test]
+fn a_luma_only_input_is_left_out_of_the_chroma() {
+    let dir = TestDir::new("luma-only");
+    let captures = dir.captures(4, 4);
+    // the chroma of each capture is another's luma, so that it differs between them
+    for (capture, other) in captures.iter().zip(captures.iter().cycle().skip(1)) {
+        fs::copy(other.clone() + ".tbc", capture.clone() + "_chroma.tbc").unwrap();
+    }
+    let read = |suffix: &str| fs::read(dir.basename("out") + suffix).unwrap();
+    let mut config = dir.config_at(&captures, 1);
+    config.overwrite = true;
+    stack(&config).unwrap();
+    let all_luma = read(".tbc");
+    config.inputs.pop();
+    stack(&config).unwrap();
+    let three_chroma = read("_chroma.tbc");
+
+    let mut config = dir.config_at(&captures, 1);
+    config.overwrite = true;
+    config.inputs[3].planes = Planes::LumaOnly;
+    stack(&config).unwrap();
+    assert!(read(".tbc") == all_luma);
+    assert!(read("_chroma.tbc") == three_chroma);
+
+    config.inputs[2].planes = Planes::LumaOnly;
+    config.inputs[1].planes = Planes::LumaOnly;
+    assert!(matches!(stack(&config), Err(StackError::InvalidOption(_))));
+}
+
+#[test]
+fn legacy_metadata_is_read_and_written_in_the_version_chosen() {
+    let dir = TestDir::new("metadata-version");
+    let captures = dir.captures(3, 4);
+    // how older versions of ld-decode name the system
+    let path = captures[0].clone() + ".tbc.json";
+    let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    let video_parameters = json["videoParameters"].as_object_mut().unwrap();
+    video_parameters.remove("system");
+    video_parameters.insert("isSourcePal".into(), false.into());
+    fs::write(&path, json.to_string()).unwrap();
+
+    let video_parameters = |config: &StackConfig| {
+        let report = stack(config).unwrap();
+        serde_json::to_value(&report.metadata).unwrap()["videoParameters"].clone()
+    };
+    let mut config = dir.config_at(&captures, 1);
+    config.overwrite = true;
+    let kept = video_parameters(&config);
+    assert_eq!(kept["isSourcePal"], false);
+    assert!(kept.get("system").is_none());
+
+    config.metadata_version = Some(MetadataVersion::Current);
+    let current = video_parameters(&config);
+    assert_eq!(current["system"], "NTSC");
+    assert!(current.get("isSourcePal").is_none());
+}
+
+#[test]
+fn a_base_input_that_doesnt_exist_is_an_error() {
+    let dir = TestDir::new("base-input");
+    let mut config = dir.config_at(&dir.captures(3, 4), 1);
+    config.mode = StackMode::DropoutFill;
+    config.base_input = 3;
+    assert!(matches!(stack(&config), Err(StackError::InvalidOption(_))));
+}
+
+#[test]
+fn an_inverted_input_stacks_the_same_once_inverted_back() {
+    let dir = TestDir::new("invert");
+    let captures = dir.captures(3, 4);
+    let mut config = dir.config_at(&captures, 1);
+    let fields = dir.output_fields(&config);
+    let normal = fs::read(dir.basename("out") + ".tbc").unwrap();
+
+    // as decoded with the wrong polarity
+    let path = captures[1].clone() + ".tbc";
+    let inverted = (read_samples(&path).iter())
+        .map(|s| u16::MAX - s)
+        .collect::<Vec<_>>();
+    write_samples(&path, &inverted);
+    config.inputs[1].invert = true;
+    config.overwrite = true;
+    assert_eq!(dir.output_fields(&config), fields);
+    assert!(fs::read(dir.basename("out") + ".tbc").unwrap() == normal);
+}
+
+#[test]
+fn the_edges_are_taken_from_the_input_with_the_best_bpsnr() {
+    let dir = TestDir::new("edge-from-best");
+    let captures = dir.captures(3, 2);
+    // no noise at all in the black pSNR window, or anywhere else
+    write_samples(captures[1].clone() + ".tbc", &vec![16000; 2 * FIELD_SIZE]);
+    let mut config = dir.config_at(&captures, 1);
+    let first_and_last_lines = |config: &StackConfig| {
+        dir.output_fields(config);
+        let samples = read_samples(dir.basename("out") + ".tbc");
+        [
+            samples[..FIELD_WIDTH].to_vec(),
+            samples[FIELD_SIZE - FIELD_WIDTH..FIELD_SIZE].to_vec(),
+        ]
+    };
+    let combined = first_and_last_lines(&config);
+    assert!(combined.iter().flatten().any(|&v| v != 16000));
+
+    config.edge_from_best = true;
+    config.overwrite = true;
+    let copied = first_and_last_lines(&config);
+    assert!(copied.iter().flatten().all(|&v| v == 16000));
+}
+
+#[test]
+fn output_bits_round_only_the_samples_written() {
+    let dir = TestDir::new("output-bits");
+    let captures = dir.captures(3, 4);
+    let mut config = dir.config_at(&captures, 1);
+    let samples = || read_samples(dir.basename("out") + ".tbc");
+    let full = stack(&config).unwrap();
+    let full_samples = samples();
+
+    config.output_bits = 10;
+    config.overwrite = true;
+    let rounded = stack(&config).unwrap();
+    let rounded_samples = samples();
+    assert_eq!(rounded_samples.len(), full_samples.len());
+    for (&r, &f) in rounded_samples.iter().zip(&full_samples) {
+        assert_eq!(r % 64, 0);
+        assert!(r.abs_diff(f) <= 32);
+    }
+    // measured before rounding
+    assert_eq!(rounded.metadata, full.metadata);
+    for (r, f) in rounded.fields.iter().zip(&full.fields) {
+        assert_eq!(r.rmse_psnr, f.rmse_psnr);
+    }
+}
+
+#[test]
+fn level_match_takes_out_an_offset_of_one_input() {
+    let dir = TestDir::new("level-match");
+    let captures = dir.captures(3, 4);
+    // the same capture as b, from a deck putting everything higher
+    let brighter = (read_samples(captures[1].clone() + ".tbc").iter())
+        .map(|s| s + 3000)
+        .collect::<Vec<_>>();
+    write_samples(captures[2].clone() + ".tbc", &brighter);
+    let mut config = dir.config_at(&captures, 1);
+    let worst_psnr = |config: &StackConfig| {
+        let report = stack(config).unwrap();
+        (report.fields.iter())
+            .map(|f| f.rmse_psnr[2])
+            .fold(f32::INFINITY, f32::min)
+    };
+    let offset = worst_psnr(&config);
+
+    config.level_match = true;
+    config.overwrite = true;
+    let matched = worst_psnr(&config);
+    assert!(matched > offset + 10., "{offset} -> {matched}");
+}
+
+#[test]
+fn preserve_seq_no_keeps_the_reference_inputs_numbers() {
+    let dir = TestDir::new("preserve-seqno");
+    let names = ["a", "b", "c"];
+    let seq_nos = [101, 102, 103, 104];
+    let captures = names.map(|name| dir.capture(name, &seq_nos));
+    let mut config = dir.config_at(&captures, 1);
+    let output_seq_nos = |config: &StackConfig| {
+        let report = stack(config).unwrap();
+        (report.metadata.fields.iter())
+            .map(|f| f.seq_no)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(output_seq_nos(&config), [1, 2, 3, 4]);
+
+    config.preserve_seq_no = true;
+    config.overwrite = true;
+    assert_eq!(output_seq_nos(&config), seq_nos);
+}
+
+#[test]
+fn the_output_metadata_records_how_it_was_stacked() {
+    let dir = TestDir::new("stack-info");
+    let a = dir.capture("a", &seq_nos(10));
+    let b = dir.capture("b", &[1, 2, 3, 4, 5, 5, 6, 7, 8, 9, 10]);
+    // field 5 is missing, field 6 stands in for it
+    let c = dir.capture("c", &[1, 2, 3, 4, 6, 7, 8, 9, 10]);
+    let report = stack(&dir.config(&[(&a, 1), (&b, 1), (&c, 1)])).unwrap();
+    let info = &report.metadata.other["stackInfo"];
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["dupesWritten"], 1);
+    let inputs = info["inputs"].as_array().unwrap();
+    for (input, (basename, dupes, gaps)) in inputs.iter().zip([(&a, 0, 0), (&b, 1, 0), (&c, 0, 1)])
+    {
+        assert_eq!(input["basename"], basename.as_str());
+        assert_eq!(input["startField"], 1);
+        assert_eq!(
+            (input["dupes"].as_u64(), input["gaps"].as_u64()),
+            (Some(dupes), Some(gaps))
+        );
+    }
+}
+
+#[test]
+fn a_frame_every_input_disagrees_on_is_dropped() {
+    let dir = TestDir::new("drop-on-max-rmse");
+    let captures = dir.captures(3, 8);
+    // field 5 is a different picture in every input, as if they were all out of sync
+    for (capture, step) in captures.iter().zip([13, 31, 57]) {
+        let path = capture.clone() + ".tbc";
+        let mut samples = read_samples(&path);
+        for (i, sample) in samples[4 * FIELD_SIZE..5 * FIELD_SIZE]
+            .iter_mut()
+            .enumerate()
+        {
+            *sample = (i * step % 60000) as u16;
+        }
+        write_samples(&path, &samples);
+    }
+    let mut config = dir.config_at(&captures, 1);
+    assert_eq!(dir.output_fields(&config), 8);
+
+    config.drop_on_max_rmse = Some(20.);
+    config.overwrite = true;
+    assert_eq!(dir.output_fields(&config), 6);
+    let report = stack(&config).unwrap();
+    let dropped = (report.fields.iter())
+        .filter(|f| f.kind == FieldKind::Dropped)
+        .map(|f| (f.field, f.sources.clone().unwrap()))
+        .collect::<Vec<_>>();
+    // the other field of its frame goes with it
+    assert_eq!(dropped, [(5, vec![5; 3]), (5, vec![6; 3])]);
+    assert_eq!(report.fields.last().unwrap().field, 6);
+}
+
+#[test]
+fn a_start_seq_no_finds_the_start_field_of_each_input() {
+    let dir = TestDir::new("start-seqno");
+    let a = dir.capture("a", &(100..110).collect::<Vec<_>>());
+    // two more fields before the ones the others have
+    let b = dir.capture("b", &(98..110).collect::<Vec<_>>());
+    let c = dir.capture("c", &(100..110).collect::<Vec<_>>());
+    let mut config = dir.config(&[(&a, 1), (&b, 3), (&c, 1)]);
+    dir.output_fields(&config);
+    let by_field = fs::read(dir.basename("out") + ".tbc").unwrap();
+
+    for input in &mut config.inputs {
+        input.start_field = 1;
+        input.start_seq_no = Some(100);
+    }
+    config.overwrite = true;
+    dir.output_fields(&config);
+    assert!(fs::read(dir.basename("out") + ".tbc").unwrap() == by_field);
+
+    config.inputs[1].start_seq_no = Some(97);
+    assert!(matches!(
+        stack(&config),
+        Err(StackError::SeqNoRange {
+            input: 1,
+            seq_no: 97,
+            first: 98,
+            last: 109
+        })
+    ));
+}
+
+#[test]
+fn a_start_seq_no_in_a_gap_is_an_error() {
+    let dir = TestDir::new("start-seqno-gap");
+    let a = dir.capture("a", &(100..110).collect::<Vec<_>>());
+    // skips 102 and 103
+    let b = dir.capture("b", &[100, 101, 104, 105, 106, 107, 108, 109]);
+    let c = dir.capture("c", &(100..110).collect::<Vec<_>>());
+    let mut config = dir.config(&[(&a, 1), (&b, 1), (&c, 1)]);
+    for input in &mut config.inputs {
+        input.start_seq_no = Some(102);
+    }
+    assert!(matches!(
+        stack(&config),
+        Err(StackError::SeqNoMissing {
+            input: 1,
+            seq_no: 102
+        })
+    ));
+
+    // the start for every input still goes on to the next field
+    for input in &mut config.inputs {
+        input.start_seq_no = None;
+    }
+    config.seq_no_start = Some(102);
+    stack(&config).unwrap();
+}
+
+#[test]
+fn the_start_field_order_is_taken_from_the_metadata() {
+    let dir = TestDir::new("start-parity");
+    // the second field is a dupe of the first, flipping the field order of those after it
+    let a = dir.capture("a", &[1, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    let path = a.clone() + ".tbc.json";
+    let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    for (i, field) in json["fields"]
+        .as_array_mut()
+        .unwrap()
+        .iter_mut()
+        .enumerate()
+    {
+        field["isFirstField"] = (i == 0 || i % 2 == 1).into();
+    }
+    fs::write(&path, json.to_string()).unwrap();
+    let b = dir.capture("b", &seq_nos(10));
+    let c = dir.capture("c", &seq_nos(10));
+
+    // field 3 would be a first field going by its position, but it's a second one
+    let config = dir.config(&[(&a, 3), (&b, 1), (&c, 1)]);
+    assert!(matches!(
+        stack(&config),
+        Err(StackError::ReferenceFieldOrder { input: 0 })
+    ));
+    let config = dir.config(&[(&a, 2), (&b, 1), (&c, 1)]);
+    let report = stack(&config).unwrap();
+    assert_eq!(report.inputs[0].dupes, 0);
+}
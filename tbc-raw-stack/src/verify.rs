@@ -0,0 +1,81 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::reader::TbcReader;
+use crate::tbc_metadata::TbcMetadata;
+use crate::{Endian, StackError};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Checks that a written output is consistent before it's handed to other tools: that its
+/// metadata counts as many fields as it lists, that they are numbered in sequence starting on a
+/// first field with the field order alternating, and that its `.tbc` files hold that many fields.
+/// Returns a description of every problem found, empty if there are none.
+pub fn verify_output(basename: &str) -> Result<Vec<String>, StackError> {
+    let json = PathBuf::from(format!("{basename}.tbc.json"));
+    let file = File::open(&json).map_err(|source| StackError::Open {
+        path: json.clone(),
+        source,
+    })?;
+    let metadata: TbcMetadata = serde_json::from_reader(BufReader::new(file))
+        .map_err(|source| StackError::BadMetadata { path: json, source })?;
+    let params = &metadata.video_parameters;
+    let fields = metadata.fields.len();
+
+    let mut problems = vec![];
+    if params.number_of_sequential_fields != fields {
+        problems.push(format!(
+            "The metadata says there are {} fields, but lists {fields}",
+            params.number_of_sequential_fields
+        ));
+    }
+
+    let out_of_sequence = (0..fields)
+        .filter(|&i| metadata.fields[i].seq_no != i + 1)
+        .collect::<Vec<_>>();
+    let wrong_order = (0..fields)
+        .filter(|&i| metadata.fields[i].is_first_field != (i % 2 == 0))
+        .collect::<Vec<_>>();
+    // one line for each kind of problem, with where it first happens, a broken sequence would
+    // report every field after it otherwise
+    for (what, bad) in [
+        ("a seqNo out of sequence", out_of_sequence),
+        ("the wrong field order", wrong_order),
+    ] {
+        match bad.len() {
+            0 => {}
+            1 => problems.push(format!("Field {} has {what}", bad[0] + 1)),
+            n => problems.push(format!(
+                "Field {} and {} more after it have {what}",
+                bad[0] + 1,
+                n - 1
+            )),
+        }
+    }
+
+    let field_size = params.field_width * params.field_height;
+    let expected = (field_size * 2 * fields) as u64;
+    for path in [format!("{basename}.tbc"), format!("{basename}_chroma.tbc")] {
+        if path.ends_with("_chroma.tbc") && !std::fs::exists(&path).unwrap_or(true) {
+            continue;
+        }
+        let open = |source| StackError::Open {
+            path: path.clone().into(),
+            source,
+        };
+        let found = TbcReader::open(path.as_ref(), 0, Endian::Le)
+            .and_then(|file| file.byte_len())
+            .map_err(open)?;
+        match found {
+            Some(found) if found != expected => problems.push(format!(
+                "{path} is {found} bytes, but the {fields} fields take {expected} bytes"
+            )),
+            Some(_) => {}
+            None => info!("{path} doesn't record its length, its size wasn't checked"),
+        }
+    }
+    Ok(problems)
+}
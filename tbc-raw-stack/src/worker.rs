@@ -0,0 +1,1286 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::align::{find_shift, shift_dropout_lines, shift_dropouts, shift_lines, shift_samples};
+use crate::levels::LevelCorrection;
+use crate::side_metadata;
+use crate::system::{calculate_bpsnr, SystemConstants, KERNEL_LANES};
+use crate::tbc_metadata;
+use crate::{
+    Planes, SideMetadata, StackMode, VitsMetricsMode, MAX_INPUT_STREAMS, MIN_INPUT_STREAMS,
+};
+use std::ops::Range;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{span, trace, Level};
+
+pub const MAX_SAMPLES_PER_FIELD: usize = 0x57000;
+
+/// How much the difference of the output and the reference input is magnified, so that noise
+/// shows up as more than black.
+const DIFF_GAIN: u16 = 4;
+
+pub unsafe fn to_bytes<T>(input: &[T]) -> &[u8] {
+    let ptr = input as *const [T] as *const u8; // Cast slice of T to a slice of u8
+    let len = size_of_val(input); // Calculate the length in bytes
+    std::slice::from_raw_parts(ptr, len) // Create a slice of u8 from the raw pointer
+}
+pub unsafe fn to_bytes_mut<T>(input: &mut [T]) -> &mut [u8] {
+    let ptr = input as *mut [T] as *mut u8; // Cast slice of T to a mutable slice of u8
+    let len = size_of_val(input); // Calculate the length in bytes
+    std::slice::from_raw_parts_mut(ptr, len) // Create a mutable slice of u8 from the raw pointer
+}
+
+#[repr(align(64))]
+#[derive(Copy, Clone)]
+pub struct FieldBuffer(pub [u16; MAX_SAMPLES_PER_FIELD]);
+
+impl Default for FieldBuffer {
+    fn default() -> Self {
+        FieldBuffer([0; MAX_SAMPLES_PER_FIELD]) // Initialize the array with zeros
+    }
+}
+
+/// Per-field working memory: the input fields read from disk and the stacked result.
+pub struct FieldBuffers {
+    pub in_luma: Vec<Box<FieldBuffer>>,
+    pub in_chroma: Vec<Box<FieldBuffer>>,
+    pub out_luma: Box<FieldBuffer>,
+    pub out_chroma: Box<FieldBuffer>,
+    /// How far apart the input lumas are, when a confidence map is written.
+    pub out_spread: Option<Box<FieldBuffer>>,
+    /// How far the output luma is from the reference input's, when a difference is written.
+    pub out_diff: Option<Box<FieldBuffer>>,
+}
+
+impl FieldBuffers {
+    pub fn new(inputs: usize, have_chroma: bool, have_spread: bool, have_diff: bool) -> Self {
+        let chroma_inputs = if have_chroma { inputs } else { 0 };
+        FieldBuffers {
+            in_luma: (0..inputs).map(|_| Box::default()).collect(),
+            in_chroma: (0..chroma_inputs).map(|_| Box::default()).collect(),
+            out_luma: Box::default(),
+            out_chroma: Box::default(),
+            out_spread: have_spread.then(Box::default),
+            out_diff: have_diff.then(Box::default),
+        }
+    }
+}
+
+/// An earlier field of an input, kept for the temporal prefilter.
+pub struct PastField {
+    pub luma: Box<FieldBuffer>,
+    pub chroma: Option<Box<FieldBuffer>>,
+}
+
+/// Parameters shared by all stacking workers, fixed for the whole run.
+pub struct StackParams {
+    pub mode: StackMode,
+    /// Fixed shift of each input, in samples.
+    pub sample_offsets: Vec<isize>,
+    /// Fixed shift of each input, in lines.
+    pub vshifts: Vec<isize>,
+    /// How many times each input takes part when combining, unless `weighted`.
+    pub input_weights: Vec<usize>,
+    /// Repeat each input by its weight when combining.
+    pub weighted: bool,
+    /// Copy the samples outside the RMSE window from the input with the best black pSNR.
+    pub edge_from_best: bool,
+    /// Which planes of each input are stacked.
+    pub planes: Vec<Planes>,
+    /// The input the others are aligned to and whose metadata the output takes: the base input
+    /// whose dropouts are filled in dropout-fill mode, the reference input otherwise.
+    pub base_input: usize,
+    pub sys: SystemConstants,
+    pub field_width: usize,
+    pub field_height: usize,
+    pub field_size: usize,
+    pub field_size_rounded: usize,
+    pub dropout_threshold: usize,
+    /// Keep the dropouts of the base input whether or not the others agree.
+    pub keep_reference_dropouts: bool,
+    pub have_chroma: bool,
+    pub halign_range: usize,
+    /// Samples at each edge of the RMSE window whose squared error is weighed down, a multiple of
+    /// the kernel lanes.
+    pub rmse_edge_taper: usize,
+    /// Measure the squared error of every line of every this many output fields, 0 for none.
+    pub line_metrics_every: usize,
+    pub side_metadata: SideMetadata,
+    pub vits_metrics: VitsMetricsMode,
+    /// Mark samples where an input deviates from the output by more than this as dropouts.
+    pub detect_dropouts: Option<u16>,
+    /// Interpolate the output along the line where every stacked input has a dropout.
+    pub interpolate_common_dropouts: bool,
+    /// The linear light of each sample value, when the luma is combined in linear light.
+    pub linear_light: Option<Vec<f32>>,
+    /// Which middle value, or their average, the median of an even number of inputs takes.
+    pub even_median: median::EvenMedian,
+}
+
+pub enum Work {
+    /// Stack the fields in `buffers`, described by each input's metadata in `fields`, leaving out
+    /// the `excluded` inputs. Each input's field is first replaced by its median with the input's
+    /// `past` fields of the same parity, if it has any, then its luma matched to the reference
+    /// input's by its entry of `levels`, if given.
+    Stack {
+        buffers: Box<FieldBuffers>,
+        fields: Vec<tbc_metadata::Field>,
+        excluded: Vec<usize>,
+        past: Vec<Vec<Arc<PastField>>>,
+        levels: Option<Vec<LevelCorrection>>,
+    },
+    /// Describe an already written output field, read back into `buffers`, when resuming.
+    Resumed {
+        buffers: Box<FieldBuffers>,
+        fields: Vec<tbc_metadata::Field>,
+    },
+    /// Copy the field of the only input left, `input`, described by `field`, its luma matched to
+    /// the reference input's by `level` if given. If `resumed`, it is already written, and
+    /// `buffers` has it read back.
+    Passthrough {
+        buffers: Box<FieldBuffers>,
+        field: tbc_metadata::Field,
+        input: usize,
+        resumed: bool,
+        level: Option<LevelCorrection>,
+    },
+    /// Write out the previous output field again.
+    Dupe,
+    /// The inputs were consumed, but nothing is written.
+    Drop,
+}
+
+pub struct Job {
+    /// Position in the dispatch order, used to put results back in order.
+    pub seq: usize,
+    /// Index of the output field this job produces.
+    pub field_idx: usize,
+    /// 1-based source field numbers for the fieldmap, if any were consumed.
+    pub sources: Option<Vec<usize>>,
+    /// Inputs that had a dupe skipped at this field.
+    pub input_dupes: Vec<usize>,
+    pub work: Work,
+    pub timing: Timing,
+}
+
+/// How long each stage of a field took, for spotting stalls. Zero for the stages it skipped.
+#[derive(Debug, Clone, Default)]
+pub struct Timing {
+    /// Reading each input's field, empty if none were read.
+    pub read: Vec<Duration>,
+    pub stack: Duration,
+}
+
+pub struct StackedField {
+    pub buffers: Box<FieldBuffers>,
+    pub field: tbc_metadata::Field,
+    pub sse_luma: Vec<u64>,
+    /// Empty without chroma.
+    pub sse_chroma: Vec<u64>,
+    /// Each input's squared error on each line, for the fields measured by line.
+    pub line_sse: Vec<Vec<u64>>,
+    /// Black pSNR of each input's field, for the stacked fields.
+    pub input_bpsnr: Vec<f32>,
+    /// Each input's `fieldPhaseID`, for the stacked fields.
+    pub field_phases: Vec<Option<i64>>,
+}
+
+pub enum Output {
+    Stacked(Box<StackedField>),
+    Passthrough(Box<StackedField>),
+    Resumed(Box<StackedField>),
+    Dupe,
+    Drop,
+}
+
+pub struct JobResult {
+    pub seq: usize,
+    pub field_idx: usize,
+    pub sources: Option<Vec<usize>>,
+    pub input_dupes: Vec<usize>,
+    pub output: Output,
+    pub timing: Timing,
+}
+
+/// Applies each input's fixed vertical shift and sample offset to its dropouts, and to its samples
+/// if they were read.
+fn apply_sample_offsets(
+    params: &StackParams,
+    buffers: Option<&mut FieldBuffers>,
+    fields: &mut [tbc_metadata::Field],
+) {
+    for (i, field) in fields.iter_mut().enumerate() {
+        if let Some(dropouts) = field.drop_outs.as_mut() {
+            offset_dropouts(params, dropouts, i);
+        }
+    }
+    let Some(buffers) = buffers else {
+        return;
+    };
+    for i in 0..params.sample_offsets.len() {
+        offset_samples(params, &mut buffers.in_luma[i].0[0..params.field_size], i);
+        if let Some(chroma) = buffers.in_chroma.get_mut(i) {
+            offset_samples(params, &mut chroma.0[0..params.field_size], i);
+        }
+    }
+}
+
+/// Moves the dropouts of `input` the way [`offset_samples`] moves its samples.
+fn offset_dropouts(params: &StackParams, dropouts: &mut tbc_metadata::DropOuts, input: usize) {
+    let (lines, shift) = (params.vshifts[input], params.sample_offsets[input]);
+    if lines != 0 {
+        shift_dropout_lines(dropouts, lines, params.field_height);
+    }
+    if shift != 0 {
+        shift_dropouts(dropouts, shift, params.field_width, params.field_height);
+    }
+}
+
+/// Applies the vertical shift and then the sample offset of `input` to a field of its samples.
+fn offset_samples(params: &StackParams, samples: &mut [u16], input: usize) {
+    shift_lines(samples, params.vshifts[input], params.field_width);
+    shift_samples(samples, params.sample_offsets[input]);
+}
+
+/// Writes to `diff` how far each sample of `out` is from `reference`, times [`DIFF_GAIN`].
+fn reference_diff(diff: &mut [u16], out: &[u16], reference: &[u16]) {
+    for ((d, &o), &r) in diff.iter_mut().zip(out).zip(reference) {
+        *d = o.abs_diff(r).saturating_mul(DIFF_GAIN);
+    }
+}
+
+/// Merges the dropouts of all input fields, keeping the regions where at least `threshold` inputs
+/// agree on having a dropout. When filling dropouts, only the base input's dropouts are kept where
+/// at least `threshold` of the other inputs agree. The `detected` ranges are kept regardless, as
+/// are the base input's dropouts with `keep_reference_dropouts`.
+fn merge_dropouts(
+    fields: &[tbc_metadata::Field],
+    detected: &[(usize, usize)],
+    params: &StackParams,
+) -> Option<tbc_metadata::DropOuts> {
+    let field_width = params.field_width;
+    // the base input outweighs all others together, so reaching the threshold needs it
+    let filling = params.mode == StackMode::DropoutFill;
+    let base_weight = fields.len();
+    let weight = |i| {
+        if filling && i == params.base_input {
+            base_weight
+        } else {
+            1
+        }
+    };
+    let threshold = if filling {
+        base_weight + params.dropout_threshold
+    } else {
+        params.dropout_threshold
+    };
+    let mut fixed = 0;
+    let mut flat_dropouts = vec![];
+    let mut kept = vec![];
+    for (i, f) in fields.iter().enumerate() {
+        let weight = weight(i) as isize;
+        let (ranges, fixed_here) = dropout_ranges(f, params);
+        fixed += fixed_here;
+        if params.keep_reference_dropouts && i == params.base_input {
+            kept.clone_from(&ranges);
+        }
+        flat_dropouts.extend(
+            ranges
+                .into_iter()
+                .flat_map(|(start, end)| [(start, weight), (end, -weight)]),
+        );
+    }
+    if fixed != 0 {
+        trace!("Fixed up {} malformed dropouts", fixed);
+    }
+    let weight = threshold as isize;
+    flat_dropouts.extend(
+        detected
+            .iter()
+            .chain(&kept)
+            .flat_map(|&(start, end)| [(start, weight), (end, -weight)]),
+    );
+    flat_dropouts.sort_unstable_by_key(|a| a.0);
+
+    if flat_dropouts.is_empty() {
+        return None;
+    }
+
+    let mut out_dropouts = tbc_metadata::DropOuts {
+        field_line: vec![],
+        startx: vec![],
+        endx: vec![],
+    };
+    let threshold = threshold as isize;
+    let mut depth = 0isize;
+    let mut start = 0usize;
+    for (sample, delta) in flat_dropouts {
+        let was_in = depth >= threshold;
+        depth += delta;
+        let is_in = depth >= threshold;
+        if is_in && !was_in {
+            start = sample;
+        } else if was_in && !is_in {
+            // merged or wrapping dropouts may span lines, but each entry must stay on its own
+            while start < sample {
+                let line = start / field_width;
+                let line_end = sample.min((line + 1) * field_width);
+                out_dropouts.push(
+                    line,
+                    start - line * field_width,
+                    line_end - line * field_width,
+                );
+                start = line_end;
+            }
+        }
+    }
+    Some(out_dropouts)
+}
+
+/// The dropouts of `field` as sample ranges within the field, with the count of malformed ones
+/// that had to be fixed up. Some decoders emit dropouts ending past their line, those wrap onto
+/// the next lines up to the end of the field. A start past the line is clamped to its end, and
+/// empty or backwards dropouts are skipped.
+fn dropout_ranges(
+    field: &tbc_metadata::Field,
+    params: &StackParams,
+) -> (Vec<(usize, usize)>, usize) {
+    let field_width = params.field_width;
+    let field_height = params.field_height;
+    let field_end = field_width * field_height;
+    let mut ranges = vec![];
+    let mut fixed = 0;
+    let Some(dropouts) = &field.drop_outs else {
+        return (ranges, fixed);
+    };
+    for j in 0..dropouts.field_line.len() {
+        let (startx, endx) = (dropouts.startx[j], dropouts.endx[j]);
+        let Some(line) = dropouts
+            .line_index(j, field_height)
+            .filter(|_| startx < endx)
+        else {
+            fixed += 1;
+            continue;
+        };
+        if startx > field_width || endx > field_width {
+            fixed += 1;
+        }
+        let line_start = line * field_width;
+        let start = line_start + startx.min(field_width);
+        let end = (line_start + endx).min(field_end);
+        if start < end {
+            ranges.push((start, end));
+        }
+    }
+    (ranges, fixed)
+}
+
+/// Sample ranges of the field where an input deviates from the output luma by more than
+/// `threshold`, the places where stacking had to correct at least one input.
+fn detect_dropouts(
+    params: &StackParams,
+    buffers: &FieldBuffers,
+    included: &[usize],
+    threshold: u16,
+) -> Vec<(usize, usize)> {
+    // small enough to live on the stack, and a multiple of the kernel's lanes
+    const CHUNK: usize = 1024;
+    let field_size = params.field_size;
+    let mut deviation = [0u16; CHUNK];
+    let mut ranges = vec![];
+    let mut start = None;
+    for chunk_start in (0..params.field_size_rounded).step_by(CHUNK) {
+        let chunk = chunk_start..(chunk_start + CHUNK).min(params.field_size_rounded);
+        let deviation = &mut deviation[0..chunk.len()];
+        median::batch_deviation_n(
+            deviation,
+            &buffers.out_luma.0[chunk.clone()],
+            included
+                .iter()
+                .map(|&i| &buffers.in_luma[i].0[chunk.clone()])
+                .collect::<Vec<_>>()
+                .as_slice(),
+        );
+        for (sample, &d) in chunk.zip(deviation.iter()) {
+            let bad = d > threshold && sample < field_size;
+            match start {
+                None if bad => start = Some(sample),
+                Some(s) if !bad => {
+                    ranges.push((s, sample));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    if let Some(s) = start {
+        ranges.push((s, field_size));
+    }
+    trace!("Detected {} dropouts", ranges.len());
+    ranges
+}
+
+/// Replaces the field of each input with its median with the input's `past` fields, using the
+/// output buffers as scratch space.
+fn temporal_prefilter(
+    params: &StackParams,
+    buffers: &mut FieldBuffers,
+    past: &[Vec<Arc<PastField>>],
+) {
+    let field_size = params.field_size;
+    let len = params.field_size_rounded;
+    let median = |out: &mut [u16], a: &[&[u16]]| {
+        median::batch_n_even(out, a, &mut vec![0u64; a.len()], params.even_median);
+    };
+    for (i, past) in past.iter().enumerate().filter(|(_, past)| !past.is_empty()) {
+        let out = &mut buffers.out_luma.0[0..len];
+        let field = &buffers.in_luma[i].0[0..len];
+        let luma = past.iter().map(|p| &p.luma.0[0..len]);
+        median(out, &std::iter::once(field).chain(luma).collect::<Vec<_>>());
+        buffers.in_luma[i].0[0..field_size].copy_from_slice(&out[0..field_size]);
+        if let Some(chroma) = buffers.in_chroma.get_mut(i) {
+            let out = &mut buffers.out_chroma.0[0..len];
+            let past = past
+                .iter()
+                .filter_map(|p| Some(&p.chroma.as_ref()?.0[0..len]));
+            median(
+                out,
+                &std::iter::once(&chroma.0[0..len])
+                    .chain(past)
+                    .collect::<Vec<_>>(),
+            );
+            chroma.0[0..field_size].copy_from_slice(&out[0..field_size]);
+        }
+    }
+}
+
+/// Combines the input sample streams `a` into `out` according to the stacking mode, writing each
+/// input's sum of squared errors against the result to `sse_`.
+fn combine<T: median::Scalar>(
+    params: &StackParams,
+    out: &mut [T],
+    a: &[&[T]],
+    sse_: &mut [T::Acc],
+) {
+    match params.mode {
+        StackMode::Median => median::batch_n_even(out, a, sse_, params.even_median),
+        StackMode::Mean => median::batch_mean_n(out, a, sse_),
+        StackMode::TrimmedMean => median::batch_trimmed_mean_n(out, a, sse_),
+        StackMode::DropoutFill => unreachable!("dropout fill doesn't combine whole fields"),
+    }
+}
+
+/// Stacks one field group: medians luma and chroma into the output buffers, and derives the
+/// output field's metadata from the reference input's. The `excluded` inputs are left out, only
+/// their squared error against the result is measured. The dropouts of inputs moved by horizontal
+/// alignment are moved along with them.
+fn stack_field(
+    params: &StackParams,
+    buffers: &mut FieldBuffers,
+    fields: &mut [tbc_metadata::Field],
+    excluded: &[usize],
+    sse_luma: &mut [u64],
+    sse_chroma: &mut [u64],
+) -> tbc_metadata::Field {
+    let sys = &params.sys;
+    let field_size = params.field_size;
+    let field_size_rounded = params.field_size_rounded;
+    let inputs = buffers.in_luma.len();
+    let included = (0..inputs)
+        .filter(|i| !excluded.contains(i))
+        .collect::<Vec<_>>();
+    let luma_inputs = plane_inputs(params, &included, Planes::luma);
+
+    // the kernels work on whole blocks past the end of the field, which must not carry anything
+    // over from an earlier use of the buffers
+    for buffer in buffers.in_luma.iter_mut().chain(&mut buffers.in_chroma) {
+        buffer.0[field_size..field_size_rounded].fill(0);
+    }
+
+    if params.halign_range != 0 {
+        let reference = params.base_input;
+        for i in (0..inputs).filter(|&i| i != reference) {
+            let [reference, input] = buffers.in_luma.get_disjoint_mut([reference, i]).unwrap();
+            let shift = find_shift(
+                &reference.0[0..field_size],
+                &input.0[0..field_size],
+                params.halign_range,
+                sys,
+            );
+            if shift != 0 {
+                trace!("Shifting input #{} by {} samples", i + 1, shift);
+                shift_samples(&mut input.0[0..field_size], shift);
+                if let Some(chroma) = buffers.in_chroma.get_mut(i) {
+                    shift_samples(&mut chroma.0[0..field_size], shift);
+                }
+                if let Some(dropouts) = fields[i].drop_outs.as_mut() {
+                    shift_dropouts(dropouts, shift, params.field_width, params.field_height);
+                }
+            }
+        }
+    }
+
+    if let Some(spread) = buffers.out_spread.as_mut() {
+        median::batch_spread_n(
+            &mut spread.0[0..field_size_rounded],
+            luma_inputs
+                .iter()
+                .map(|&i| &buffers.in_luma[i].0[0..field_size_rounded])
+                .collect::<Vec<_>>()
+                .as_slice(),
+        );
+    }
+
+    if params.mode == StackMode::DropoutFill {
+        fill_dropouts(params, buffers, fields, sse_luma, sse_chroma);
+        if params.interpolate_common_dropouts {
+            interpolate_common_dropouts(params, buffers, fields, &included);
+        }
+        let detected = params.detect_dropouts.map_or(vec![], |threshold| {
+            detect_dropouts(params, buffers, &included, threshold)
+        });
+        return output_field(
+            params,
+            &buffers.out_luma.0[0..field_size],
+            fields,
+            &detected,
+        );
+    }
+
+    // each input takes part as many times as its weight
+    let lanes = |plane_inputs: &[usize]| {
+        if params.weighted {
+            weighted_lanes(params, &buffers.in_luma, plane_inputs)
+        } else {
+            repeat_lanes(plane_inputs.iter().map(|&i| (i, params.input_weights[i])))
+        }
+    };
+    let luma_lanes = lanes(&luma_inputs);
+    let chroma_inputs = plane_inputs(params, &included, Planes::chroma);
+    let chroma_lanes = if chroma_inputs == luma_inputs {
+        luma_lanes.clone()
+    } else {
+        lanes(&chroma_inputs)
+    };
+    if let Some(table) = &params.linear_light {
+        combine_linear(
+            params,
+            table,
+            &mut buffers.out_luma.0[0..field_size_rounded],
+            &buffers.in_luma,
+            &luma_lanes,
+            sse_luma,
+        );
+    } else {
+        combine_plane(
+            params,
+            &mut buffers.out_luma.0[0..field_size_rounded],
+            &buffers.in_luma,
+            &luma_lanes,
+            &left_out(inputs, &luma_inputs),
+            sse_luma,
+        );
+    }
+    if params.have_chroma {
+        combine_plane(
+            params,
+            &mut buffers.out_chroma.0[0..field_size_rounded],
+            &buffers.in_chroma,
+            &chroma_lanes,
+            &left_out(inputs, &chroma_inputs),
+            sse_chroma,
+        );
+    }
+    if params.edge_from_best {
+        copy_edges_from_best(params, buffers, &luma_inputs, &chroma_inputs);
+    }
+    if params.interpolate_common_dropouts {
+        interpolate_common_dropouts(params, buffers, fields, &luma_inputs);
+    }
+
+    let detected = params.detect_dropouts.map_or(vec![], |threshold| {
+        detect_dropouts(params, buffers, &luma_inputs, threshold)
+    });
+    output_field(
+        params,
+        &buffers.out_luma.0[0..field_size],
+        fields,
+        &detected,
+    )
+}
+
+/// Overwrites the samples of the output outside the RMSE window with those of the input of
+/// `luma_inputs` with the best black pSNR, and its chroma too if it is one of `chroma_inputs`.
+/// Their squared error isn't measured either way.
+fn copy_edges_from_best(
+    params: &StackParams,
+    buffers: &mut FieldBuffers,
+    luma_inputs: &[usize],
+    chroma_inputs: &[usize],
+) {
+    let sys = &params.sys;
+    let field_size = params.field_size;
+    let Some((best, _)) = luma_inputs
+        .iter()
+        .map(|&i| {
+            (
+                i,
+                calculate_bpsnr(&buffers.in_luma[i].0[0..field_size], sys),
+            )
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    else {
+        return;
+    };
+    trace!("Taking the edges from input #{}", best + 1);
+    let edges = [
+        0..sys.useful_start_sample,
+        sys.useful_end_sample..field_size,
+    ];
+    for edge in edges {
+        buffers.out_luma.0[edge.clone()].copy_from_slice(&buffers.in_luma[best].0[edge.clone()]);
+        if params.have_chroma && chroma_inputs.contains(&best) {
+            buffers.out_chroma.0[edge.clone()].copy_from_slice(&buffers.in_chroma[best].0[edge]);
+        }
+    }
+}
+
+/// The inputs stacked into one plane, the `included` ones that take part in it. If exclusions
+/// leave too few of them for the mode, all that take part in it are.
+fn plane_inputs(
+    params: &StackParams,
+    included: &[usize],
+    takes_part: fn(Planes) -> bool,
+) -> Vec<usize> {
+    let min = match params.mode {
+        StackMode::TrimmedMean => 4,
+        _ => MIN_INPUT_STREAMS,
+    };
+    let inputs = included
+        .iter()
+        .copied()
+        .filter(|&i| takes_part(params.planes[i]))
+        .collect::<Vec<_>>();
+    if inputs.len() >= min {
+        return inputs;
+    }
+    (0..params.planes.len())
+        .filter(|&i| takes_part(params.planes[i]))
+        .collect()
+}
+
+/// The inputs of `0..inputs` not in `stacked`.
+fn left_out(inputs: usize, stacked: &[usize]) -> Vec<usize> {
+    (0..inputs).filter(|i| !stacked.contains(i)).collect()
+}
+
+/// Combines the `lanes` of one plane, luma or chroma, of every input into `out`, writing each
+/// input's squared error against the result in the RMSE window to `sse`. The `excluded` inputs
+/// are only measured.
+fn combine_plane(
+    params: &StackParams,
+    out: &mut [u16],
+    inputs: &[Box<FieldBuffer>],
+    lanes: &[usize],
+    excluded: &[usize],
+    sse: &mut [u64],
+) {
+    let sys = &params.sys;
+    let useful = sys.useful_start_sample..sys.useful_end_sample;
+    let len = out.len();
+    let mut sse_lanes = vec![0u64; lanes.len()];
+    // the kernels overwrite the squared error rather than add to it, so the edges can share one
+    // buffer that is thrown away
+    let mut sse_lanes_edge = vec![0u64; lanes.len()];
+    let parts = |range: Range<usize>| {
+        lanes
+            .iter()
+            .map(|&i| &inputs[i].0[range.clone()])
+            .collect::<Vec<_>>()
+    };
+
+    // We combine in 3 parts, because we only want the SSE of the middle bits.
+    // The rest may be garbage due to head switch, and we don't want it to skew the numbers.
+    combine(
+        params,
+        &mut out[0..useful.start],
+        &parts(0..useful.start),
+        &mut sse_lanes_edge[..],
+    );
+    combine_useful(
+        params,
+        &mut out[useful.clone()],
+        &parts(useful.clone()),
+        &mut sse_lanes[..],
+    );
+    combine(
+        params,
+        &mut out[useful.end..],
+        &parts(useful.end..len),
+        &mut sse_lanes_edge[..],
+    );
+    lanes_to_inputs(lanes, &sse_lanes, sse);
+    for &i in excluded {
+        sse[i] = useful_sse(params, &out[useful.clone()], &inputs[i].0[useful.clone()]);
+    }
+}
+
+/// Combines the `lanes` of one plane like [`combine_plane`], but as the linear light in `table`
+/// the samples stand for, each result turned back into the nearest sample value. The squared
+/// error of every input is still measured on the sample values, so the metrics don't change
+/// meaning.
+fn combine_linear(
+    params: &StackParams,
+    table: &[f32],
+    out: &mut [u16],
+    inputs: &[Box<FieldBuffer>],
+    lanes: &[usize],
+    sse: &mut [u64],
+) {
+    let len = out.len();
+    // an input weighed more than once is only looked up once
+    let mut linear = vec![None; inputs.len()];
+    for &i in lanes {
+        linear[i].get_or_insert_with(|| {
+            inputs[i].0[0..len]
+                .iter()
+                .map(|&v| table[v as usize])
+                .collect::<Vec<_>>()
+        });
+    }
+    let mut out_linear = vec![0f32; len];
+    combine(
+        params,
+        &mut out_linear,
+        &lanes
+            .iter()
+            .map(|&i| linear[i].as_deref().unwrap())
+            .collect::<Vec<_>>(),
+        &mut vec![0f64; lanes.len()],
+    );
+    for (out, &l) in out.iter_mut().zip(&out_linear) {
+        let above = table.partition_point(|&v| v < l).min(table.len() - 1);
+        // the nearest of the sample values on either side
+        *out = match above.checked_sub(1) {
+            Some(below) if l - table[below] < table[above] - l => below as u16,
+            _ => above as u16,
+        };
+    }
+
+    let useful = params.sys.useful_start_sample..params.sys.useful_end_sample;
+    for (sse, input) in sse.iter_mut().zip(inputs) {
+        *sse = useful_sse(params, &out[useful.clone()], &input.0[useful.clone()]);
+    }
+}
+
+/// Combines the RMSE window like [`combine`], with the squared error of the blocks
+/// within `params.rmse_edge_taper` samples of its edges weighed down linearly towards them. The
+/// sum is scaled back up to what it would be over the whole window at full weight, so the RMSE
+/// stays comparable.
+fn combine_useful(params: &StackParams, out: &mut [u16], a: &[&[u16]], sse: &mut [u64]) {
+    let taper = params.rmse_edge_taper;
+    if taper == 0 {
+        combine(params, out, a, sse);
+        return;
+    }
+
+    let len = out.len();
+    // overwritten by every block, not added to
+    let mut block_sse = vec![0u64; sse.len()];
+    let mut total = vec![0f64; sse.len()];
+    let mut total_weight = 0f64;
+    for (range, weight) in tapered_parts(len, taper) {
+        combine(
+            params,
+            &mut out[range.clone()],
+            a.iter()
+                .map(|input| &input[range.clone()])
+                .collect::<Vec<_>>()
+                .as_slice(),
+            &mut block_sse,
+        );
+        for (total, &e) in total.iter_mut().zip(&block_sse) {
+            *total += e as f64 * weight;
+        }
+        total_weight += range.len() as f64 * weight;
+    }
+
+    for (e, total) in sse.iter_mut().zip(total) {
+        *e = (total * len as f64 / total_weight).round() as u64;
+    }
+}
+
+/// Splits a window of `len` samples into the blocks within `taper` samples of its edges, weighed
+/// down linearly towards them, and the rest at full weight.
+fn tapered_parts(len: usize, taper: usize) -> Vec<(Range<usize>, f64)> {
+    let blocks = taper / KERNEL_LANES;
+    let mut parts = vec![];
+    for k in 0..blocks {
+        let weight = (k as f64 + 0.5) / blocks as f64;
+        parts.push((k * KERNEL_LANES..(k + 1) * KERNEL_LANES, weight));
+        parts.push((len - (k + 1) * KERNEL_LANES..len - k * KERNEL_LANES, weight));
+    }
+    parts.push((taper..len - taper, 1.));
+    parts
+}
+
+/// The squared error of an input left out of the stack against the RMSE window of the output,
+/// weighed like [`combine_useful`] does for the stacked inputs.
+fn useful_sse(params: &StackParams, out: &[u16], input: &[u16]) -> u64 {
+    let sse = |range: Range<usize>| {
+        out[range.clone()]
+            .iter()
+            .zip(&input[range])
+            .map(|(&o, &i)| (o.abs_diff(i) as u64).pow(2))
+            .sum::<u64>()
+    };
+    let len = out.len();
+    if params.rmse_edge_taper == 0 {
+        return sse(0..len);
+    }
+    let mut total = 0f64;
+    let mut total_weight = 0f64;
+    for (range, weight) in tapered_parts(len, params.rmse_edge_taper) {
+        total += sse(range.clone()) as f64 * weight;
+        total_weight += range.len() as f64 * weight;
+    }
+    (total * len as f64 / total_weight).round() as u64
+}
+
+/// The inputs to combine for a weighted median or mean, each repeated by its weight. The weights
+/// come from how well the inputs match the unweighted result of this field: each is proportional
+/// to the best input's RMSE divided by its own, scaled up as far as the total fits in
+/// [`MAX_INPUT_STREAMS`]. Only the `included` inputs take part.
+fn weighted_lanes(
+    params: &StackParams,
+    in_luma: &[Box<FieldBuffer>],
+    included: &[usize],
+) -> Vec<usize> {
+    let sys = &params.sys;
+    let useful = sys.useful_start_sample..sys.useful_end_sample;
+    let mut scratch = vec![0u16; useful.len()];
+    let mut sse = vec![0u64; included.len()];
+    combine(
+        params,
+        &mut scratch,
+        included
+            .iter()
+            .map(|&i| &in_luma[i].0[useful.clone()])
+            .collect::<Vec<_>>()
+            .as_slice(),
+        &mut sse,
+    );
+
+    let best = *sse.iter().min().unwrap();
+    let quality = sse
+        .iter()
+        .map(|&e| {
+            if e == 0 {
+                1.
+            } else {
+                (best as f64 / e as f64).sqrt()
+            }
+        })
+        .collect::<Vec<_>>();
+    let weights_at = |scale: usize| {
+        quality
+            .iter()
+            .map(|q| ((q * scale as f64) as usize).max(1))
+            .collect::<Vec<_>>()
+    };
+    let mut scale = 1;
+    while weights_at(scale + 1).iter().sum::<usize>() <= MAX_INPUT_STREAMS {
+        scale += 1;
+    }
+    let weights = weights_at(scale);
+    trace!("Input weights: {:?}", weights);
+    repeat_lanes(included.iter().copied().zip(weights))
+}
+
+/// The lanes to combine, each input repeated by its weight.
+fn repeat_lanes(weights: impl Iterator<Item = (usize, usize)>) -> Vec<usize> {
+    weights
+        .flat_map(|(i, w)| std::iter::repeat_n(i, w))
+        .collect()
+}
+
+/// The squared error of every input's luma against the output on each line of the field, with
+/// the lines as rows. A separate pass, as the kernels only sum up the whole window.
+fn line_sse(params: &StackParams, buffers: &FieldBuffers) -> Vec<Vec<u64>> {
+    let width = params.field_width;
+    (0..params.field_height)
+        .map(|line| {
+            let line = line * width..(line + 1) * width;
+            let out = &buffers.out_luma.0[line.clone()];
+            buffers
+                .in_luma
+                .iter()
+                .map(|input| {
+                    input.0[line.clone()]
+                        .iter()
+                        .zip(out)
+                        .map(|(&a, &b)| (a.abs_diff(b) as u64).pow(2))
+                        .sum()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Gives each input the squared error of its lanes, which are all the same.
+fn lanes_to_inputs(lanes: &[usize], sse_lanes: &[u64], sse: &mut [u64]) {
+    for (&i, &e) in lanes.iter().zip(sse_lanes) {
+        sse[i] = e;
+    }
+}
+
+/// Copies the base input into the output buffers, filling its dropouts with the median of the
+/// other inputs.
+fn fill_dropouts(
+    params: &StackParams,
+    buffers: &mut FieldBuffers,
+    fields: &[tbc_metadata::Field],
+    sse_luma: &mut [u64],
+    sse_chroma: &mut [u64],
+) {
+    let sys = &params.sys;
+    let size = params.field_size_rounded;
+    let mut mask = vec![false; size];
+    for (start, end) in dropout_ranges(&fields[params.base_input], params).0 {
+        mask[start.min(size)..end.min(size)].fill(true);
+    }
+
+    fill_masked(
+        params,
+        &mut buffers.out_luma.0[0..size],
+        &buffers.in_luma,
+        &mask,
+    );
+    if params.have_chroma {
+        fill_masked(
+            params,
+            &mut buffers.out_chroma.0[0..size],
+            &buffers.in_chroma,
+            &mask,
+        );
+    }
+
+    let useful = sys.useful_start_sample..sys.useful_end_sample;
+    for (out, inputs, sse) in [
+        (&buffers.out_luma, &buffers.in_luma, sse_luma),
+        (&buffers.out_chroma, &buffers.in_chroma, sse_chroma),
+    ] {
+        let out = &out.0[useful.clone()];
+        for (sse, input) in sse.iter_mut().zip(inputs) {
+            *sse = input.0[useful.clone()]
+                .iter()
+                .zip(out)
+                .map(|(&a, &b)| (a.abs_diff(b) as u64).pow(2))
+                .sum();
+        }
+    }
+}
+
+/// Writes the base input to `out`, except where `mask` is set, which gets the median of the other
+/// inputs.
+fn fill_masked(params: &StackParams, out: &mut [u16], inputs: &[Box<FieldBuffer>], mask: &[bool]) {
+    let base = params.base_input;
+    let len = out.len();
+    if mask.contains(&true) {
+        let others = inputs
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != base)
+            .map(|(_, f)| &f.0[0..len])
+            .collect::<Vec<_>>();
+        if let [other] = others[..] {
+            out.copy_from_slice(other);
+        } else {
+            median::batch_n_even(
+                out,
+                &others,
+                &mut vec![0u64; others.len()],
+                params.even_median,
+            );
+        }
+    }
+    for ((out, &sample), &dropout) in out.iter_mut().zip(&inputs[base].0[0..len]).zip(mask) {
+        if !dropout {
+            *out = sample;
+        }
+    }
+}
+
+/// Where every `included` input has a dropout, replaces the output with a straight line between
+/// the samples on either side on the same line, as there is no clean input to take it from.
+fn interpolate_common_dropouts(
+    params: &StackParams,
+    buffers: &mut FieldBuffers,
+    fields: &[tbc_metadata::Field],
+    included: &[usize],
+) {
+    let size = params.field_size;
+    let mut common = vec![true; size];
+    let mut mask = vec![false; size];
+    for &i in included {
+        mask.fill(false);
+        for (start, end) in dropout_ranges(&fields[i], params).0 {
+            mask[start.min(size)..end.min(size)].fill(true);
+        }
+        for (common, &dropout) in common.iter_mut().zip(&mask) {
+            *common &= dropout;
+        }
+    }
+    let count = common.iter().filter(|&&c| c).count();
+    if included.is_empty() || count == 0 {
+        return;
+    }
+    trace!(
+        "Interpolating {} samples all inputs have a dropout on",
+        count
+    );
+
+    interpolate_masked(
+        &mut buffers.out_luma.0[0..size],
+        &common,
+        params.field_width,
+    );
+    if params.have_chroma {
+        interpolate_masked(
+            &mut buffers.out_chroma.0[0..size],
+            &common,
+            params.field_width,
+        );
+    }
+}
+
+/// Replaces every run of `mask` in `out` with a straight line between the samples on either side
+/// on the same line. A run reaching the edge of its line repeats the one side it has.
+fn interpolate_masked(out: &mut [u16], mask: &[bool], field_width: usize) {
+    for (line, mask) in out.chunks_mut(field_width).zip(mask.chunks(field_width)) {
+        let mut x = 0;
+        while x < line.len() {
+            if !mask[x] {
+                x += 1;
+                continue;
+            }
+            let start = x;
+            while x < line.len() && mask[x] {
+                x += 1;
+            }
+            let before = start.checked_sub(1).map(|i| line[i]);
+            let after = line.get(x).copied();
+            match (before, after) {
+                (Some(a), Some(b)) => {
+                    let steps = (x - start + 1) as f64;
+                    for (j, sample) in line[start..x].iter_mut().enumerate() {
+                        let t = (j + 1) as f64 / steps;
+                        *sample = (a as f64 + (b as f64 - a as f64) * t).round() as u16;
+                    }
+                }
+                (Some(side), None) | (None, Some(side)) => line[start..x].fill(side),
+                // the whole line is a dropout, there is nothing to go by
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+/// Derives the output field's metadata from the reference input's, the stacked luma, the
+/// `detected` dropouts, and the selected side metadata and VITS metrics.
+fn output_field(
+    params: &StackParams,
+    luma: &[u16],
+    fields: &[tbc_metadata::Field],
+    detected: &[(usize, usize)],
+) -> tbc_metadata::Field {
+    let mut new_field = fields[params.base_input].clone();
+    new_field.vits_metrics = Some(side_metadata::vits_metrics(
+        calculate_bpsnr(luma, &params.sys) as f64,
+        fields,
+        params.base_input,
+        params.vits_metrics,
+    ));
+    new_field.drop_outs = merge_dropouts(fields, detected, params);
+    side_metadata::select(&mut new_field, fields, params.side_metadata);
+    new_field
+}
+
+/// Copies the field of `input` to the output buffers, with its own metadata, once the other
+/// inputs ended. Only the metadata is derived when `resumed`.
+fn pass_through(
+    params: &StackParams,
+    buffers: &mut FieldBuffers,
+    mut field: tbc_metadata::Field,
+    input: usize,
+    resumed: bool,
+) -> tbc_metadata::Field {
+    let field_size = params.field_size;
+    if let Some(dropouts) = field.drop_outs.as_mut() {
+        offset_dropouts(params, dropouts, input);
+    }
+    if !resumed {
+        let out_luma = &mut buffers.out_luma.0[0..field_size];
+        out_luma.copy_from_slice(&buffers.in_luma[input].0[0..field_size]);
+        offset_samples(params, out_luma, input);
+        if params.have_chroma {
+            let out_chroma = &mut buffers.out_chroma.0[0..field_size];
+            out_chroma.copy_from_slice(&buffers.in_chroma[input].0[0..field_size]);
+            offset_samples(params, out_chroma, input);
+        }
+        // a single input agrees with itself everywhere
+        for out in [&mut buffers.out_spread, &mut buffers.out_diff] {
+            if let Some(out) = out.as_mut() {
+                out.0[0..field_size].fill(0);
+            }
+        }
+    }
+    let bpsnr = calculate_bpsnr(&buffers.out_luma.0[0..field_size], &params.sys) as f64;
+    // the only input taking part is its own reference and average
+    field.vits_metrics = Some(side_metadata::vits_metrics(
+        bpsnr,
+        std::slice::from_ref(&field),
+        0,
+        params.vits_metrics,
+    ));
+    field
+}
+
+pub fn stack_worker(params: &StackParams, jobs: &Mutex<Receiver<Job>>, results: Sender<JobResult>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => break, // dispatcher is done
+        };
+        let started = Instant::now();
+        let output = match job.work {
+            Work::Stack {
+                mut buffers,
+                mut fields,
+                excluded,
+                past,
+                levels,
+            } => {
+                let _span = span!(Level::INFO, "field", idx = job.field_idx + 1).entered();
+                temporal_prefilter(params, &mut buffers, &past);
+                drop(past);
+                // after the prefilter, which only compares each input with itself
+                for (luma, level) in buffers.in_luma.iter_mut().zip(levels.iter().flatten()) {
+                    level.apply(&mut luma.0[0..params.field_size]);
+                }
+                apply_sample_offsets(params, Some(&mut buffers), &mut fields);
+                let mut sse_luma = vec![0u64; fields.len()];
+                let chroma_inputs = if params.have_chroma { fields.len() } else { 0 };
+                let mut sse_chroma = vec![0u64; chroma_inputs];
+                let field = stack_field(
+                    params,
+                    &mut buffers,
+                    &mut fields,
+                    &excluded,
+                    &mut sse_luma,
+                    &mut sse_chroma,
+                );
+                if let Some(diff) = buffers.out_diff.as_mut() {
+                    let field_size = params.field_size;
+                    reference_diff(
+                        &mut diff.0[0..field_size],
+                        &buffers.out_luma.0[0..field_size],
+                        &buffers.in_luma[params.base_input].0[0..field_size],
+                    );
+                }
+                let every = params.line_metrics_every;
+                let line_sse = if every != 0 && job.field_idx.is_multiple_of(every) {
+                    line_sse(params, &buffers)
+                } else {
+                    vec![]
+                };
+                let input_bpsnr = buffers
+                    .in_luma
+                    .iter()
+                    .map(|luma| calculate_bpsnr(&luma.0[0..params.field_size], &params.sys))
+                    .collect();
+                let field_phases = fields.iter().map(side_metadata::field_phase).collect();
+                Output::Stacked(Box::new(StackedField {
+                    buffers,
+                    field,
+                    sse_luma,
+                    sse_chroma,
+                    line_sse,
+                    input_bpsnr,
+                    field_phases,
+                }))
+            }
+            Work::Resumed {
+                buffers,
+                mut fields,
+            } => {
+                // the output was written shifted already, only the dropouts need it
+                apply_sample_offsets(params, None, &mut fields);
+                // dropouts aren't detected when resuming, the inputs weren't read
+                let field = output_field(
+                    params,
+                    &buffers.out_luma.0[0..params.field_size],
+                    &fields,
+                    &[],
+                );
+                // the errors are unknown, the inputs weren't read
+                Output::Resumed(Box::new(StackedField {
+                    buffers,
+                    field,
+                    sse_luma: vec![],
+                    sse_chroma: vec![],
+                    line_sse: vec![],
+                    input_bpsnr: vec![],
+                    field_phases: vec![],
+                }))
+            }
+            Work::Passthrough {
+                mut buffers,
+                field,
+                input,
+                resumed,
+                level,
+            } => {
+                let _span = span!(Level::INFO, "field", idx = job.field_idx + 1).entered();
+                if let Some(level) = level {
+                    level.apply(&mut buffers.in_luma[input].0[0..params.field_size]);
+                }
+                let field = pass_through(params, &mut buffers, field, input, resumed);
+                // there is nothing to compare the input to
+                let passed = Box::new(StackedField {
+                    buffers,
+                    field,
+                    sse_luma: vec![],
+                    sse_chroma: vec![],
+                    line_sse: vec![],
+                    input_bpsnr: vec![],
+                    field_phases: vec![],
+                });
+                if resumed {
+                    Output::Resumed(passed)
+                } else {
+                    Output::Passthrough(passed)
+                }
+            }
+            Work::Dupe => Output::Dupe,
+            Work::Drop => Output::Drop,
+        };
+        let result = JobResult {
+            seq: job.seq,
+            field_idx: job.field_idx,
+            sources: job.sources,
+            input_dupes: job.input_dupes,
+            output,
+            timing: Timing {
+                stack: started.elapsed(),
+                ..job.timing
+            },
+        };
+        if results.send(result).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,470 @@
+//  This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::compress::TbcWriter;
+use crate::report::{FieldKind, FieldReport, StackObserver};
+use crate::system::SystemConstants;
+use crate::tbc_metadata;
+use crate::worker::{FieldBuffers, JobResult, Output, StackedField, Timing};
+use crate::{RmseWarn, StackError};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::time::{Duration, Instant};
+use tracing::{span, trace, warn, Level};
+
+pub struct Writer<'a> {
+    pub sys: SystemConstants,
+    pub field_width: usize,
+    pub field_size: usize,
+    /// How many bits the output luma and chroma samples are rounded to.
+    pub output_bits: u32,
+    /// Scratch space for the rounded samples.
+    pub rounded: Vec<u16>,
+    pub out_luma: TbcWriter,
+    pub out_chroma: Option<TbcWriter>,
+    pub out_spread: Option<TbcWriter>,
+    pub out_diff: Option<TbcWriter>,
+    pub out_fields: Vec<tbc_metadata::Field>,
+    pub reports: Vec<FieldReport>,
+    /// Count of fields already written by the run being resumed.
+    pub resumed_fields: usize,
+    pub observer: &'a mut dyn StackObserver,
+    pub rmse_warn: RmseWarn,
+    pub rmse_bad_in_a_row: Vec<usize>,
+    pub rmse_high_in_a_row: Vec<usize>,
+    /// Count of stacked fields whose inputs disagreed on `fieldPhaseID`.
+    pub phase_mismatches: usize,
+    pub stalls: Stalls,
+    /// Leaves inputs out of the stack while they match poorly, if enabled.
+    pub exclusion: Option<Exclusion>,
+    /// The most recently written field, kept around for writing dupes.
+    pub last: Option<Box<StackedField>>,
+    /// Drops the frames with a stacked field every input's RMSE pSNR is below this on, if set.
+    pub drop_on_max_rmse: Option<f32>,
+    /// The results of the frame held back until both of its fields are in, to know whether to
+    /// drop it.
+    pub frame: Vec<JobResult>,
+    /// Count of fields dropped for every input disagreeing with them.
+    pub disagreement_drops: usize,
+}
+
+/// Decides which inputs to leave out of the stack: an input is left out once it counted as bad
+/// for the RMSE warning for a streak of fields, and stacked again once it hasn't for as many.
+pub struct Exclusion {
+    /// Receives the inputs to leave out after every stacked field.
+    pub decisions: Sender<Vec<usize>>,
+    pub excluded: Vec<usize>,
+    /// How many fields in a row each left out input didn't count as bad.
+    pub good_in_a_row: Vec<usize>,
+    /// How many inputs have to stay in the stack.
+    pub min_inputs: usize,
+}
+
+impl Exclusion {
+    /// Updates the decision after a stacked field, with the bad streak of every input.
+    fn update(&mut self, rmse_warn: &RmseWarn, rmse_psnr: &[f32], bad_in_a_row: &[usize]) {
+        for (i, &bad) in bad_in_a_row.iter().enumerate() {
+            if self.excluded.contains(&i) {
+                if rmse_warn.is_bad(rmse_psnr, i) {
+                    self.good_in_a_row[i] = 0;
+                } else {
+                    self.good_in_a_row[i] += 1;
+                    if self.good_in_a_row[i] >= rmse_warn.streak {
+                        self.excluded.retain(|&e| e != i);
+                    }
+                }
+            } else if bad >= rmse_warn.streak
+                && bad_in_a_row.len() - self.excluded.len() > self.min_inputs
+            {
+                self.excluded.push(i);
+                self.excluded.sort();
+                self.good_in_a_row[i] = 0;
+            }
+        }
+        // the dispatcher is gone once it's done
+        let _ = self.decisions.send(self.excluded.clone());
+    }
+}
+
+/// How many times the median time of the recent fields a field has to take to count as a stall.
+pub const STALL_FACTOR: u32 = 10;
+/// How many of the fields before it a field's time is compared to.
+const STALL_WINDOW: usize = 64;
+/// Anything quicker isn't worth a warning, however slow the fields before it were.
+const STALL_MIN: Duration = Duration::from_millis(200);
+
+/// Spots the fields that took much longer to read, stack and write than the ones before them,
+/// like an input on a drive that stopped answering for a while.
+#[derive(Default)]
+pub struct Stalls {
+    recent: VecDeque<Duration>,
+    /// Count of the fields that counted as a stall.
+    pub count: usize,
+}
+
+impl Stalls {
+    /// Adds the times of output field `field_idx`, warning with the stage that took longest if it
+    /// stalled.
+    fn check(&mut self, field_idx: usize, timing: &Timing, write: Duration) {
+        let read = timing.read.iter().sum::<Duration>();
+        let total = read + timing.stack + write;
+        // too few fields to know what's usual yet
+        if self.recent.len() >= STALL_WINDOW / 4 {
+            let mut sorted = self.recent.iter().copied().collect::<Vec<_>>();
+            sorted.sort_unstable();
+            let median = sorted[sorted.len() / 2];
+            if total >= STALL_MIN && total > median * STALL_FACTOR {
+                self.count += 1;
+                let (slowest, slowest_read) = timing
+                    .read
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .max_by_key(|&(_, t)| t)
+                    .unwrap_or_default();
+                let stage = if read >= timing.stack && read >= write {
+                    format!(
+                        "reading, {} ms of it input #{}",
+                        slowest_read.as_millis(),
+                        slowest + 1
+                    )
+                } else if timing.stack >= write {
+                    "stacking".to_string()
+                } else {
+                    "writing".to_string()
+                };
+                warn!(
+                    "Output field {} took {} ms against {} ms for the recent ones, mostly {stage}. Slow or failing drive?",
+                    field_idx + 1,
+                    total.as_millis(),
+                    median.as_millis()
+                );
+            }
+        }
+        if self.recent.len() == STALL_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(total);
+    }
+}
+
+impl Writer<'_> {
+    /// Consumes results in dispatch order, writing them out and recycling their buffers. Stops at
+    /// the first error, returned along with the writer, whose `out_fields` are then the fields
+    /// written in full.
+    pub fn run(
+        mut self,
+        results: Receiver<JobResult>,
+        pool: SyncSender<Box<FieldBuffers>>,
+    ) -> (Self, Result<(), StackError>) {
+        let mut pending = BTreeMap::new();
+        let mut next_seq = 0usize;
+        for result in results {
+            pending.insert(result.seq, result);
+            while let Some(result) = pending.remove(&next_seq) {
+                next_seq += 1;
+                if let Err(e) = self.take(result, &pool) {
+                    return (self, Err(e));
+                }
+            }
+        }
+        // the last frame may be a lone field
+        for result in std::mem::take(&mut self.frame) {
+            if let Err(e) = self.write(result) {
+                return (self, Err(e));
+            }
+        }
+        (self, Ok(()))
+    }
+
+    /// Writes out a result, or if dropping frames the inputs disagree on, holds it back until
+    /// the frame is complete and then writes or drops the whole frame. Sends the buffers done
+    /// with back to `pool`.
+    fn take(
+        &mut self,
+        mut result: JobResult,
+        pool: &SyncSender<Box<FieldBuffers>>,
+    ) -> Result<(), StackError> {
+        // the pool may already be gone once the dispatcher is done
+        let recycle = |buffers| drop(pool.send(buffers));
+        let Some(floor) = self.drop_on_max_rmse else {
+            if let Some(buffers) = self.write(result)? {
+                recycle(buffers);
+            }
+            return Ok(());
+        };
+        // the dispatcher doesn't know about the fields dropped here
+        result.field_idx -= self.disagreement_drops;
+        self.frame.push(result);
+        let fields = (self.frame.iter())
+            .filter(|r| !matches!(r.output, Output::Drop))
+            .count();
+        if fields < 2 {
+            return Ok(());
+        }
+
+        let frame = std::mem::take(&mut self.frame);
+        let disagreeing = frame.iter().find_map(|r| match &r.output {
+            Output::Stacked(stacked) => {
+                let psnr = rmse_psnr(&self.sys, &stacked.sse_luma);
+                let finite = psnr.iter().filter(|v| v.is_finite());
+                let disagree =
+                    finite.clone().next().is_some() && finite.clone().all(|&v| v < floor);
+                disagree.then_some((r.field_idx, psnr))
+            }
+            _ => None,
+        });
+        let Some((field_idx, psnr)) = disagreeing else {
+            for result in frame {
+                if let Some(buffers) = self.write(result)? {
+                    recycle(buffers);
+                }
+            }
+            return Ok(());
+        };
+
+        let str = psnr
+            .iter()
+            .map(|v| format!("{v:.2}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        warn!(
+            "Every input disagrees with output field {}, RMSE pSNR {str}, dropping its frame. Inputs out of sync?",
+            field_idx + 1
+        );
+        // the frame's fields all share the index of the next field written, as any drop does
+        let first_idx = frame.iter().map(|r| r.field_idx).min().unwrap();
+        for result in frame {
+            let buffers = match result.output {
+                Output::Stacked(stacked) | Output::Passthrough(stacked) => Some(stacked.buffers),
+                _ => None,
+            };
+            self.write(JobResult {
+                field_idx: first_idx,
+                output: Output::Drop,
+                ..result
+            })?;
+            if let Some(buffers) = buffers {
+                recycle(buffers);
+            }
+        }
+        self.disagreement_drops += 2;
+        Ok(())
+    }
+
+    fn report(&mut self, report: FieldReport) -> Result<(), StackError> {
+        self.observer.field(&report)?;
+        self.reports.push(report);
+        Ok(())
+    }
+
+    fn write(&mut self, result: JobResult) -> Result<Option<Box<FieldBuffers>>, StackError> {
+        let _span = span!(Level::INFO, "field", idx = result.field_idx + 1).entered();
+        let started = Instant::now();
+
+        // already written fields are only replayed to know what they were
+        let resumed = result.field_idx < self.resumed_fields;
+
+        if let Some(sources) = &result.sources {
+            let str = sources
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            trace!("Generating from fields {}", str);
+        }
+
+        let kind = match &result.output {
+            _ if resumed => FieldKind::Resumed,
+            Output::Stacked(_) => FieldKind::Stacked,
+            Output::Passthrough(_) => FieldKind::Passthrough,
+            Output::Resumed(_) => FieldKind::Resumed,
+            Output::Dupe => FieldKind::Dupe,
+            Output::Drop => FieldKind::Dropped,
+        };
+        let recycled = match result.output {
+            Output::Drop => {
+                if !resumed {
+                    self.report(FieldReport {
+                        field: result.field_idx + 1,
+                        kind,
+                        sources: result.sources,
+                        input_dupes: result.input_dupes,
+                        rmse_psnr: vec![],
+                        chroma_rmse_psnr: vec![],
+                        line_rmse_psnr: vec![],
+                        bpsnr: None,
+                        input_bpsnr: vec![],
+                        dropouts: 0,
+                    })?;
+                }
+                return Ok(None);
+            }
+            Output::Dupe => None,
+            Output::Stacked(stacked) | Output::Passthrough(stacked) | Output::Resumed(stacked) => {
+                self.last.replace(stacked).map(|last| last.buffers)
+            }
+        };
+
+        let StackedField {
+            buffers,
+            field,
+            sse_luma,
+            sse_chroma,
+            line_sse,
+            input_bpsnr,
+            field_phases,
+        } = self.last.as_deref().expect("Dupe before any field");
+        let sys = self.sys;
+
+        let to_psnr = |sse: &[u64]| rmse_psnr(&sys, sse);
+        // chroma samples are in the same units as luma, so its pSNR is against the same black to
+        // white range, which keeps the two comparable
+        let chroma_rmse_psnr = if resumed { vec![] } else { to_psnr(sse_chroma) };
+        let input_bpsnr = if resumed { vec![] } else { input_bpsnr.clone() };
+        // a dupe repeats the field, but it was measured already
+        let line_rmse_psnr = match kind {
+            FieldKind::Stacked => line_sse
+                .iter()
+                .map(|line| {
+                    line.iter()
+                        .map(|&e| sys.error_to_psnr((e as f32 / self.field_width as f32).sqrt()))
+                        .collect()
+                })
+                .collect(),
+            _ => vec![],
+        };
+        // the output can only have one of them, which may not match its chroma
+        if kind == FieldKind::Stacked && phases_disagree(field_phases) {
+            self.phase_mismatches += 1;
+            if self.phase_mismatches == 1 {
+                let phases = field_phases
+                    .iter()
+                    .map(|p| p.map_or("-".to_string(), |p| p.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                warn!(
+                    "The inputs disagree on fieldPhaseID at output field {}: {phases}. The output takes one of them, and its chroma may not decode right. Check the start fields, or take the side metadata by vote",
+                    result.field_idx + 1
+                );
+            }
+        }
+        let mut rmse_psnr = vec![];
+        if !resumed && !sse_luma.is_empty() {
+            rmse_psnr = to_psnr(sse_luma);
+
+            let str = rmse_psnr
+                .iter()
+                .map(|v| format!("{}", v))
+                .collect::<Vec<_>>()
+                .join(",");
+            trace!("RMSE pSNR: {}", str);
+            for (i, &v) in rmse_psnr.iter().enumerate() {
+                if self.rmse_warn.is_bad(&rmse_psnr, i) {
+                    self.rmse_bad_in_a_row[i] += 1;
+                    if self.rmse_bad_in_a_row[i].is_multiple_of(self.rmse_warn.streak) {
+                        warn!(
+                            "RMSE pSNR on input #{} has been very high for {} fields: {}. Bad source or desync?",
+                            i + 1,
+                            self.rmse_bad_in_a_row[i],
+                            v
+                        );
+                    }
+                } else {
+                    self.rmse_bad_in_a_row[i] = 0;
+                }
+                if self.rmse_warn.is_high(&rmse_psnr, i) {
+                    self.rmse_high_in_a_row[i] += 1;
+                    if self.rmse_high_in_a_row[i].is_multiple_of(self.rmse_warn.streak) {
+                        warn!(
+                            "Input #{} has had suspiciously high agreement with the output for {} fields, RMSE pSNR {}. Duplicate input, or the others have no picture?",
+                            i + 1,
+                            self.rmse_high_in_a_row[i],
+                            v
+                        );
+                    }
+                } else {
+                    self.rmse_high_in_a_row[i] = 0;
+                }
+            }
+            if let (Some(exclusion), FieldKind::Stacked) = (self.exclusion.as_mut(), kind) {
+                exclusion.update(&self.rmse_warn, &rmse_psnr, &self.rmse_bad_in_a_row);
+            }
+        }
+
+        if !resumed {
+            let (bits, rounded) = (self.output_bits, &mut self.rounded);
+            let luma = round_samples(&buffers.out_luma.0[0..self.field_size], bits, rounded);
+            self.out_luma.write_samples(luma)?;
+            if let Some(out_chroma) = self.out_chroma.as_mut() {
+                let chroma =
+                    round_samples(&buffers.out_chroma.0[0..self.field_size], bits, rounded);
+                out_chroma.write_samples(chroma)?;
+            }
+            if let (Some(out_spread), Some(spread)) =
+                (self.out_spread.as_mut(), buffers.out_spread.as_ref())
+            {
+                out_spread.write_samples(&spread.0[0..self.field_size])?;
+            }
+            if let (Some(out_diff), Some(diff)) =
+                (self.out_diff.as_mut(), buffers.out_diff.as_ref())
+            {
+                out_diff.write_samples(&diff.0[0..self.field_size])?;
+            }
+        }
+        let field = field.clone();
+        let report = FieldReport {
+            field: result.field_idx + 1,
+            kind,
+            sources: result.sources,
+            input_dupes: result.input_dupes,
+            rmse_psnr,
+            chroma_rmse_psnr,
+            line_rmse_psnr,
+            bpsnr: field.vits_metrics.as_ref().map(|m| m.bpsnr),
+            input_bpsnr,
+            dropouts: field.drop_outs.as_ref().map_or(0, |d| d.field_line.len()),
+        };
+        self.out_fields.push(field);
+        if matches!(kind, FieldKind::Stacked | FieldKind::Passthrough) {
+            self.stalls
+                .check(result.field_idx, &result.timing, started.elapsed());
+        }
+        self.report(report)?;
+        Ok(recycled)
+    }
+}
+
+/// The RMSE pSNR of each input, from its squared error in the RMSE window.
+fn rmse_psnr(sys: &SystemConstants, sse: &[u64]) -> Vec<f32> {
+    let useful_size = sys.useful_end_sample - sys.useful_start_sample;
+    sse.iter()
+        .map(|&f| sys.error_to_psnr((f as f32 / useful_size as f32).sqrt()))
+        .collect()
+}
+
+/// `samples` rounded to the nearest value with only the top `bits` bits set, in `scratch` unless
+/// they are kept whole.
+fn round_samples<'a>(samples: &'a [u16], bits: u32, scratch: &'a mut Vec<u16>) -> &'a [u16] {
+    if bits >= 16 {
+        return samples;
+    }
+    let step = 1u32 << (16 - bits);
+    let top = u16::MAX as u32 + 1 - step;
+    scratch.clear();
+    scratch.extend(
+        samples
+            .iter()
+            .map(|&v| ((v as u32 + step / 2) & !(step - 1)).min(top) as u16),
+    );
+    scratch
+}
+
+/// Whether the inputs that recorded a `fieldPhaseID` recorded different ones.
+fn phases_disagree(phases: &[Option<i64>]) -> bool {
+    let mut known = phases.iter().flatten();
+    known
+        .next()
+        .is_some_and(|first| known.any(|phase| phase != first))
+}